@@ -0,0 +1,11 @@
+-- Migration 061 — Relay custom domains
+-- Maps a custom domain (e.g. forum.example.org, CNAME'd to the relay) to a
+-- directory slug, so nodyx-relay routes it like slug.<base domain>.
+
+CREATE TABLE IF NOT EXISTS relay_custom_domains (
+  domain      VARCHAR(253) PRIMARY KEY,
+  slug        VARCHAR(63)  NOT NULL REFERENCES directory_instances(slug) ON DELETE CASCADE,
+  created_at  TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_relay_custom_domains_slug ON relay_custom_domains(slug);
@@ -0,0 +1,21 @@
+-- Migration 062 — Relay quotas and usage
+-- Per-slug limits enforced by nodyx-relay (NULL = unlimited), and daily usage
+-- counters it flushes for billing / fair-use reports.
+
+ALTER TABLE directory_instances
+  ADD COLUMN IF NOT EXISTS relay_rps_limit        INTEGER DEFAULT NULL,
+  ADD COLUMN IF NOT EXISTS relay_concurrent_limit INTEGER DEFAULT NULL,
+  ADD COLUMN IF NOT EXISTS relay_bytes_per_day    BIGINT  DEFAULT NULL;
+
+-- bytes_in: request bodies from visitors; bytes_out: response bodies to visitors.
+CREATE TABLE IF NOT EXISTS relay_usage (
+  slug       VARCHAR(63) NOT NULL REFERENCES directory_instances(slug) ON DELETE CASCADE,
+  day        DATE        NOT NULL,
+  requests   BIGINT      NOT NULL DEFAULT 0,
+  rejected   BIGINT      NOT NULL DEFAULT 0,
+  bytes_in   BIGINT      NOT NULL DEFAULT 0,
+  bytes_out  BIGINT      NOT NULL DEFAULT 0,
+  PRIMARY KEY (slug, day)
+);
+
+CREATE INDEX IF NOT EXISTS idx_relay_usage_day ON relay_usage(day);
@@ -0,0 +1,52 @@
+-- Migration 063 — Hashed instance tokens and rotation
+-- Instance tokens (directory API, relay) were stored in clear. They are now
+-- kept as salted SHA-256 hashes and checked with directory_token_valid().
+-- A rotation keeps the previous token valid until previous_token_expires_at,
+-- so an instance and its relay client can switch over without downtime.
+-- Lookups by token alone (ping, assets, …) find their row through
+-- token_lookup, a short unsalted digest that is not enough to check a token
+-- with: directory_token_valid() still decides.
+
+ALTER TABLE directory_instances
+  ADD COLUMN IF NOT EXISTS token_salt                TEXT,
+  ADD COLUMN IF NOT EXISTS token_hash                TEXT,
+  ADD COLUMN IF NOT EXISTS token_lookup              TEXT,
+  ADD COLUMN IF NOT EXISTS previous_token_salt       TEXT,
+  ADD COLUMN IF NOT EXISTS previous_token_hash       TEXT,
+  ADD COLUMN IF NOT EXISTS previous_token_lookup     TEXT,
+  ADD COLUMN IF NOT EXISTS previous_token_expires_at TIMESTAMPTZ,
+  ADD COLUMN IF NOT EXISTS token_rotated_at          TIMESTAMPTZ;
+
+CREATE OR REPLACE FUNCTION directory_token_hash(salt TEXT, token TEXT) RETURNS TEXT
+  LANGUAGE sql IMMUTABLE STRICT
+  AS $$ SELECT encode(digest(salt || token, 'sha256'), 'hex') $$;
+
+-- First 64 bits of the token's SHA-256: finds its row, proves nothing.
+CREATE OR REPLACE FUNCTION directory_token_lookup(token TEXT) RETURNS TEXT
+  LANGUAGE sql IMMUTABLE STRICT
+  AS $$ SELECT left(encode(digest(token, 'sha256'), 'hex'), 16) $$;
+
+-- Hash the existing tokens, then forget them.
+UPDATE directory_instances SET token_salt = encode(gen_random_bytes(16), 'hex') WHERE token_salt IS NULL;
+UPDATE directory_instances SET token_hash = directory_token_hash(token_salt, token) WHERE token_hash IS NULL;
+UPDATE directory_instances SET token_lookup = directory_token_lookup(token) WHERE token_lookup IS NULL;
+
+ALTER TABLE directory_instances
+  ALTER COLUMN token_salt SET NOT NULL,
+  ALTER COLUMN token_hash SET NOT NULL,
+  ALTER COLUMN token_lookup SET NOT NULL,
+  DROP COLUMN IF EXISTS token;
+
+CREATE INDEX IF NOT EXISTS idx_directory_instances_token_lookup
+  ON directory_instances(token_lookup);
+CREATE INDEX IF NOT EXISTS idx_directory_instances_previous_token_lookup
+  ON directory_instances(previous_token_lookup) WHERE previous_token_lookup IS NOT NULL;
+
+-- The current token, or the previous one during its grace period.
+CREATE OR REPLACE FUNCTION directory_token_valid(inst directory_instances, candidate TEXT) RETURNS BOOLEAN
+  LANGUAGE sql STABLE
+  AS $$
+    SELECT inst.token_hash = directory_token_hash(inst.token_salt, candidate)
+        OR COALESCE(inst.previous_token_expires_at > NOW()
+                    AND inst.previous_token_hash = directory_token_hash(inst.previous_token_salt, candidate), FALSE)
+  $$;
@@ -0,0 +1,15 @@
+-- Migration 064 — Relay auth bans
+-- Failed relay registrations and the bans they lead to, shared by every
+-- nodyx-relay server on this database and kept across restarts.
+-- strikes counts past bans: each one doubles the next ban, up to the relay's
+-- --auth-max-ban. Permanent blocks stay in ip_bans, which the relay honours too.
+
+CREATE TABLE IF NOT EXISTS relay_auth_bans (
+  ip            INET        NOT NULL PRIMARY KEY,
+  failures      INTEGER     NOT NULL DEFAULT 0,
+  window_start  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  strikes       INTEGER     NOT NULL DEFAULT 0,
+  banned_until  TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_relay_auth_bans_until ON relay_auth_bans(banned_until);
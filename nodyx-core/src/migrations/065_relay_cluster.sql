@@ -0,0 +1,14 @@
+-- Migration 065 — Relay cluster
+-- Which relay server holds a tunnel for which slug, so several nodes behind
+-- one DNS name can forward requests to each other. node is the address peers
+-- reach that node's cluster listener on; rows whose seen_at stops moving
+-- belong to a node that went away and are ignored.
+
+CREATE TABLE IF NOT EXISTS relay_cluster_tunnels (
+  slug     VARCHAR(63) NOT NULL,
+  node     TEXT        NOT NULL,
+  seen_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (slug, node)
+);
+
+CREATE INDEX IF NOT EXISTS idx_relay_cluster_tunnels_node ON relay_cluster_tunnels(node);
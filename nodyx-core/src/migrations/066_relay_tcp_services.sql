@@ -0,0 +1,16 @@
+-- Migration 066 — Relay TCP services
+-- Public port allocated to each raw TCP service a relay client exposes, so a
+-- service keeps its port across reconnections and relay restarts. TCP
+-- services are not available in cluster mode. seen_at moves each time the
+-- service registers and while its port stays open; the relay reclaims rows
+-- left alone for a month.
+
+CREATE TABLE IF NOT EXISTS relay_tcp_services (
+  slug     VARCHAR(63) NOT NULL REFERENCES directory_instances(slug) ON DELETE CASCADE,
+  name     TEXT        NOT NULL,
+  port     INTEGER     NOT NULL UNIQUE,
+  seen_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (slug, name)
+);
+
+CREATE INDEX IF NOT EXISTS idx_relay_tcp_services_seen ON relay_tcp_services(seen_at);
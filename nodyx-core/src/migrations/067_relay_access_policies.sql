@@ -0,0 +1,20 @@
+-- Migration 067 — Relay access policies
+-- Who may reach an instance behind the relay, set by its owner with the
+-- directory token (PUT /api/directory/:slug/relay-access) and enforced by
+-- nodyx-relay before forwarding, e.g. to keep a staging community private.
+-- deny_ips wins over allow_ips; an empty allow_ips lets any other address in.
+-- Basic auth and the secret header are alternatives: a request passes with
+-- either. The basic auth password is stored as a bcrypt hash; the header
+-- value, a random secret, like instance tokens: directory_token_hash(salt, value).
+
+CREATE TABLE IF NOT EXISTS relay_access_policies (
+  slug                VARCHAR(63) PRIMARY KEY REFERENCES directory_instances(slug) ON DELETE CASCADE,
+  allow_ips           CIDR[]      NOT NULL DEFAULT '{}',
+  deny_ips            CIDR[]      NOT NULL DEFAULT '{}',
+  basic_auth_user     TEXT,
+  basic_auth_hash     TEXT,
+  secret_header       TEXT,
+  secret_header_salt  TEXT,
+  secret_header_hash  TEXT,
+  updated_at          TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
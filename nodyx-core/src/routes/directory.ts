@@ -1,5 +1,7 @@
 import { FastifyInstance, FastifyRequest, FastifyReply } from 'fastify';
 import { randomBytes } from 'crypto';
+import bcrypt from 'bcrypt';
+import { isIP } from 'net';
 import { lookup } from 'dns';
 import { promisify } from 'util';
 import https from 'https';
@@ -98,6 +100,61 @@ async function deleteCloudflareRecord(recordId: string): Promise<void> {
   }
 }
 
+// ── Relay access policies ─────────────────────────────────────────────────────
+
+const MAX_ACCESS_IPS = 100;
+// The relay checks basic auth passwords against a bcrypt hash, which only
+// reads their first 72 bytes.
+const BCRYPT_ROUNDS = 12;
+const MAX_PASSWORD_BYTES = 72;
+
+// Headers the relay sets or consumes itself, so they can't carry the secret.
+const RESERVED_HEADERS = new Set([
+  'host', 'authorization', 'forwarded', 'x-forwarded-for', 'x-forwarded-host',
+  'x-forwarded-proto', 'x-real-ip',
+]);
+
+function isIpOrCidr(value: unknown): boolean {
+  if (typeof value !== 'string') return false;
+  const [addr, prefix, ...rest] = value.split('/');
+  const family = isIP(addr);
+  if (!family || rest.length) return false;
+  if (prefix === undefined) return true;
+  const bits = Number(prefix);
+  return /^\d+$/.test(prefix) && bits <= (family === 4 ? 32 : 128);
+}
+
+function relayAccessError(
+  allow: unknown, deny: unknown,
+  basic: { user: string; password: string } | null,
+  header: { name: string; value: string } | null,
+): string | null {
+  for (const [field, list] of [['allow_ips', allow], ['deny_ips', deny]] as const) {
+    if (!Array.isArray(list) || list.length > MAX_ACCESS_IPS || !list.every(isIpOrCidr)) {
+      return `${field} must be a list of at most ${MAX_ACCESS_IPS} IP addresses or CIDR networks`;
+    }
+  }
+  if (basic) {
+    if (typeof basic.user !== 'string' || !/^[^:\s]{1,64}$/.test(basic.user)) {
+      return 'basic_auth.user must be 1-64 characters, without spaces or ":"';
+    }
+    if (typeof basic.password !== 'string'
+        || Buffer.byteLength(basic.password) < 8 || Buffer.byteLength(basic.password) > MAX_PASSWORD_BYTES) {
+      return `basic_auth.password must be 8-${MAX_PASSWORD_BYTES} bytes`;
+    }
+  }
+  if (header) {
+    if (typeof header.name !== 'string' || !/^[A-Za-z0-9-]{1,64}$/.test(header.name)
+        || RESERVED_HEADERS.has(header.name.toLowerCase())) {
+      return 'secret_header.name must be a header name the relay does not set itself';
+    }
+    if (typeof header.value !== 'string' || !/^[\x21-\x7e]{16,256}$/.test(header.value)) {
+      return 'secret_header.value must be 16-256 printable ASCII characters';
+    }
+  }
+  return null;
+}
+
 export default async function directoryRoutes(app: FastifyInstance) {
 
   // ── Subdomain redirect ────────────────────────────────────────────────────
@@ -184,14 +241,16 @@ export default async function directoryRoutes(app: FastifyInstance) {
     }
 
     const token = randomBytes(32).toString('hex');
+    const salt  = randomBytes(16).toString('hex');
     const subdomain = `${slug}.nodyx.org`;
 
     const result = await db.query(
       `INSERT INTO directory_instances
-         (slug, name, description, url, language, country, theme, version, token, status)
-       VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,'pending')
+         (slug, name, description, url, language, country, theme, version,
+          token_salt, token_hash, token_lookup, status)
+       VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,directory_token_hash($9,$10),directory_token_lookup($10),'pending')
        RETURNING id, slug, name, url, status, registered_at`,
-      [slug, name, description ?? '', url, language, country ?? '', theme ?? '', version ?? '', token]
+      [slug, name, description ?? '', url, language, country ?? '', theme ?? '', version ?? '', salt, token]
     );
 
     const instance = result.rows[0];
@@ -255,14 +314,15 @@ export default async function directoryRoutes(app: FastifyInstance) {
       const pingIp = isPrivate ? null : rawIp;
 
       const result = await db.query(
-        `UPDATE directory_instances
+        `UPDATE directory_instances d
          SET last_seen  = NOW(),
              members    = COALESCE($2, members),
              online     = COALESCE($3, online),
              logo_url   = COALESCE($4, logo_url),
              banner_url = COALESCE($5, banner_url),
              ip         = COALESCE($6, ip)
-         WHERE token = $1
+         WHERE directory_token_lookup($1) IN (token_lookup, previous_token_lookup)
+           AND directory_token_valid(d, $1)
          RETURNING slug, status`,
         [token, members ?? null, online ?? null, logo_url ?? null, banner_url ?? null, pingIp]
       );
@@ -284,7 +344,7 @@ export default async function directoryRoutes(app: FastifyInstance) {
       if (!token) return reply.status(400).send({ error: 'token required' });
 
       const result = await db.query(
-        'SELECT id, cloudflare_record_id FROM directory_instances WHERE slug=$1 AND token=$2',
+        'SELECT id, cloudflare_record_id FROM directory_instances d WHERE slug=$1 AND directory_token_valid(d, $2)',
         [slug, token]
       );
 
@@ -300,6 +360,125 @@ export default async function directoryRoutes(app: FastifyInstance) {
     }
   );
 
+  // POST /api/directory/:slug/token — rotate the instance token
+  // Only the current token may rotate. The previous one stays valid for
+  // grace_hours (default 24, max 168) so the instance and its relay client
+  // can switch over without downtime.
+  app.post<{ Params: { slug: string }; Body: { token: string; grace_hours?: number } }>(
+    '/directory/:slug/token',
+    async (req, reply) => {
+      const { slug } = req.params;
+      const { token, grace_hours = 24 } = req.body ?? {};
+      if (!token) return reply.status(400).send({ error: 'token required' });
+      if (!Number.isInteger(grace_hours) || grace_hours < 0 || grace_hours > 168) {
+        return reply.status(400).send({ error: 'grace_hours must be between 0 and 168' });
+      }
+
+      const newToken = randomBytes(32).toString('hex');
+      const salt     = randomBytes(16).toString('hex');
+      const result = await db.query(
+        `UPDATE directory_instances
+         SET previous_token_salt       = token_salt,
+             previous_token_hash       = token_hash,
+             previous_token_lookup     = token_lookup,
+             previous_token_expires_at = NOW() + make_interval(hours => $3),
+             token_salt                = $4,
+             token_hash                = directory_token_hash($4, $5),
+             token_lookup              = directory_token_lookup($5),
+             token_rotated_at          = NOW()
+         WHERE slug=$1 AND token_hash = directory_token_hash(token_salt, $2)
+         RETURNING previous_token_expires_at`,
+        [slug, token, grace_hours, salt, newToken]
+      );
+
+      if (result.rows.length === 0) {
+        return reply.status(403).send({ error: 'Invalid slug or token' });
+      }
+
+      console.log(`[Directory] ${slug} rotated its token (${grace_hours}h grace)`);
+      return reply.send({
+        ok: true,
+        token: newToken,
+        previous_valid_until: result.rows[0].previous_token_expires_at,
+      });
+    }
+  );
+
+  // PUT /api/directory/:slug/relay-access — who may reach the instance through the relay
+  // Replaces the whole policy: IP allow/deny lists (addresses or CIDR), HTTP
+  // basic auth and/or a shared-secret header, either of which lets a request
+  // through. Secrets are stored hashed. An empty policy removes it. nodyx-relay
+  // applies changes within a minute.
+  app.put<{
+    Params: { slug: string };
+    Body: {
+      token: string;
+      allow_ips?: string[];
+      deny_ips?: string[];
+      basic_auth?: { user: string; password: string } | null;
+      secret_header?: { name: string; value: string } | null;
+    };
+  }>(
+    '/directory/:slug/relay-access',
+    async (req, reply) => {
+      const { slug } = req.params;
+      const { token, allow_ips = [], deny_ips = [], basic_auth = null, secret_header = null } = req.body ?? {};
+      if (!token) return reply.status(400).send({ error: 'token required' });
+
+      const error = relayAccessError(allow_ips, deny_ips, basic_auth, secret_header);
+      if (error) return reply.status(400).send({ error });
+
+      const { rows: [inst] } = await db.query(
+        'SELECT slug FROM directory_instances d WHERE slug=$1 AND directory_token_valid(d, $2)',
+        [slug, token]
+      );
+      if (!inst) return reply.status(403).send({ error: 'Invalid slug or token' });
+
+      if (!allow_ips.length && !deny_ips.length && !basic_auth && !secret_header) {
+        await db.query('DELETE FROM relay_access_policies WHERE slug=$1', [slug]);
+        console.log(`[Directory] ${slug} removed its relay access policy`);
+        return reply.send({ ok: true, policy: null });
+      }
+
+      const basicHash  = basic_auth ? await bcrypt.hash(basic_auth.password, BCRYPT_ROUNDS) : null;
+      const headerSalt = secret_header ? randomBytes(16).toString('hex') : null;
+      await db.query(
+        `INSERT INTO relay_access_policies
+           (slug, allow_ips, deny_ips, basic_auth_user, basic_auth_hash,
+            secret_header, secret_header_salt, secret_header_hash, updated_at)
+         VALUES ($1,
+                 ARRAY(SELECT network(ip) FROM unnest($2::inet[]) AS ip),
+                 ARRAY(SELECT network(ip) FROM unnest($3::inet[]) AS ip),
+                 $4, $5,
+                 $6, $7, directory_token_hash($7, $8), NOW())
+         ON CONFLICT (slug) DO UPDATE SET
+           allow_ips          = EXCLUDED.allow_ips,
+           deny_ips           = EXCLUDED.deny_ips,
+           basic_auth_user    = EXCLUDED.basic_auth_user,
+           basic_auth_hash    = EXCLUDED.basic_auth_hash,
+           secret_header      = EXCLUDED.secret_header,
+           secret_header_salt = EXCLUDED.secret_header_salt,
+           secret_header_hash = EXCLUDED.secret_header_hash,
+           updated_at         = NOW()`,
+        [
+          slug, allow_ips, deny_ips,
+          basic_auth?.user ?? null, basicHash,
+          secret_header?.name.toLowerCase() ?? null, headerSalt, secret_header?.value ?? null,
+        ]
+      );
+
+      console.log(`[Directory] ${slug} updated its relay access policy`);
+      return reply.send({
+        ok: true,
+        policy: {
+          allow_ips, deny_ips,
+          basic_auth_user: basic_auth?.user ?? null,
+          secret_header: secret_header?.name.toLowerCase() ?? null,
+        },
+      });
+    }
+  );
+
   // ── v0.7 Federation — Asset federation ───────────────────────────────────
 
   // POST /api/directory/assets
@@ -329,7 +508,9 @@ export default async function directoryRoutes(app: FastifyInstance) {
 
     // Validate token → fetch instance
     const { rows: instanceRows } = await db.query(
-      `SELECT id, slug FROM directory_instances WHERE token = $1 AND status = 'active'`,
+      `SELECT id, slug FROM directory_instances d
+       WHERE directory_token_lookup($1) IN (token_lookup, previous_token_lookup)
+         AND directory_token_valid(d, $1) AND status = 'active'`,
       [token]
     );
     if (instanceRows.length === 0) {
@@ -478,7 +659,9 @@ export default async function directoryRoutes(app: FastifyInstance) {
       if (!token) return reply.status(400).send({ error: 'token required' });
 
       const instance = await db.query(
-        `SELECT slug, url FROM directory_instances WHERE token = $1 AND status = 'active' LIMIT 1`,
+        `SELECT slug, url FROM directory_instances d
+         WHERE directory_token_lookup($1) IN (token_lookup, previous_token_lookup)
+           AND directory_token_valid(d, $1) AND status = 'active' LIMIT 1`,
         [token]
       );
       if (!instance.rows[0]) return reply.status(403).send({ error: 'invalid token' });
@@ -553,7 +736,7 @@ export default async function directoryRoutes(app: FastifyInstance) {
 
       // Vérifier que le token correspond à l'instance enregistrée
       const { rows: [inst] } = await db.query(
-        `SELECT id FROM directory_instances WHERE slug = $1 AND token = $2 AND status = 'active' LIMIT 1`,
+        `SELECT id FROM directory_instances d WHERE slug = $1 AND directory_token_valid(d, $2) AND status = 'active' LIMIT 1`,
         [instance_slug, token]
       );
       if (!inst) return reply.status(403).send({ error: 'Invalid token or unknown instance' });
@@ -653,7 +836,9 @@ export default async function directoryRoutes(app: FastifyInstance) {
 
       // Validate token against active instances
       const { rows: [inst] } = await db.query(
-        `SELECT slug FROM directory_instances WHERE token = $1 AND status = 'active' LIMIT 1`,
+        `SELECT slug FROM directory_instances d
+         WHERE directory_token_lookup($1) IN (token_lookup, previous_token_lookup)
+           AND directory_token_valid(d, $1) AND status = 'active' LIMIT 1`,
         [token]
       )
       if (!inst) return reply.status(403).send({ error: 'invalid token' })
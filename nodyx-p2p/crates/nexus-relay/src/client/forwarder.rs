@@ -1,98 +1,361 @@
-use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use http_body_util::{BodyExt, Empty};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, warn};
 use base64::{Engine as _, engine::general_purpose::STANDARD as B64};
 
-use crate::protocol::ClientMessage;
+use crate::protocol::{self, BodyReceiver, CHUNK_SIZE, ClientMessage, Headers, Window};
+use super::routes::{Route, Routes};
 
-/// Forward an HTTP request to localhost:{local_port} and return the response
-/// as a ClientMessage::Response ready to send back to the relay server.
+/// How long a local TCP service has to accept a connection.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request or upgrade received from the relay server.
+pub struct Forward {
+    pub id: String,
+    pub method: String,
+    pub path: String,
+    pub headers: Headers,
+    /// How long the local server has to send its response head.
+    pub timeout: Duration,
+}
+
+/// Forward an HTTP request to the local server its path routes to and stream
+/// the response back as ResponseHead + ResponseChunk* + ResponseEnd on `tx`,
+/// as fast as `window` lets it.
 ///
 /// This function is designed to be spawned concurrently — it does NOT write
 /// to the TCP stream directly; the caller serializes writes via an mpsc channel.
+/// The request body arrives on `body_rx`; one cut short aborts the local request.
 pub async fn handle_request(
-    id: String,
-    method: String,
-    path: String,
-    headers: HashMap<String, String>,
-    body_b64: String,
-    local_port: u16,
-) -> ClientMessage {
-    let url = format!("http://127.0.0.1:{local_port}{path}");
-    debug!("Forwarding {method} {url}");
-
-    let body_bytes = B64.decode(&body_b64).unwrap_or_else(|e| {
-        warn!("Failed to decode request body base64 (id={id}): {e}");
-        vec![]
-    });
-
-    let client = match reqwest::Client::builder()
-        // Slightly above pingInterval (8s) so long polls complete before timeout.
-        // Must be less than the relay server's reply timeout (15s).
-        .timeout(std::time::Duration::from_secs(12))
-        .build()
-    {
-        Ok(c) => c,
-        Err(e) => {
-            warn!("Failed to build reqwest client: {e}");
-            return error_response(id, 500, "Client build error");
-        }
+    fwd: Forward,
+    mut body_rx: BodyReceiver<ClientMessage>,
+    window: Window,
+    routes: Arc<Routes>,
+    tx: mpsc::Sender<ClientMessage>,
+) {
+    let Forward { id, method, path, headers, timeout } = fwd;
+    let Some(route) = routes.resolve(&path) else {
+        warn!("No local route for {path} (id={id})");
+        let _ = tx.send(error_response(id, 404, "No local route for this path")).await;
+        return;
     };
+    let url = route.url(&path);
+    debug!("Forwarding {method} {url}");
 
     let method_parsed = match reqwest::Method::from_bytes(method.as_bytes()) {
         Ok(m)  => m,
         Err(_) => {
             warn!("Invalid HTTP method '{method}' in relay request {id}");
-            return error_response(id, 400, "Invalid HTTP method");
+            let _ = tx.send(error_response(id, 400, "Invalid HTTP method")).await;
+            return;
         }
     };
 
-    let mut req = client.request(method_parsed, &url).body(body_bytes);
+    let mut req = route.client.request(method_parsed, &url);
+
+    // Only attach a streaming body when there is one: an empty stream would
+    // make reqwest send `transfer-encoding: chunked` on plain GETs.
+    // `uploaded` resolves once the stream is done with, the last chunk sent.
+    let uploaded = match body_rx.recv().await {
+        Some(first) => {
+            let (done_tx, done_rx) = oneshot::channel::<()>();
+            let rest = stream::unfold((body_rx, done_tx), |(mut rx, done)| async move {
+                rx.recv().await.map(|b| (b, (rx, done)))
+            });
+            let chunks = stream::once(async { first }).chain(rest).map(|chunk| chunk.map_err(std::io::Error::other));
+            req = req.body(reqwest::Body::wrap_stream(chunks));
+            Some(done_rx)
+        }
+        None => None,
+    };
 
     // Forward request headers, skip hop-by-hop.
-    for (k, v) in &headers {
-        if !is_hop_by_hop(k) && k != "host" {
-            req = req.header(k, v);
+    let mut forwarded = reqwest::header::HeaderMap::new();
+    headers.append_to(&mut forwarded, |k| !is_hop_by_hop(k) && k != "host");
+    req = req.headers(forwarded);
+    // Set Host to the local server's so it responds normally, unless the
+    // route keeps the visitor's.
+    req = req.header("host", route.host(&headers));
+
+    // Like the relay server's, the deadline starts once the request body is
+    // through, so uploads take as long as they take, and only covers the
+    // response head so large downloads can stream freely. The relay server
+    // waits a little longer than `timeout`, so our 504 gets through.
+    let send = req.send();
+    tokio::pin!(send);
+    let answered = match uploaded {
+        Some(uploaded) => tokio::select! {
+            sent = &mut send => Some(sent),
+            _ = uploaded => None,
+        },
+        None => None,
+    };
+    let sent = match answered {
+        Some(sent) => Ok(sent),
+        None => tokio::time::timeout(timeout, send).await,
+    };
+    let mut response = match sent {
+        Ok(Ok(r)) => r,
+        Ok(Err(e)) => {
+            warn!("Local request failed: {e}");
+            let _ = tx.send(error_response(id, 502, "Local server unreachable")).await;
+            return;
         }
+        Err(_) => {
+            warn!("Local request timed out after {}s (id={id})", timeout.as_secs());
+            let _ = tx.send(error_response(id, 504, "Local server timed out")).await;
+            return;
+        }
+    };
+
+    let status = response.status().as_u16();
+
+    let resp_headers = Headers::from_http(response.headers(), |k| !is_hop_by_hop(k));
+
+    let head = ClientMessage::ResponseHead { id: id.clone(), status, headers: resp_headers };
+    if tx.send(head).await.is_err() {
+        return;
     }
-    // Set Host to localhost so the local server responds normally.
-    req = req.header("host", format!("localhost:{local_port}"));
 
-    let response = match req.send().await {
-        Ok(r) => r,
-        Err(e) => {
-            warn!("Local request failed: {e}");
-            return error_response(id, 502, "Local server unreachable");
+    loop {
+        match response.chunk().await {
+            Ok(Some(data)) => {
+                for data in protocol::chunks(data) {
+                    let room = window.reserve().await;
+                    let msg = ClientMessage::ResponseChunk { id: id.clone(), data };
+                    if tx.send(msg).await.is_err() {
+                        return;
+                    }
+                    room.spend();
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                // Headers are already sent — tell the relay server the body
+                // is incomplete rather than end it as if it were whole.
+                warn!("Local response body failed (id={id}): {e}");
+                let _ = tx.send(ClientMessage::Abort { id }).await;
+                return;
+            }
+        }
+    }
+
+    let _ = tx.send(ClientMessage::ResponseEnd { id }).await;
+}
+
+/// Open an upgraded connection (WebSocket, …) against the local server its
+/// path routes to and bridge it to the tunnel: StreamOpened first, then StreamData in both
+/// directions until either side sends StreamClose.
+///
+/// Uses a raw hyper connection rather than reqwest, which cannot hand back
+/// the upgraded socket.
+pub async fn handle_stream(
+    fwd: Forward,
+    data_rx: BodyReceiver<ClientMessage>,
+    window: Window,
+    routes: Arc<Routes>,
+    tx: mpsc::Sender<ClientMessage>,
+) {
+    let Forward { id, method, path, headers, timeout } = fwd;
+    let Some(route) = routes.resolve(&path) else {
+        warn!("No local route for {path} (id={id})");
+        let msg = ClientMessage::StreamOpened { id: id.clone(), status: 404, headers: Headers::default() };
+        let _ = tx.send(msg).await;
+        let _ = tx.send(ClientMessage::StreamClose { id }).await;
+        return;
+    };
+    debug!("Opening upgraded stream {method} {}", route.url(&path));
+
+    let opened = tokio::time::timeout(timeout, open_upgrade(route, &method, &path, &headers)).await;
+    let mut response = match opened {
+        Ok(Ok(r)) => r,
+        failed => {
+            let status = match failed {
+                Ok(Err(e)) => {
+                    warn!("Local upgrade request failed (id={id}): {e}");
+                    502
+                }
+                _ => {
+                    warn!("Local upgrade request timed out after {}s (id={id})", timeout.as_secs());
+                    504
+                }
+            };
+            let msg = ClientMessage::StreamOpened { id: id.clone(), status, headers: Headers::default() };
+            let _ = tx.send(msg).await;
+            let _ = tx.send(ClientMessage::StreamClose { id }).await;
+            return;
         }
     };
 
     let status = response.status().as_u16();
+    let resp_headers = Headers::from_http(response.headers(), |_| true);
+    let opened = ClientMessage::StreamOpened { id: id.clone(), status, headers: resp_headers };
+    if tx.send(opened).await.is_err() {
+        return;
+    }
 
-    let mut resp_headers = HashMap::new();
-    for (k, v) in response.headers() {
-        let key = k.as_str().to_lowercase();
-        if !is_hop_by_hop(&key) {
-            if let Ok(val) = v.to_str() {
-                resp_headers.insert(key, val.to_owned());
+    if status != 101 {
+        // The local server refused the upgrade — relay its (small) body as-is.
+        match response.into_body().collect().await {
+            Ok(body) => {
+                for data in protocol::chunks(body.to_bytes()) {
+                    let room = window.reserve().await;
+                    let msg = ClientMessage::StreamData { id: id.clone(), data };
+                    let _ = tx.send(msg).await;
+                    room.spend();
+                }
+                let _ = tx.send(ClientMessage::StreamClose { id }).await;
+            }
+            Err(e) => {
+                warn!("Local response body failed (id={id}): {e}");
+                let _ = tx.send(ClientMessage::Abort { id }).await;
             }
         }
+        return;
     }
 
-    let resp_body = response.bytes().await.unwrap_or_default();
-    let body_b64 = B64.encode(&resp_body);
+    match hyper::upgrade::on(&mut response).await {
+        Ok(upgraded) => bridge_stream(&id, TokioIo::new(upgraded), data_rx, &window, &tx).await,
+        Err(e) => warn!("Local upgrade failed (id={id}): {e}"),
+    }
+    let _ = tx.send(ClientMessage::StreamClose { id }).await;
+}
 
-    ClientMessage::Response {
-        id,
-        status,
-        headers: resp_headers,
-        body_b64,
+/// Connect a raw TCP connection opened on the relay (TcpOpen) to the local
+/// `address` of its service, then pump bytes both ways like an upgraded
+/// stream. `address` is `None` for a service this client doesn't expose.
+pub async fn handle_tcp(
+    id: String,
+    service: String,
+    address: Option<String>,
+    data_rx: BodyReceiver<ClientMessage>,
+    window: Window,
+    tx: mpsc::Sender<ClientMessage>,
+) {
+    let status = match &address {
+        None => {
+            warn!("No local service '{service}' (id={id})");
+            404
+        }
+        Some(address) => match tokio::time::timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(local)) => {
+                let opened = ClientMessage::StreamOpened { id: id.clone(), status: 200, headers: Headers::default() };
+                if tx.send(opened).await.is_ok() {
+                    bridge_stream(&id, local, data_rx, &window, &tx).await;
+                }
+                let _ = tx.send(ClientMessage::StreamClose { id }).await;
+                return;
+            }
+            Ok(Err(e)) => {
+                warn!("Local service '{service}' at {address} unreachable (id={id}): {e}");
+                502
+            }
+            Err(_) => {
+                warn!("Local service '{service}' at {address} did not accept in time (id={id})");
+                504
+            }
+        },
+    };
+    let msg = ClientMessage::StreamOpened { id: id.clone(), status, headers: Headers::default() };
+    let _ = tx.send(msg).await;
+    let _ = tx.send(ClientMessage::StreamClose { id }).await;
+}
+
+async fn open_upgrade(
+    route: &Route,
+    method: &str,
+    path: &str,
+    headers: &Headers,
+) -> anyhow::Result<hyper::Response<hyper::body::Incoming>> {
+    let stream = TcpStream::connect(&route.addr).await?;
+    match &route.tls {
+        Some(tls) => {
+            let stream = tls.connector.connect(tls.server_name.clone(), stream).await?;
+            send_upgrade(stream, route, method, path, headers).await
+        }
+        None => send_upgrade(stream, route, method, path, headers).await,
+    }
+}
+
+async fn send_upgrade<S>(
+    io: S,
+    route: &Route,
+    method: &str,
+    path: &str,
+    headers: &Headers,
+) -> anyhow::Result<hyper::Response<hyper::body::Incoming>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.with_upgrades().await {
+            debug!("Local upgraded connection ended: {e}");
+        }
+    });
+
+    let mut req = hyper::Request::builder().method(method).uri(route.target_path(path));
+    if let Some(map) = req.headers_mut() {
+        // Keep `upgrade` and `connection` — they are the whole point here.
+        headers.append_to(map, |k| k != "host" && k != "keep-alive" && k != "transfer-encoding");
+    }
+    req = req.header("host", route.host(headers));
+
+    Ok(sender.send_request(req.body(Empty::<Bytes>::new())?).await?)
+}
+
+/// Pump bytes between the local upgraded socket and the tunnel until either
+/// the local server closes the socket or the relay server sends StreamClose.
+/// The socket is only read while `window` has room.
+async fn bridge_stream<S>(
+    id: &str,
+    io: S,
+    mut data_rx: BodyReceiver<ClientMessage>,
+    window: &Window,
+    tx: &mpsc::Sender<ClientMessage>,
+)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    let (mut local_rd, mut local_wr) = tokio::io::split(io);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+
+    loop {
+        tokio::select! {
+            (room, n) = async { (window.reserve().await, local_rd.read(&mut buf).await) } => {
+                let n = match n {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let msg = ClientMessage::StreamData { id: id.to_owned(), data: Bytes::copy_from_slice(&buf[..n]) };
+                if tx.send(msg).await.is_err() {
+                    break;
+                }
+                room.spend();
+            }
+            data = data_rx.recv() => {
+                let Some(Ok(data)) = data else { break };
+                if local_wr.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        }
     }
+    let _ = local_wr.shutdown().await;
 }
 
 fn error_response(id: String, status: u16, msg: &str) -> ClientMessage {
     ClientMessage::Response {
         id,
         status,
-        headers: HashMap::new(),
+        headers: Headers::default(),
         body_b64: B64.encode(msg.as_bytes()),
     }
 }
@@ -1,36 +1,198 @@
 mod forwarder;
+pub mod routes;
 
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
-use tracing::{error, info, warn};
+use tokio::task::AbortHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info, warn};
 
-use crate::protocol::{ClientMessage, ServerMessage, read_msg, write_msg};
+use crate::protocol::{
+    BodySender, ClientMessage, Expose, Flow, Framing, MIN_SERVER_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    ServerMessage, ServiceSpec, Undelivered, capability, read_frame, read_msg, write_frame, write_msg,
+};
+use crate::timeouts::{TimeoutPolicy, DEFAULT_REQUEST_TIMEOUT};
+use crate::tls::ClientTls;
+use forwarder::Forward;
+use routes::Routes;
+
+/// How long a draining client waits for in-flight requests before closing anyway.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why a session ended.
+enum SessionEnd {
+    /// Connection lost — reconnect.
+    Disconnected,
+    /// Shut down on request after draining — exit.
+    Drained,
+    /// The relay server is shutting down — reconnect without waiting.
+    Goodbye,
+}
+
+/// Relay client settings, from the `client` subcommand and its config file.
+pub struct ClientConfig {
+    /// "host:port" of the relay server.
+    pub server: String,
+    pub slug: String,
+    pub token: Token,
+    pub routes: Routes,
+    /// Deadline preferences announced at registration.
+    pub timeouts: TimeoutPolicy,
+    /// Raw TCP services to expose through the relay.
+    pub services: Vec<LocalService>,
+    pub tls: Option<ClientTls>,
+}
+
+/// Where the registration token comes from.
+pub enum Token {
+    Fixed(String),
+    /// Read again before every registration, so a rotated token is picked
+    /// up without a restart.
+    File(PathBuf),
+}
+
+impl Token {
+    pub fn read(&self) -> anyhow::Result<String> {
+        match self {
+            Token::Fixed(token) => Ok(token.clone()),
+            Token::File(path) => {
+                let token = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading token file {}", path.display()))?;
+                Ok(token.trim().to_owned())
+            }
+        }
+    }
+}
+
+/// A raw TCP service exposed through the relay, as `NAME=HOST:PORT`.
+#[derive(Clone, Debug)]
+pub struct LocalService {
+    pub name: String,
+    /// "host:port" of the local server.
+    pub address: String,
+    pub expose: Expose,
+}
+
+impl FromStr for LocalService {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, address) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=HOST:PORT, got '{s}'"))?;
+        let (name, address) = (name.trim(), address.trim());
+        if !ServiceSpec::valid_name(name) {
+            return Err(format!("service name '{name}' must be a DNS label (a-z, 0-9, '-')"));
+        }
+        if address.rsplit_once(':').is_none_or(|(_, port)| port.parse::<u16>().is_err()) {
+            return Err(format!("expected HOST:PORT for service '{name}', got '{address}'"));
+        }
+        Ok(Self { name: name.to_owned(), address: address.to_owned(), expose: Expose::Port })
+    }
+}
+
+/// Re-reads the configuration, on SIGHUP.
+pub type Reload = Box<dyn Fn() -> anyhow::Result<ClientConfig> + Send + Sync>;
+
+/// Settings a reload can change without reconnecting.
+struct Live {
+    routes: RwLock<Arc<Routes>>,
+    /// Used at the next registration.
+    token: RwLock<Token>,
+    /// Announced at the next registration.
+    timeouts: RwLock<TimeoutPolicy>,
+    /// Announced at the next registration.
+    services: RwLock<Arc<[LocalService]>>,
+}
 
 // ── Entry point with reconnect loop ──────────────────────────────────────────
 
-pub async fn run(
-    server_addr: &str,
-    slug: &str,
-    token: &str,
-    local_port: u16,
-) -> anyhow::Result<()> {
+pub async fn run(config: ClientConfig, reload: Option<Reload>) -> anyhow::Result<()> {
+    let ClientConfig { server, slug, token, routes, timeouts, services, tls } = config;
+    let server_addr = server.as_str();
     let mut backoff = Duration::from_secs(1);
     let max_backoff = Duration::from_secs(30);
 
     info!("nodyx-relay client starting");
     info!("  Server    : {server_addr}");
     info!("  Slug      : {slug}");
-    info!("  Local     : localhost:{local_port}");
+    for route in routes.describe() {
+        info!("  Route     : {route}");
+    }
+    info!("  Transport : {}", if tls.is_some() { "TLS" } else { "plain TCP" });
+    if tls.is_none() {
+        warn!("--insecure: the token and all relayed traffic cross the network unencrypted");
+    }
+    if let Some(ms) = timeouts.default_ms {
+        info!("  Timeout   : {}s ({} override(s))", ms / 1000, timeouts.rules.len());
+    }
+    for service in &services {
+        info!("  Service   : {} → {} ({:?})", service.name, service.address, service.expose);
+    }
+
+    let live = Arc::new(Live {
+        routes: RwLock::new(Arc::new(routes)),
+        token: RwLock::new(token),
+        timeouts: RwLock::new(timeouts),
+        services: RwLock::new(services.into()),
+    });
+    let identity = (server.clone(), slug.clone());
+    tokio::spawn(reload_on_hangup(reload, identity, live.clone()));
+
+    // Ctrl-C or SIGTERM hands the slug over to the other relay clients
+    // serving it, if any.
+    let shutdown = CancellationToken::new();
+    {
+        let shutdown = shutdown.clone();
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => info!("Interrupt received — draining"),
+                _ = terminate.recv() => info!("SIGTERM received — draining"),
+            }
+            shutdown.cancel();
+        });
+    }
 
     loop {
         info!("Connecting to relay server {server_addr}...");
         match TcpStream::connect(server_addr).await {
             Ok(stream) => {
-                backoff = Duration::from_secs(1); // reset on successful connect
-                info!("Connected. Registering slug '{slug}'...");
-                if let Err(e) = handle_session(stream, slug, token, local_port).await {
-                    warn!("Session ended: {e}");
+                let result = match &tls {
+                    Some(tls) => match tls.connector.connect(tls.server_name.clone(), stream).await {
+                        Ok(stream) => {
+                            backoff = Duration::from_secs(1); // reset on successful connect
+                            info!("Connected (TLS). Registering slug '{slug}'...");
+                            handle_session(stream, &slug, &live, &shutdown).await
+                        }
+                        Err(e) => {
+                            error!("TLS handshake failed: {e} (a relay server without TLS needs --insecure)");
+                            Ok(SessionEnd::Disconnected)
+                        }
+                    },
+                    None => {
+                        backoff = Duration::from_secs(1); // reset on successful connect
+                        info!("Connected. Registering slug '{slug}'...");
+                        handle_session(stream, &slug, &live, &shutdown).await
+                    }
+                };
+                match result {
+                    Ok(SessionEnd::Drained) => return Ok(()),
+                    Ok(SessionEnd::Goodbye) => {
+                        info!("Relay server is shutting down — reconnecting");
+                        continue;
+                    }
+                    Ok(SessionEnd::Disconnected) => {}
+                    Err(e) => warn!("Session ended: {e}"),
                 }
             }
             Err(e) => {
@@ -38,36 +200,124 @@ pub async fn run(
             }
         }
 
+        if shutdown.is_cancelled() {
+            return Ok(());
+        }
         info!("Reconnecting in {}s...", backoff.as_secs());
-        tokio::time::sleep(backoff).await;
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
         backoff = (backoff * 2).min(max_backoff);
     }
 }
 
+/// Apply the routes, token and timeouts of the configuration again on every
+/// SIGHUP. A configuration that fails to load leaves the current one in place;
+/// `identity` (server, slug) only changes with a restart.
+async fn reload_on_hangup(reload: Option<Reload>, identity: (String, String), live: Arc<Live>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, configuration reload disabled: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let Some(reload) = &reload else {
+            warn!("SIGHUP received, but there is no --config file to reload");
+            continue;
+        };
+        match reload() {
+            Ok(config) => {
+                for route in config.routes.describe() {
+                    info!("  Route     : {route}");
+                }
+                *live.routes.write().unwrap() = Arc::new(config.routes);
+                *live.token.write().unwrap() = config.token;
+                *live.timeouts.write().unwrap() = config.timeouts;
+                *live.services.write().unwrap() = config.services.into();
+                info!("Configuration reloaded — token, timeout and service changes apply from the next connection");
+                if (config.server, config.slug) != identity {
+                    warn!("Server or slug changes take effect after a restart");
+                }
+            }
+            Err(e) => error!("Configuration reload failed, keeping the current settings: {e:#}"),
+        }
+    }
+}
+
 // ── Single session ────────────────────────────────────────────────────────────
 
-async fn handle_session(
-    mut stream: TcpStream,
+async fn handle_session<S>(
+    mut stream: S,
     slug: &str,
-    token: &str,
-    local_port: u16,
-) -> anyhow::Result<()> {
+    live: &Arc<Live>,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<SessionEnd>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let token = live.token.read().unwrap().read()?;
+    let timeouts = live.timeouts.read().unwrap().clone();
+    let services = live.services.read().unwrap().clone();
+
     // 1. Send Register.
     write_msg(
         &mut stream,
         &ClientMessage::Register {
             slug: slug.to_owned(),
-            token: token.to_owned(),
+            token,
+            version: PROTOCOL_VERSION,
+            capabilities: capability::all(),
+            framings: Framing::SUPPORTED.to_vec(),
+            timeouts: timeouts.clone(),
+            services: services
+                .iter()
+                .map(|s| ServiceSpec { name: s.name.clone(), expose: s.expose })
+                .collect(),
         },
     )
     .await?;
 
     // 2. Wait for Registered confirmation.
-    match read_msg::<_, ServerMessage>(&mut stream).await? {
-        Some(ServerMessage::Registered { ok: true, .. }) => {
-            info!("Relay registered — '{slug}.nodyx.org' is live");
+    // Older servers don't announce a framing and only speak JSON.
+    let (framing, can_drain, header_list, can_abort, credit) = match read_msg::<_, ServerMessage>(&mut stream).await? {
+        Some(ServerMessage::Registered { ok: true, version, .. }) if version < MIN_SERVER_PROTOCOL_VERSION => {
+            return Err(anyhow::anyhow!(
+                "Relay server speaks protocol v{version}, this client needs v{MIN_SERVER_PROTOCOL_VERSION}+ \
+                 — the relay server must be upgraded"
+            ));
         }
-        Some(ServerMessage::Registered { ok: false, error }) => {
+        Some(ServerMessage::Registered { ok: true, version, capabilities, framing, services: grants, host, .. }) => {
+            let framing = framing.unwrap_or(Framing::Json);
+            // Older servers don't say which domain the instance is served under.
+            let public = host.map_or_else(|| format!("slug '{slug}'"), |host| format!("'{host}'"));
+            info!("Relay registered — {public} is live (protocol v{version}, {framing:?} framing)");
+            debug!("Negotiated capabilities: {capabilities:?}");
+            let has = |name| capabilities.iter().any(|c| c == name);
+            if !services.is_empty() && !has(capability::TCP) {
+                warn!("Relay server does not support TCP services — none exposed");
+            }
+            for grant in grants {
+                match (grant.address, grant.error) {
+                    (Some(address), _) => info!("Service '{}' is live at {address}", grant.name),
+                    (None, error) => warn!(
+                        "Service '{}' refused by the relay server: {}",
+                        grant.name,
+                        error.as_deref().unwrap_or("unknown error"),
+                    ),
+                }
+            }
+            (
+                framing,
+                has(capability::DRAIN),
+                has(capability::HEADER_LIST),
+                has(capability::ABORT),
+                has(capability::CREDIT),
+            )
+        }
+        Some(ServerMessage::Registered { ok: false, error, .. }) => {
             return Err(anyhow::anyhow!(
                 "Registration rejected: {}",
                 error.unwrap_or_else(|| "unknown error".into())
@@ -76,43 +326,142 @@ async fn handle_session(
         other => {
             return Err(anyhow::anyhow!("Unexpected message: {other:?}"));
         }
-    }
+    };
 
     // 3. Split stream: concurrent reader + serialized writer.
-    let (mut reader, mut writer) = stream.into_split();
+    let (mut reader, mut writer) = tokio::io::split(stream);
 
     // Channel to serialize all writes back to the relay server.
     // Multiple concurrent request handlers send their responses here;
     // the write task drains it in order so writes are never concurrent.
     let (resp_tx, mut resp_rx) = mpsc::channel::<ClientMessage>(256);
 
+    // Kept to announce a drain.
+    let drain_tx = resp_tx.clone();
+    // Local request handlers, so a drain can wait for them.
+    let tracker = TaskTracker::new();
+
     // Write task — drains the response channel and writes to TCP stream.
-    let write_task = tokio::spawn(async move {
-        while let Some(msg) = resp_rx.recv().await {
-            if write_msg(&mut writer, &msg).await.is_err() {
+    let mut write_task = tokio::spawn(async move {
+        while let Some(mut msg) = resp_rx.recv().await {
+            if let ClientMessage::Abort { id } = &msg {
+                // Older servers can only be told the body is over.
+                if !can_abort {
+                    msg = ClientMessage::ResponseEnd { id: id.clone() };
+                }
+            }
+            if !header_list {
+                // Older servers only read a name → value map.
+                if let Some(headers) = msg.headers_mut() {
+                    let lost = headers.flatten();
+                    if !lost.is_empty() {
+                        warn!("Relay server too old for these headers, dropped: {}", lost.join(", "));
+                    }
+                }
+            }
+            if write_frame(&mut writer, &msg, framing).await.is_err() {
                 break;
             }
         }
     });
 
+    // Deadline for a request: the one the relay server settled on, or our
+    // own settings if the server predates timeout negotiation.
+    let local_timeouts = timeouts;
+    let deadline = move |path: &str, timeout_ms: Option<u64>| match timeout_ms {
+        Some(ms) => Duration::from_millis(ms),
+        None => local_timeouts
+            .rule_for(path)
+            .or(local_timeouts.default_ms.map(Duration::from_millis))
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+    };
+
     // Read task — reads requests from the relay server and spawns a concurrent
     // handler per request so that long-polling GETs don't block other requests.
-    let read_task = tokio::spawn(async move {
+    let tracker_r = tracker.clone();
+    let live = live.clone();
+    let flow = Flow::new(credit, resp_tx.clone(), |id, chunks| ClientMessage::Credit { id, chunks });
+    let mut read_task = tokio::spawn(async move {
+        // Request bodies still being received and upgraded streams, keyed by
+        // request id. Those still open when the session ends are cut short.
+        let mut bodies: HashMap<String, BodySender> = HashMap::new();
+        // Local request handlers, so a cancelled request can be aborted.
+        let mut running: HashMap<String, AbortHandle> = HashMap::new();
+
         loop {
-            match read_msg::<_, ServerMessage>(&mut reader).await {
-                Ok(Some(ServerMessage::Request { id, method, path, headers, body_b64 })) => {
+            match read_frame::<_, ServerMessage>(&mut reader, framing).await {
+                Ok(Some(ServerMessage::Request { id, method, path, headers, timeout_ms, .. })) => {
+                    let (body_tx, body_rx) = flow.receive(&id);
+                    bodies.insert(id.clone(), body_tx);
+                    let tx = resp_tx.clone();
+                    let timeout = deadline(&path, timeout_ms);
+                    let window = flow.send(&id);
+                    let fwd = Forward { id: id.clone(), method, path, headers, timeout };
+                    let routes = live.routes.read().unwrap().clone();
+                    let task = tracker_r.spawn(forwarder::handle_request(fwd, body_rx, window, routes, tx));
+                    running.insert(id, task.abort_handle());
+                }
+                Ok(Some(ServerMessage::StreamOpen { id, method, path, headers, timeout_ms })) => {
+                    let (data_tx, data_rx) = flow.receive(&id);
+                    bodies.insert(id.clone(), data_tx);
                     let tx = resp_tx.clone();
-                    tokio::spawn(async move {
-                        let msg = forwarder::handle_request(
-                            id, method, path, headers, body_b64, local_port,
-                        )
-                        .await;
-                        let _ = tx.send(msg).await;
-                    });
+                    let timeout = deadline(&path, timeout_ms);
+                    let window = flow.send(&id);
+                    let fwd = Forward { id: id.clone(), method, path, headers, timeout };
+                    let routes = live.routes.read().unwrap().clone();
+                    let task = tracker_r.spawn(forwarder::handle_stream(fwd, data_rx, window, routes, tx));
+                    running.insert(id, task.abort_handle());
+                }
+                Ok(Some(ServerMessage::TcpOpen { id, service, peer })) => {
+                    let (data_tx, data_rx) = flow.receive(&id);
+                    bodies.insert(id.clone(), data_tx);
+                    let tx = resp_tx.clone();
+                    let address = services.iter().find(|s| s.name == service).map(|s| s.address.clone());
+                    let window = flow.send(&id);
+                    debug!("TCP connection to service '{service}' from {peer} (id={id})");
+                    let task = tracker_r.spawn(forwarder::handle_tcp(id.clone(), service, address, data_rx, window, tx));
+                    running.insert(id, task.abort_handle());
+                }
+                Ok(Some(
+                    ServerMessage::RequestChunk { id, data }
+                    | ServerMessage::StreamData { id, data },
+                )) => {
+                    let Some(body_tx) = bodies.get(&id) else { continue };
+                    match body_tx.send(data).await {
+                        Ok(()) => {}
+                        // The local request or stream already ended — discard the rest.
+                        Err(Undelivered::Closed) => {
+                            bodies.remove(&id);
+                        }
+                        // The relay server ignored its window: cut the body short,
+                        // which aborts the local request.
+                        Err(Undelivered::Overrun) => {
+                            bodies.remove(&id);
+                            warn!("Relay server sent more of {id} than it had room for — dropping it");
+                        }
+                    }
+                }
+                Ok(Some(ServerMessage::Credit { id, chunks })) => {
+                    flow.grant(&id, chunks);
+                }
+                Ok(Some(ServerMessage::RequestEnd { id } | ServerMessage::StreamClose { id })) => {
+                    if let Some(body_tx) = bodies.remove(&id) {
+                        body_tx.finish();
+                    }
+                }
+                Ok(Some(ServerMessage::Cancel { id })) => {
+                    // Dropping the handler drops its local connection too.
+                    bodies.remove(&id);
+                    if let Some(task) = running.remove(&id) {
+                        task.abort();
+                        debug!("Request {id} cancelled by the relay server");
+                    }
                 }
                 Ok(Some(ServerMessage::Ping)) => {
                     let _ = resp_tx.send(ClientMessage::Heartbeat).await;
+                    running.retain(|_, task| !task.is_finished());
                 }
+                Ok(Some(ServerMessage::Goodbye)) => return SessionEnd::Goodbye,
                 Ok(Some(ServerMessage::Registered { .. })) => {
                     warn!("Unexpected Registered message — ignoring");
                 }
@@ -126,13 +475,40 @@ async fn handle_session(
                 }
             }
         }
+        SessionEnd::Disconnected
     });
 
-    // Wait until either task ends (connection dropped or error).
+    // Wait until either task ends (connection dropped or error), or a shutdown.
     tokio::select! {
-        _ = write_task => {}
-        _ = read_task  => {}
+        _ = &mut write_task => {}
+        end = &mut read_task => {
+            if let Ok(SessionEnd::Goodbye) = end {
+                write_task.abort();
+                return Ok(SessionEnd::Goodbye);
+            }
+        }
+        _ = shutdown.cancelled() => {
+            if !can_drain {
+                warn!("Relay server does not support draining — closing now");
+                return Ok(SessionEnd::Drained);
+            }
+            let _ = drain_tx.send(ClientMessage::Drain).await;
+            info!("Draining: waiting for {} in-flight request(s)", tracker.len());
+
+            // The read task keeps feeding request bodies to the handlers meanwhile.
+            tracker.close();
+            if tokio::time::timeout(DRAIN_TIMEOUT, tracker.wait()).await.is_err() {
+                warn!("Drain timed out with {} request(s) still running", tracker.len());
+            }
+
+            // Let the write task flush what the handlers queued, then close.
+            read_task.abort();
+            drop(drain_tx);
+            let _ = tokio::time::timeout(Duration::from_secs(5), write_task).await;
+            info!("Drained — exiting");
+            return Ok(SessionEnd::Drained);
+        }
     }
 
-    Ok(())
+    Ok(SessionEnd::Disconnected)
 }
@@ -0,0 +1,199 @@
+//! Local routing: which local server a tunnelled request is forwarded to.
+//!
+//! By default everything goes to `127.0.0.1:{--local-port}`. Instances that
+//! run several processes (frontend, API, …) describe them in a routes file:
+//!
+//! ```toml
+//! [[route]]
+//! prefix   = "/api"
+//! upstream = "127.0.0.1:3000"
+//!
+//! [[route]]
+//! prefix   = "/"
+//! upstream = "frontend.internal:8443"
+//! tls      = true                      # HTTPS to the local server
+//! ca       = "/etc/nodyx/local-ca.pem" # trusted instead of the built-in roots
+//! preserve_host = true                 # send the visitor's Host header
+//! ```
+//!
+//! The same `[[route]]` tables may also go straight into the client's
+//! `--config` file. The longest matching prefix wins. A prefix matches whole path segments:
+//! `/api` matches `/api` and `/api/users`, not `/apix`.
+
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::protocol::Headers;
+use crate::tls::{self, ClientTls, ClientTlsOptions};
+
+/// One `[[route]]` entry of the routes file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    prefix: String,
+    /// "host:port" of the local server.
+    upstream: String,
+    #[serde(default)]
+    tls: bool,
+    /// PEM CA bundle for `tls`, instead of the built-in roots.
+    ca: Option<PathBuf>,
+    /// Host header sent upstream. Defaults to `upstream`.
+    host: Option<String>,
+    /// Send the Host the visitor asked for (`slug.nodyx.org`, a custom
+    /// domain) instead of `host`.
+    #[serde(default)]
+    preserve_host: bool,
+    /// Remove the prefix from the path before forwarding.
+    #[serde(default)]
+    strip_prefix: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoutesFile {
+    route: Vec<RouteConfig>,
+}
+
+/// A local server requests can be forwarded to.
+pub struct Route {
+    prefix: String,
+    /// "http(s)://host:port".
+    base_url: String,
+    /// "host:port", for upgraded connections.
+    pub addr: String,
+    /// Host header sent to the local server.
+    host: String,
+    preserve_host: bool,
+    strip_prefix: bool,
+    /// For plain requests; keeps connections to the local server alive.
+    pub client: reqwest::Client,
+    /// For upgraded connections, which bypass reqwest. `None` = plain TCP.
+    pub tls: Option<ClientTls>,
+}
+
+impl Route {
+    fn new(config: RouteConfig) -> anyhow::Result<Self> {
+        if !config.prefix.starts_with('/') {
+            bail!("route prefix '{}' must start with '/'", config.prefix);
+        }
+        if config.ca.is_some() && !config.tls {
+            bail!("route '{}': `ca` requires `tls = true`", config.prefix);
+        }
+        if config.host.is_some() && config.preserve_host {
+            bail!("route '{}': use either `host` or `preserve_host`", config.prefix);
+        }
+
+        let mut client = reqwest::Client::builder();
+        let tls = if config.tls {
+            if let Some(path) = &config.ca {
+                let pem = std::fs::read(path)
+                    .with_context(|| format!("reading TLS CA bundle {}", path.display()))?;
+                for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+                    client = client.add_root_certificate(cert);
+                }
+                client = client.tls_built_in_root_certs(false);
+            }
+            let opts = ClientTlsOptions { ca: config.ca.clone(), ..Default::default() };
+            Some(tls::client_connector(&config.upstream, &opts)?)
+        } else {
+            None
+        };
+
+        let scheme = if config.tls { "https" } else { "http" };
+        Ok(Self {
+            base_url: format!("{scheme}://{}", config.upstream),
+            host: config.host.unwrap_or_else(|| config.upstream.clone()),
+            preserve_host: config.preserve_host,
+            prefix: config.prefix,
+            addr: config.upstream,
+            strip_prefix: config.strip_prefix,
+            client: client.build()?,
+            tls,
+        })
+    }
+
+    /// Host header for a request carrying `headers`: the visitor's with
+    /// `preserve_host`, else ours.
+    pub fn host<'a>(&'a self, headers: &'a Headers) -> &'a str {
+        match headers.get("host") {
+            Some(host) if self.preserve_host => host,
+            _ => &self.host,
+        }
+    }
+
+    /// Full URL for `path` (as received, query string included).
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, self.target_path(path))
+    }
+
+    /// Path to request from the local server.
+    pub fn target_path(&self, path: &str) -> String {
+        if !self.strip_prefix || self.prefix == "/" {
+            return path.to_owned();
+        }
+        let rest = &path[self.prefix.trim_end_matches('/').len()..];
+        if rest.starts_with('/') {
+            rest.to_owned()
+        } else {
+            format!("/{rest}")
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or(path);
+        let prefix = self.prefix.trim_end_matches('/');
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// The routing table, longest prefix first.
+pub struct Routes {
+    routes: Vec<Route>,
+}
+
+impl Routes {
+    /// Everything to `127.0.0.1:{port}`, as `localhost` unless `preserve_host`
+    /// (the `--local-port` default).
+    pub fn local(port: u16, preserve_host: bool) -> anyhow::Result<Self> {
+        let route = Route::new(RouteConfig {
+            prefix: "/".into(),
+            upstream: format!("127.0.0.1:{port}"),
+            tls: false,
+            ca: None,
+            host: (!preserve_host).then(|| format!("localhost:{port}")),
+            preserve_host,
+            strip_prefix: false,
+        })?;
+        Ok(Self { routes: vec![route] })
+    }
+
+    /// Load a routes file (see the module docs).
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading routes file {}", path.display()))?;
+        let file: RoutesFile = toml::from_str(&text)
+            .with_context(|| format!("parsing routes file {}", path.display()))?;
+        Self::from_configs(file.route)
+    }
+
+    pub fn from_configs(configs: Vec<RouteConfig>) -> anyhow::Result<Self> {
+        if configs.is_empty() {
+            bail!("no routes defined");
+        }
+        let mut routes = configs.into_iter().map(Route::new).collect::<anyhow::Result<Vec<_>>>()?;
+        routes.sort_by_key(|r| std::cmp::Reverse(r.prefix.trim_end_matches('/').len()));
+        Ok(Self { routes })
+    }
+
+    /// The route serving `path`, if any.
+    pub fn resolve(&self, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|r| r.matches(path))
+    }
+
+    /// One "prefix → url" line per route, for the startup log.
+    pub fn describe(&self) -> Vec<String> {
+        self.routes.iter().map(|r| format!("{} → {}", r.prefix, r.base_url)).collect()
+    }
+}
@@ -0,0 +1,199 @@
+//! `--config` files: TOML documents holding any of a subcommand's settings,
+//! under the flag names with underscores (`tcp_port`, `database_url`, …).
+//! Repeatable flags take arrays (`domains`, `trusted_proxies`, `timeout_overrides`,
+//! `tls_pins`, `tcp_services`, `sni_services`).
+//!
+//! A flag or environment variable wins over the file, which wins over the
+//! built-in defaults. The client file may also hold `[[route]]` tables, as in
+//! a routes file.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use anyhow::{bail, Context};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, FromArgMatches};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::client::routes::RouteConfig;
+use crate::server::registry::Balance;
+use crate::{ClientArgs, ServerArgs};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerFile {
+    tcp_port: Option<u16>,
+    http_port: Option<u16>,
+    database_url: Option<String>,
+    main_slug: Option<String>,
+    domains: Option<Vec<String>>,
+    upstream: Option<String>,
+    trusted_proxies: Option<Vec<String>>,
+    balance: Option<Balance>,
+    admin_bind: Option<String>,
+    admin_token: Option<String>,
+    metrics_bind: Option<String>,
+    request_timeout: Option<u64>,
+    timeout_overrides: Option<Vec<String>>,
+    max_request_timeout: Option<u64>,
+    offline_page: Option<PathBuf>,
+    offline_cache_mb: Option<usize>,
+    shutdown_timeout: Option<u64>,
+    auth_max_failures: Option<u32>,
+    auth_window: Option<u64>,
+    auth_ban: Option<u64>,
+    auth_max_ban: Option<u64>,
+    cluster_bind: Option<String>,
+    cluster_advertise: Option<String>,
+    cluster_secret: Option<String>,
+    tcp_ports: Option<String>,
+    sni_bind: Option<String>,
+    access_log: Option<String>,
+    access_log_sample: Option<f64>,
+    access_log_max_mb: Option<u64>,
+    access_log_keep: Option<usize>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientFile {
+    server: Option<String>,
+    slug: Option<String>,
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    local_port: Option<u16>,
+    routes: Option<PathBuf>,
+    route: Option<Vec<RouteConfig>>,
+    preserve_host: Option<bool>,
+    tls: Option<bool>,
+    insecure: Option<bool>,
+    tls_ca: Option<PathBuf>,
+    tls_pins: Option<Vec<String>>,
+    tls_server_name: Option<String>,
+    request_timeout: Option<u64>,
+    timeout_overrides: Option<Vec<String>>,
+    tcp_services: Option<Vec<String>>,
+    sni_services: Option<Vec<String>>,
+}
+
+/// Copy each listed setting from the file unless it was given as a flag or
+/// environment variable.
+macro_rules! merge {
+    ($matches:expr, $args:ident, $file:ident; $($field:ident),+ $(,)?) => {
+        $(
+            if let Some(value) = $file.$field {
+                if !explicit($matches, stringify!($field)) {
+                    $args.$field = value.into();
+                }
+            }
+        )+
+    };
+}
+
+/// `server` arguments parsed from `matches`, completed from `--config`.
+pub fn server_args(matches: &ArgMatches) -> anyhow::Result<ServerArgs> {
+    let mut args = ServerArgs::from_arg_matches(matches)?;
+    let Some(path) = args.config.clone() else { return Ok(args) };
+    let file: ServerFile = load(&path)?;
+
+    if let Some(rules) = &file.timeout_overrides {
+        if !explicit(matches, "timeout_overrides") {
+            args.timeout_overrides = parse_list(rules, &path)?;
+        }
+    }
+    if let Some(proxies) = &file.trusted_proxies {
+        if !explicit(matches, "trusted_proxies") {
+            args.trusted_proxies = parse_list(proxies, &path)?;
+        }
+    }
+    if let Some(range) = &file.tcp_ports {
+        if !explicit(matches, "tcp_ports") {
+            let range = range.parse().map_err(|e: String| anyhow::anyhow!("{}: {e}", path.display()))?;
+            args.tcp_ports = Some(range);
+        }
+    }
+    if let Some(target) = &file.access_log {
+        if !explicit(matches, "access_log") {
+            let target = target.parse().map_err(|e: String| anyhow::anyhow!("{}: {e}", path.display()))?;
+            args.access_log = Some(target);
+        }
+    }
+    merge!(matches, args, file;
+        tcp_port, http_port, database_url, main_slug, domains, upstream, balance,
+        admin_bind, admin_token, metrics_bind, request_timeout, max_request_timeout,
+        offline_page, offline_cache_mb, shutdown_timeout, auth_max_failures, auth_window,
+        auth_ban, auth_max_ban, cluster_bind, cluster_advertise, cluster_secret, sni_bind, access_log_sample, access_log_max_mb, access_log_keep,
+        tls_cert, tls_key,
+    );
+    Ok(args)
+}
+
+/// `client` arguments parsed from `matches`, completed from `--config`.
+pub fn client_args(matches: &ArgMatches) -> anyhow::Result<ClientArgs> {
+    let mut args = ClientArgs::from_arg_matches(matches)?;
+    let Some(path) = args.config.clone() else { return Ok(args) };
+    let file: ClientFile = load(&path)?;
+
+    if let Some(rules) = &file.timeout_overrides {
+        if !explicit(matches, "timeout_overrides") {
+            args.timeout_overrides = parse_list(rules, &path)?;
+        }
+    }
+    if let Some(services) = &file.tcp_services {
+        if !explicit(matches, "tcp_services") {
+            args.tcp_services = parse_list(services, &path)?;
+        }
+    }
+    if let Some(services) = &file.sni_services {
+        if !explicit(matches, "sni_services") {
+            args.sni_services = parse_list(services, &path)?;
+        }
+    }
+
+    // Local routing is one setting: a flag for any of it replaces the file's.
+    let in_file = [file.local_port.is_some(), file.routes.is_some(), file.route.is_some()];
+    if in_file.iter().filter(|set| **set).count() > 1 {
+        bail!("{}: use only one of `local_port`, `routes` and `[[route]]`", path.display());
+    }
+    if !explicit(matches, "local_port") && !explicit(matches, "routes") {
+        let ClientFile { local_port, routes, route, .. } = file;
+        if let Some(port) = local_port {
+            args.local_port = port;
+        }
+        args.routes = routes;
+        args.route = route.unwrap_or_default();
+    }
+
+    // Same for the token.
+    if !explicit(matches, "token") && !explicit(matches, "token_file") {
+        args.token = file.token;
+        args.token_file = file.token_file;
+    }
+
+    merge!(matches, args, file;
+        server, slug, preserve_host, tls, insecure, tls_ca, tls_pins, tls_server_name, request_timeout,
+    );
+    Ok(args)
+}
+
+fn load<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading config file {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("parsing config file {}", path.display()))
+}
+
+fn parse_list<T: FromStr<Err = String>>(items: &[String], path: &Path) -> anyhow::Result<Vec<T>> {
+    items
+        .iter()
+        .map(|item| item.parse().map_err(|e: String| anyhow::anyhow!("{}: {e}", path.display())))
+        .collect()
+}
+
+/// Whether `id` was given on the command line or in the environment.
+fn explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches
+        .value_source(id)
+        .is_some_and(|source| source != ValueSource::DefaultValue)
+}
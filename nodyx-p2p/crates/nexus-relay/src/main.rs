@@ -1,8 +1,25 @@
 mod client;
+mod config;
 mod protocol;
 mod server;
+mod timeouts;
+mod tls;
 
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use client::routes::{RouteConfig, Routes};
+use client::{LocalService, Token};
+use protocol::Expose;
+use server::access_log::{AccessLogConfig, LogTarget};
+use server::bans::BanPolicy;
+use server::cluster::{self, ClusterConfig};
+use server::forwarding::TrustedProxy;
+use server::registry::Balance;
+use server::services::{PortRange, ServicesConfig};
+use timeouts::{ServerTimeouts, TimeoutPolicy, TimeoutRule};
 use tracing_subscriber::{EnvFilter, fmt};
 
 // ── CLI ───────────────────────────────────────────────────────────────────────
@@ -22,44 +39,268 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Run the relay server (on nodyx.org VPS).
-    Server {
-        /// TCP port for relay client connections.
-        #[arg(long, default_value = "7443")]
-        tcp_port: u16,
-
-        /// HTTP port for Caddy reverse proxy.
-        #[arg(long, default_value = "7001")]
-        http_port: u16,
-
-        /// PostgreSQL connection string.
-        /// Defaults to DATABASE_URL environment variable.
-        #[arg(long, env = "DATABASE_URL")]
-        database_url: String,
-
-        /// The main community slug hosted on this VPS (excluded from relay routing).
-        /// Defaults to RELAY_MAIN_SLUG environment variable.
-        #[arg(long, env = "RELAY_MAIN_SLUG", default_value = "nodyxnode")]
-        main_slug: String,
-    },
+    Server(ServerArgs),
 
     /// Run the relay client (on a user's Nodyx instance).
-    Client {
-        /// Address of the relay server.
-        #[arg(long, default_value = "relay.nodyx.org:7443")]
-        server: String,
-
-        /// The slug to register (e.g. "moncommunaute").
-        #[arg(long)]
-        slug: String,
-
-        /// Authentication token from nodyx.org directory registration.
-        #[arg(long, env = "NODYX_RELAY_TOKEN")]
-        token: String,
-
-        /// Local HTTP port to forward traffic to.
-        #[arg(long, default_value = "80")]
-        local_port: u16,
-    },
+    Client(ClientArgs),
+}
+
+#[derive(Args)]
+struct ServerArgs {
+    /// TOML file with any of the settings below, keyed by flag name with
+    /// underscores (e.g. `tcp_port = 7443`). Flags and environment variables
+    /// take precedence. Sending SIGHUP reloads all but ports, binds and the database.
+    #[arg(long, env = "RELAY_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// TCP port for relay client connections.
+    #[arg(long, default_value = "7443")]
+    tcp_port: u16,
+
+    /// HTTP port for Caddy reverse proxy.
+    #[arg(long, default_value = "7001")]
+    http_port: u16,
+
+    /// PostgreSQL connection string.
+    /// Defaults to DATABASE_URL environment variable.
+    #[arg(long, env = "DATABASE_URL")]
+    database_url: Option<String>,
+
+    /// The main community slug hosted on this VPS (excluded from relay routing).
+    /// Defaults to RELAY_MAIN_SLUG environment variable.
+    #[arg(long, env = "RELAY_MAIN_SLUG", default_value = "nodyxnode")]
+    main_slug: String,
+
+    /// Base domain served by this relay: `slug.<domain>` routes to `slug`.
+    /// May be repeated (or comma-separated in RELAY_DOMAINS); the first is canonical.
+    /// Custom domains are mapped to slugs in the `relay_custom_domains` table.
+    #[arg(
+        long = "domain",
+        env = "RELAY_DOMAINS",
+        value_delimiter = ',',
+        default_value = "nodyx.org"
+    )]
+    domains: Vec<String>,
+
+    /// Local HTTP server for the main slug and unknown hosts (e.g. 127.0.0.1:3000).
+    /// When omitted, those requests are redirected to the first --domain.
+    #[arg(long, env = "RELAY_UPSTREAM")]
+    upstream: Option<String>,
+
+    /// Address or network (CIDR) of a proxy in front of the HTTP port, such
+    /// as Caddy, whose X-Forwarded-For / -Proto / -Host headers are believed.
+    /// May be repeated. Headers from anyone else are replaced.
+    #[arg(
+        long = "trusted-proxy",
+        env = "RELAY_TRUSTED_PROXIES",
+        value_delimiter = ',',
+        default_value = "127.0.0.1,::1"
+    )]
+    trusted_proxies: Vec<TrustedProxy>,
+
+    /// How requests are spread when several relay clients serve the same slug.
+    #[arg(long, env = "RELAY_BALANCE", value_enum, default_value_t = Balance::LeastPending)]
+    balance: Balance,
+
+    /// Bind address for the admin API (e.g. 127.0.0.1:7002). Disabled when omitted.
+    #[arg(long, env = "RELAY_ADMIN_BIND")]
+    admin_bind: Option<String>,
+
+    /// Bearer token required by the admin API.
+    #[arg(long, env = "RELAY_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Bind address for the Prometheus /metrics endpoint (e.g. 127.0.0.1:9464).
+    #[arg(long, env = "RELAY_METRICS_BIND")]
+    metrics_bind: Option<String>,
+
+    /// Seconds a relay client's local server has to send a response head,
+    /// unless the client asks for something else.
+    #[arg(long, env = "RELAY_REQUEST_TIMEOUT", default_value = "12")]
+    request_timeout: u64,
+
+    /// Longer (or shorter) deadline for matching paths, as PATTERN=SECONDS
+    /// (e.g. "/api/events*=90"). `*` matches anything. May be repeated;
+    /// the first match wins, and the client's own overrides come first.
+    #[arg(long = "timeout-override", env = "RELAY_TIMEOUT_OVERRIDES", value_delimiter = ',')]
+    timeout_overrides: Vec<TimeoutRule>,
+
+    /// Upper bound, in seconds, on any deadline a relay client asks for.
+    #[arg(long, env = "RELAY_MAX_REQUEST_TIMEOUT", default_value = "300")]
+    max_request_timeout: u64,
+
+    /// HTML page shown while an instance's relay client is disconnected.
+    /// `{{name}}`, `{{slug}}` and `{{logo}}` are filled in from the directory.
+    #[arg(long, env = "RELAY_OFFLINE_PAGE")]
+    offline_page: Option<PathBuf>,
+
+    /// Megabytes of recent cacheable GET responses to keep, served while an
+    /// instance is offline. 0 disables the cache.
+    #[arg(long, env = "RELAY_OFFLINE_CACHE_MB", default_value = "0")]
+    offline_cache_mb: usize,
+
+    /// Seconds a shutdown (SIGTERM) waits for in-flight requests before
+    /// telling relay clients goodbye.
+    #[arg(long, env = "RELAY_SHUTDOWN_TIMEOUT", default_value = "30")]
+    shutdown_timeout: u64,
+
+    /// Failed registrations from one IP within --auth-window that get it banned.
+    #[arg(long, env = "RELAY_AUTH_MAX_FAILURES", default_value = "5")]
+    auth_max_failures: u32,
+
+    /// Seconds over which failed registrations are counted.
+    #[arg(long, env = "RELAY_AUTH_WINDOW", default_value = "60")]
+    auth_window: u64,
+
+    /// Seconds of a first ban. Each new ban within a day of the previous one
+    /// doubles it, up to --auth-max-ban.
+    #[arg(long, env = "RELAY_AUTH_BAN", default_value = "300")]
+    auth_ban: u64,
+
+    /// Upper bound, in seconds, on an escalated ban.
+    #[arg(long, env = "RELAY_AUTH_MAX_BAN", default_value = "86400")]
+    auth_max_ban: u64,
+
+    /// Bind address for requests forwarded by other relay servers sharing
+    /// the database (e.g. 10.0.0.2:7101). Enables cluster mode. Must be a
+    /// private address: traffic between nodes is not encrypted.
+    #[arg(long, env = "RELAY_CLUSTER_BIND")]
+    cluster_bind: Option<String>,
+
+    /// Address other relay servers reach --cluster-bind on, when it differs
+    /// (NAT). Identifies this node in the cluster.
+    #[arg(long, env = "RELAY_CLUSTER_ADVERTISE")]
+    cluster_advertise: Option<String>,
+
+    /// Secret shared by every node of the cluster, required by --cluster-bind.
+    #[arg(long, env = "RELAY_CLUSTER_SECRET", hide_env_values = true)]
+    cluster_secret: Option<String>,
+
+    /// Public ports handed out to relay clients' TCP services, as FIRST-LAST
+    /// (e.g. 20000-20999). TCP port services are refused when omitted.
+    /// Not available with --cluster-bind.
+    #[arg(long, env = "RELAY_TCP_PORTS")]
+    tcp_ports: Option<PortRange>,
+
+    /// Bind address of the TLS passthrough listener (e.g. 0.0.0.0:8443),
+    /// routing on the server name to `<service>.<slug>.<domain>`. SNI
+    /// services are refused when omitted. Not available with --cluster-bind.
+    #[arg(long, env = "RELAY_SNI_BIND")]
+    sni_bind: Option<String>,
+
+    /// File to write an access log of relayed requests to, as JSON lines,
+    /// or - for stdout. Disabled when omitted.
+    #[arg(long, env = "RELAY_ACCESS_LOG")]
+    access_log: Option<LogTarget>,
+
+    /// Share of requests written to the access log, from 0 to 1. Server
+    /// errors are always written.
+    #[arg(long, env = "RELAY_ACCESS_LOG_SAMPLE", default_value = "1")]
+    access_log_sample: f64,
+
+    /// Megabytes at which the access log file is rotated. 0 never rotates.
+    #[arg(long, env = "RELAY_ACCESS_LOG_MAX_MB", default_value = "100")]
+    access_log_max_mb: u64,
+
+    /// Rotated access log files kept (access.log.1, .2, …).
+    #[arg(long, env = "RELAY_ACCESS_LOG_KEEP", default_value = "5")]
+    access_log_keep: usize,
+
+    /// PEM certificate chain for TLS on the relay TCP port.
+    /// When omitted, relay clients connect in plain TCP.
+    #[arg(long, env = "RELAY_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching --tls-cert.
+    #[arg(long, env = "RELAY_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ClientArgs {
+    /// TOML file with any of the settings below, keyed by flag name with
+    /// underscores, plus optional `[[route]]` tables. Flags and environment
+    /// variables take precedence. Sending SIGHUP reloads the routes and timeouts.
+    #[arg(long, env = "NODYX_RELAY_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address of the relay server.
+    #[arg(long, default_value = "relay.nodyx.org:7443")]
+    server: String,
+
+    /// The slug to register (e.g. "moncommunaute").
+    #[arg(long)]
+    slug: Option<String>,
+
+    /// Authentication token from nodyx.org directory registration.
+    #[arg(long, env = "NODYX_RELAY_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// File holding the token, read again at every (re)connection so a
+    /// rotated token is picked up without a restart. Replaces --token.
+    #[arg(long, env = "NODYX_RELAY_TOKEN_FILE", conflicts_with = "token")]
+    token_file: Option<PathBuf>,
+
+    /// Local HTTP port to forward traffic to.
+    #[arg(long, default_value = "80")]
+    local_port: u16,
+
+    /// TOML file routing path prefixes to different local servers
+    /// (`[[route]] prefix = "/api" upstream = "127.0.0.1:3000"`). Replaces --local-port.
+    #[arg(long, env = "NODYX_RELAY_ROUTES", conflicts_with = "local_port")]
+    routes: Option<PathBuf>,
+
+    /// Send the local server the Host the visitor asked for (`slug.nodyx.org`,
+    /// a custom domain) instead of `localhost`. Routes set `preserve_host` each.
+    #[arg(long, env = "NODYX_RELAY_PRESERVE_HOST")]
+    preserve_host: bool,
+
+    /// `[[route]]` tables of the config file.
+    #[arg(skip)]
+    route: Vec<RouteConfig>,
+
+    /// Connect to the relay server over TLS. The default; kept for existing
+    /// setups.
+    #[arg(long, env = "NODYX_RELAY_TLS", hide = true)]
+    tls: bool,
+
+    /// Connect over plain TCP, for a relay server without TLS. The token and
+    /// all traffic then cross the network in clear.
+    #[arg(long, env = "NODYX_RELAY_INSECURE", conflicts_with = "tls")]
+    insecure: bool,
+
+    /// PEM CA bundle to trust instead of the built-in roots (self-hosted relays).
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of the server certificate to pin (hex, colons optional).
+    /// May be repeated to allow a certificate rollover.
+    #[arg(long = "tls-pin")]
+    tls_pins: Vec<String>,
+
+    /// Name to verify the server certificate against (defaults to the --server host).
+    #[arg(long)]
+    tls_server_name: Option<String>,
+
+    /// Seconds the local server has to send a response head. Sent to the
+    /// relay server at registration; the relay's own setting applies when omitted.
+    #[arg(long, env = "NODYX_RELAY_REQUEST_TIMEOUT")]
+    request_timeout: Option<u64>,
+
+    /// Deadline for matching paths, as PATTERN=SECONDS (e.g. "/api/export/*=120").
+    /// May be repeated; the first match wins. Capped by the relay server.
+    #[arg(long = "timeout-override", env = "NODYX_RELAY_TIMEOUT_OVERRIDES", value_delimiter = ',')]
+    timeout_overrides: Vec<TimeoutRule>,
+
+    /// Raw TCP service to expose on a public port of the relay, as
+    /// NAME=HOST:PORT (e.g. "ssh=127.0.0.1:22"). May be repeated.
+    #[arg(long = "tcp-service", env = "NODYX_RELAY_TCP_SERVICES", value_delimiter = ',')]
+    tcp_services: Vec<LocalService>,
+
+    /// TLS service to expose by TLS passthrough at `NAME.<slug>.<domain>`, as
+    /// NAME=HOST:PORT. The local server handles TLS itself. May be repeated.
+    #[arg(long = "sni-service", env = "NODYX_RELAY_SNI_SERVICES", value_delimiter = ',')]
+    sni_services: Vec<LocalService>,
 }
 
 // ── Main ──────────────────────────────────────────────────────────────────────
@@ -76,27 +317,219 @@ async fn main() -> anyhow::Result<()> {
         .compact()
         .init();
 
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let Cli { command } = Cli::from_arg_matches(&matches)?;
 
-    match cli.command {
-        Commands::Server {
-            tcp_port,
-            http_port,
-            database_url,
-            main_slug,
-        } => {
-            server::run(tcp_port, http_port, &database_url, &main_slug).await?;
+    match command {
+        Commands::Server(args) => {
+            let matches = matches.subcommand_matches("server").cloned().unwrap_or_default();
+            let config = server_config(config::server_args(&matches)?)?;
+            let reload = args.config.is_some().then(|| -> server::Reload {
+                Box::new(move || server_config(config::server_args(&matches)?))
+            });
+            server::run(config, reload).await?;
         }
 
-        Commands::Client {
-            server,
-            slug,
-            token,
-            local_port,
-        } => {
-            client::run(&server, &slug, &token, local_port).await?;
+        Commands::Client(args) => {
+            let matches = matches.subcommand_matches("client").cloned().unwrap_or_default();
+            let config = client_config(config::client_args(&matches)?)?;
+            let reload = args.config.is_some().then(|| -> client::Reload {
+                Box::new(move || client_config(config::client_args(&matches)?))
+            });
+            client::run(config, reload).await?;
         }
     }
 
     Ok(())
 }
+
+// ── Settings ──────────────────────────────────────────────────────────────────
+
+/// Check the merged `server` arguments and load what they point to.
+fn server_config(args: ServerArgs) -> anyhow::Result<server::ServerConfig> {
+    let ServerArgs {
+        config: _,
+        tcp_port,
+        http_port,
+        database_url,
+        main_slug,
+        domains,
+        upstream,
+        trusted_proxies,
+        balance,
+        admin_bind,
+        admin_token,
+        metrics_bind,
+        request_timeout,
+        timeout_overrides,
+        max_request_timeout,
+        offline_page,
+        offline_cache_mb,
+        shutdown_timeout,
+        auth_max_failures,
+        auth_window,
+        auth_ban,
+        auth_max_ban,
+        cluster_bind,
+        cluster_advertise,
+        cluster_secret,
+        tcp_ports,
+        sni_bind,
+        access_log,
+        access_log_sample,
+        access_log_max_mb,
+        access_log_keep,
+        tls_cert,
+        tls_key,
+    } = args;
+
+    let database_url = database_url.context("--database-url (or DATABASE_URL) is required")?;
+    if admin_bind.is_some() && admin_token.is_none() {
+        bail!("--admin-bind requires --admin-token");
+    }
+    if auth_max_failures == 0 {
+        bail!("--auth-max-failures must be at least 1");
+    }
+    if auth_max_ban < auth_ban {
+        bail!("--auth-max-ban must not be shorter than --auth-ban");
+    }
+    if !(0.0..=1.0).contains(&access_log_sample) {
+        bail!("--access-log-sample must be between 0 and 1");
+    }
+    let cluster = match (cluster_bind, cluster_secret) {
+        (Some(bind), Some(secret)) => {
+            // Visitor traffic and the cluster secret cross as plain HTTP.
+            let address = |addr: &str| addr.parse::<std::net::SocketAddr>().ok().map(|a| a.ip());
+            if !address(&bind).is_some_and(cluster::is_private) {
+                bail!("--cluster-bind must be a private IP address and port: traffic between nodes is not encrypted");
+            }
+            let advertise = cluster_advertise.unwrap_or_else(|| bind.clone());
+            if address(&advertise).is_some_and(|ip| !cluster::is_private(ip)) {
+                bail!("--cluster-advertise must be a private address: traffic between nodes is not encrypted");
+            }
+            Some(ClusterConfig { bind, advertise, secret })
+        }
+        (None, None) if cluster_advertise.is_none() => None,
+        (Some(_), None) => bail!("--cluster-bind requires --cluster-secret"),
+        _ => bail!("--cluster-advertise and --cluster-secret require --cluster-bind"),
+    };
+    // Visitor connections to a TCP service are only served by the node its
+    // tunnel is on: other nodes would have no way to reach it.
+    if cluster.is_some() && (tcp_ports.is_some() || sni_bind.is_some()) {
+        bail!("--tcp-ports and --sni-bind are not available with --cluster-bind: TCP services are not forwarded between nodes");
+    }
+    let tls = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_acceptor(&cert, &key)?),
+        (None, None) => None,
+        _ => bail!("--tls-cert and --tls-key go together"),
+    };
+
+    Ok(server::ServerConfig {
+        tcp_port,
+        http_port,
+        database_url,
+        main_slug,
+        domains,
+        upstream,
+        trusted_proxies,
+        balance,
+        admin: admin_bind.zip(admin_token),
+        metrics_bind,
+        timeouts: ServerTimeouts {
+            policy: TimeoutPolicy {
+                default_ms: Some(request_timeout * 1000),
+                rules: timeout_overrides,
+            },
+            max: Duration::from_secs(max_request_timeout),
+        },
+        offline_page,
+        offline_cache: offline_cache_mb * 1024 * 1024,
+        shutdown_timeout: Duration::from_secs(shutdown_timeout),
+        bans: BanPolicy {
+            max_failures: auth_max_failures,
+            window: Duration::from_secs(auth_window),
+            ban: Duration::from_secs(auth_ban),
+            max_ban: Duration::from_secs(auth_max_ban),
+        },
+        cluster,
+        services: ServicesConfig { ports: tcp_ports, sni_bind },
+        access_log: access_log.map(|target| AccessLogConfig {
+            target,
+            max_bytes: access_log_max_mb * 1024 * 1024,
+            keep: access_log_keep,
+        }),
+        access_log_sample,
+        tls,
+    })
+}
+
+/// Check the merged `client` arguments and load what they point to.
+fn client_config(args: ClientArgs) -> anyhow::Result<client::ClientConfig> {
+    let ClientArgs {
+        config: _,
+        server,
+        slug,
+        token,
+        token_file,
+        local_port,
+        routes,
+        route,
+        preserve_host,
+        tls,
+        insecure,
+        tls_ca,
+        tls_pins,
+        tls_server_name,
+        request_timeout,
+        timeout_overrides,
+        tcp_services,
+        sni_services,
+    } = args;
+
+    let slug = slug.context("--slug is required")?;
+    let token = match (token, token_file) {
+        (Some(token), None) => Token::Fixed(token),
+        (None, Some(path)) => Token::File(path),
+        (None, None) => bail!("--token (or NODYX_RELAY_TOKEN) or --token-file is required"),
+        (Some(_), Some(_)) => bail!("use either --token or --token-file"),
+    };
+    token.read()?;
+
+    if tls && insecure {
+        bail!("--tls and --insecure contradict each other");
+    }
+    let tls = if !insecure {
+        let opts = tls::ClientTlsOptions {
+            ca: tls_ca,
+            pins: tls_pins,
+            server_name: tls_server_name,
+        };
+        Some(tls::client_connector(&server, &opts)?)
+    } else if tls_ca.is_some() || !tls_pins.is_empty() || tls_server_name.is_some() {
+        bail!("--tls-ca, --tls-pin and --tls-server-name do not go with --insecure");
+    } else {
+        None
+    };
+    let timeouts = TimeoutPolicy {
+        default_ms: request_timeout.map(|secs| secs * 1000),
+        rules: timeout_overrides,
+    };
+    if preserve_host && (routes.is_some() || !route.is_empty()) {
+        bail!("--preserve-host goes with --local-port; routes set `preserve_host` each");
+    }
+    let routes = match routes {
+        Some(path) => Routes::load(&path)?,
+        None if !route.is_empty() => Routes::from_configs(route)?,
+        None => Routes::local(local_port, preserve_host)?,
+    };
+
+    let mut services = tcp_services;
+    services.extend(sni_services.into_iter().map(|s| LocalService { expose: Expose::Sni, ..s }));
+    for (i, service) in services.iter().enumerate() {
+        if services[..i].iter().any(|s| s.name == service.name) {
+            bail!("TCP service '{}' is declared twice", service.name);
+        }
+    }
+
+    Ok(client::ClientConfig { server, slug, token, routes, timeouts, services, tls })
+}
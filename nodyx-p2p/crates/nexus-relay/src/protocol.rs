@@ -1,6 +1,15 @@
-use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use base64::{Engine as _, engine::general_purpose::STANDARD as B64};
+use bytes::Bytes;
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use dashmap::DashMap;
+use tokio::sync::{mpsc, Semaphore, SemaphorePermit};
+
+use crate::timeouts::TimeoutPolicy;
 
 // ── Message types ─────────────────────────────────────────────────────────────
 
@@ -9,17 +18,82 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
     /// First message after TCP connection — authenticate and claim a slug.
-    Register { slug: String, token: String },
-    /// HTTP response for a forwarded request.
+    Register {
+        slug: String,
+        token: String,
+        /// Protocol version spoken by the client.
+        /// Absent for the original clients, which speak LEGACY_PROTOCOL_VERSION.
+        #[serde(default = "legacy_version")]
+        version: u32,
+        /// Optional features the client supports, see `capability`.
+        #[serde(default)]
+        capabilities: Vec<String>,
+        /// Framings the client can speak, in order of preference.
+        /// Absent for older clients, which only speak JSON.
+        #[serde(default)]
+        framings: Vec<Framing>,
+        /// Request deadlines the client would like, see `timeouts`.
+        #[serde(default)]
+        timeouts: TimeoutPolicy,
+        /// Raw TCP services to expose, with the `tcp` capability.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        services: Vec<ServiceSpec>,
+    },
+    /// HTTP response for a forwarded request, with the whole body inline.
+    /// Legacy clients answer every request this way; newer ones only for
+    /// small locally generated errors — real responses are streamed as
+    /// ResponseHead + ResponseChunk* + ResponseEnd.
     Response {
         id: String,
         status: u16,
-        headers: HashMap<String, String>,
+        headers: Headers,
         /// Base64-encoded response body.
         body_b64: String,
     },
+    /// Status line and headers of a streamed response.
+    ResponseHead {
+        id: String,
+        status: u16,
+        headers: Headers,
+    },
+    /// One piece of a streamed response body (at most CHUNK_SIZE bytes).
+    ResponseChunk {
+        id: String,
+        #[serde(rename = "data_b64", with = "b64")]
+        data: Bytes,
+    },
+    /// End of a streamed response body.
+    ResponseEnd { id: String },
+    /// The local server failed part-way through a response body: what was
+    /// sent of it is incomplete. Only sent to servers with the `abort`
+    /// capability; older ones get ResponseEnd instead.
+    Abort { id: String },
+    /// Room for `chunks` more RequestChunk / StreamData messages of `id`,
+    /// made by the client taking that many out of its buffer. Only with the
+    /// `credit` capability, see `Flow`.
+    Credit { id: String, chunks: u32 },
+    /// Local server's answer to a StreamOpen. Status 101 means the stream is
+    /// open; anything else is a plain response whose body follows as
+    /// StreamData* + StreamClose. Also answers a TcpOpen, with status 200
+    /// once the local service accepted the connection.
+    StreamOpened {
+        id: String,
+        status: u16,
+        headers: Headers,
+    },
+    /// Raw bytes read from the local end of an upgraded connection.
+    StreamData {
+        id: String,
+        #[serde(rename = "data_b64", with = "b64")]
+        data: Bytes,
+    },
+    /// The local end of an upgraded connection closed.
+    StreamClose { id: String },
     /// Keep-alive ping reply.
     Heartbeat,
+    /// The client is going away: send it no new requests. In-flight ones
+    /// complete normally, then the client closes the connection.
+    Drain,
 }
 
 /// Messages sent from the relay server to the relay client.
@@ -27,21 +101,662 @@ pub enum ClientMessage {
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
     /// Confirmation (or rejection) of a Register message.
-    Registered { ok: bool, error: Option<String> },
+    Registered {
+        ok: bool,
+        error: Option<String>,
+        /// Protocol version the session runs at.
+        /// Absent from the original servers, which speak LEGACY_PROTOCOL_VERSION.
+        #[serde(default = "legacy_version")]
+        version: u32,
+        /// Capabilities enabled for this session: those both sides support.
+        #[serde(default)]
+        capabilities: Vec<String>,
+        /// Framing used for every message after this one.
+        /// Absent from older servers, which only speak JSON.
+        #[serde(default)]
+        framing: Option<Framing>,
+        /// Where each requested TCP service is exposed, or why it isn't.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        services: Vec<ServiceGrant>,
+        /// The instance's public host name, under the relay's primary domain.
+        /// Absent from older servers.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        host: Option<String>,
+    },
     /// An HTTP request that the client must forward to its local server.
+    /// The body follows as RequestChunk* + RequestEnd with the same id.
     Request {
-        /// Correlation ID — must be echoed in the Response.
+        /// Correlation ID — must be echoed in the response messages.
         id: String,
         method: String,
         path: String,
-        headers: HashMap<String, String>,
-        /// Base64-encoded request body (empty string if no body).
-        body_b64: String,
+        headers: Headers,
+        /// How long the local server has to answer, set by the relay server.
+        /// Absent from older servers; the client then uses its own setting.
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+        /// Base64-encoded whole body, for LEGACY_PROTOCOL_VERSION clients
+        /// only: they get no RequestChunk / RequestEnd.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body_b64: Option<String>,
+    },
+    /// One piece of a request body (at most CHUNK_SIZE bytes).
+    RequestChunk {
+        id: String,
+        #[serde(rename = "data_b64", with = "b64")]
+        data: Bytes,
+    },
+    /// End of a request body — always sent, even for bodiless requests.
+    RequestEnd { id: String },
+    /// An HTTP Upgrade request (WebSocket, …) that the client must open
+    /// against its local server. Answered by ClientMessage::StreamOpened.
+    StreamOpen {
+        id: String,
+        method: String,
+        path: String,
+        /// Includes the `upgrade` and `connection` headers.
+        headers: Headers,
+        /// How long the local server has to answer the upgrade, as for Request.
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+    /// Raw bytes from the browser side of an upgraded connection.
+    StreamData {
+        id: String,
+        #[serde(rename = "data_b64", with = "b64")]
+        data: Bytes,
+    },
+    /// The browser side of an upgraded connection closed.
+    StreamClose { id: String },
+    /// A connection to one of the client's TCP services, to open against the
+    /// local service. Answered by ClientMessage::StreamOpened, after which
+    /// bytes flow as StreamData both ways until either side sends StreamClose.
+    TcpOpen {
+        id: String,
+        service: String,
+        /// Address of the remote end, for logs.
+        peer: String,
     },
     /// Server-initiated keep-alive.
     Ping,
+    /// The browser went away or the reply timed out: abandon request (or
+    /// stream) `id`. Only sent to clients with the `cancel` capability.
+    Cancel { id: String },
+    /// Room for `chunks` more ResponseChunk / StreamData messages of `id`,
+    /// made by the server taking that many out of its buffer. Only with the
+    /// `credit` capability, see `Flow`.
+    Credit { id: String, chunks: u32 },
+    /// The server is shutting down after finishing in-flight requests: the
+    /// client should reconnect right away, to another relay server if the
+    /// address resolves to several. Only sent to clients with the `goodbye`
+    /// capability; others just see the connection close.
+    Goodbye,
+}
+
+impl ServerMessage {
+    /// Headers of a Request or StreamOpen.
+    pub fn headers_mut(&mut self) -> Option<&mut Headers> {
+        match self {
+            ServerMessage::Request { headers, .. } | ServerMessage::StreamOpen { headers, .. } => Some(headers),
+            _ => None,
+        }
+    }
+
+    /// A failed registration. The fields older clients don't know about are
+    /// left empty — they only read `ok` and `error`.
+    pub fn rejected(error: impl Into<String>) -> Self {
+        ServerMessage::Registered {
+            ok: false,
+            error: Some(error.into()),
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            framing: None,
+            services: Vec::new(),
+            host: None,
+        }
+    }
+}
+
+impl ClientMessage {
+    /// Headers of a Response, ResponseHead or StreamOpened.
+    pub fn headers_mut(&mut self) -> Option<&mut Headers> {
+        match self {
+            ClientMessage::Response { headers, .. }
+            | ClientMessage::ResponseHead { headers, .. }
+            | ClientMessage::StreamOpened { headers, .. } => Some(headers),
+            _ => None,
+        }
+    }
+}
+
+// ── TCP services ──────────────────────────────────────────────────────────────
+
+/// How the relay server exposes a TCP service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Expose {
+    /// A public port of its own.
+    Port,
+    /// TLS passthrough on the shared SNI port, for `<service>.<slug host>`.
+    Sni,
+}
+
+/// A TCP service a client asks to expose.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceSpec {
+    /// DNS label naming the service (`ssh`, `minecraft`, …).
+    pub name: String,
+    pub expose: Expose,
+}
+
+impl ServiceSpec {
+    /// Service names are DNS labels, so an SNI service can be addressed as
+    /// `<service>.<slug host>`.
+    pub fn valid_name(name: &str) -> bool {
+        (1..=63).contains(&name.len())
+            && !name.starts_with('-')
+            && !name.ends_with('-')
+            && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    }
+}
+
+/// The server's answer for one requested service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceGrant {
+    pub name: String,
+    /// "host:port" the service is reachable at; `None` when refused.
+    pub address: Option<String>,
+    pub error: Option<String>,
+}
+
+// ── Versioning ────────────────────────────────────────────────────────────────
+//
+// Register carries the client's protocol version and capability list; the
+// server refuses versions it cannot speak with an upgrade message, and
+// answers with the capabilities enabled for the session. Additive changes
+// become a new capability; breaking changes bump PROTOCOL_VERSION.
+
+/// Version spoken by clients and servers that predate versioning:
+/// whole-body base64 Request / Response, JSON framing only.
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// Version spoken by this build: streamed bodies and framing negotiation.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest client version the server still accepts. Legacy clients get each
+/// request whole, once its body has been received.
+pub const MIN_PROTOCOL_VERSION: u32 = LEGACY_PROTOCOL_VERSION;
+
+/// Oldest server version the client registers with: legacy servers cannot
+/// read streamed responses.
+pub const MIN_SERVER_PROTOCOL_VERSION: u32 = 2;
+
+fn legacy_version() -> u32 {
+    LEGACY_PROTOCOL_VERSION
+}
+
+/// Optional protocol features, announced in Register / Registered.
+pub mod capability {
+    /// HTTP Upgrade / WebSocket relaying (StreamOpen, StreamData, StreamClose).
+    pub const UPGRADE: &str = "upgrade";
+    /// Graceful hand-over (ClientMessage::Drain).
+    pub const DRAIN: &str = "drain";
+    /// Request cancellation (ServerMessage::Cancel).
+    pub const CANCEL: &str = "cancel";
+    /// Shutdown notice (ServerMessage::Goodbye).
+    pub const GOODBYE: &str = "goodbye";
+    /// Headers as an ordered list of fields, see `Headers`.
+    pub const HEADER_LIST: &str = "header-list";
+    /// Raw TCP services (Register.services, ServerMessage::TcpOpen).
+    pub const TCP: &str = "tcp";
+    /// Response bodies cut short (ClientMessage::Abort).
+    pub const ABORT: &str = "abort";
+    /// Per-stream flow control (Credit), see `Flow`.
+    pub const CREDIT: &str = "credit";
+
+    /// Capabilities this build supports.
+    pub const SUPPORTED: &[&str] = &[UPGRADE, DRAIN, CANCEL, GOODBYE, HEADER_LIST, TCP, ABORT, CREDIT];
+
+    /// Capabilities offered by the peer that this build also supports.
+    pub fn intersect(offered: &[String]) -> Vec<String> {
+        offered
+            .iter()
+            .filter(|c| SUPPORTED.contains(&c.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// Everything this build supports, ready to put in a message.
+    pub fn all() -> Vec<String> {
+        SUPPORTED.iter().map(|c| (*c).to_owned()).collect()
+    }
+}
+
+/// Base64 (de)serialization of raw payloads, so the JSON framing keeps its
+/// `data_b64` string fields while the binary framing carries the bytes as-is.
+mod b64 {
+    use base64::{Engine as _, engine::general_purpose::STANDARD as B64};
+    use bytes::Bytes;
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &Bytes, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&B64.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Bytes, D::Error> {
+        let s = String::deserialize(d)?;
+        B64.decode(s).map(Bytes::from).map_err(D::Error::custom)
+    }
+}
+
+// ── Headers ───────────────────────────────────────────────────────────────────
+
+/// HTTP header fields as they travel through the tunnel: every field line in
+/// order, names lowercased, values as raw bytes. Repeated fields (several
+/// `Set-Cookie` lines, …) and non-UTF-8 values make it through intact.
+///
+/// On the wire a list of `[name, value]` pairs, with `{"b64": "…"}` standing
+/// in for values that are not UTF-8. Peers without the `header-list`
+/// capability get the original name → value map instead, see `flatten`.
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    fields: Vec<(String, Bytes)>,
+    /// Serialize as a map, for peers that predate the list.
+    flat: bool,
+}
+
+impl Headers {
+    /// The fields of `map` whose name passes `keep`.
+    pub fn from_http(map: &hyper::HeaderMap, keep: impl Fn(&str) -> bool) -> Self {
+        let fields = map
+            .iter()
+            .filter(|(name, _)| keep(name.as_str()))
+            .map(|(name, value)| (name.as_str().to_owned(), Bytes::copy_from_slice(value.as_bytes())))
+            .collect();
+        Self { fields, flat: false }
+    }
+
+    /// Append the fields whose name passes `keep` to `map`. Fields hyper
+    /// rejects (bad name, control characters in the value) are skipped.
+    pub fn append_to(&self, map: &mut hyper::HeaderMap, keep: impl Fn(&str) -> bool) {
+        for (name, value) in self.iter().filter(|(name, _)| keep(name)) {
+            let (Ok(name), Ok(value)) =
+                (hyper::header::HeaderName::from_bytes(name.as_bytes()), hyper::header::HeaderValue::from_bytes(value))
+            else {
+                continue;
+            };
+            map.append(name, value);
+        }
+    }
+
+    pub fn push(&mut self, name: &str, value: impl Into<Bytes>) {
+        self.fields.push((name.to_ascii_lowercase(), value.into()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), &value[..]))
+    }
+
+    /// The first `name` field, if it is UTF-8.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.fields.iter().any(|(n, _)| n == name)
+    }
+
+    /// Reduce to the name → value map older peers expect, and serialize as
+    /// such: repeated fields are joined with ", " (`Cookie` with "; "). A map
+    /// cannot carry more than one `Set-Cookie`, nor non-UTF-8 values, so those
+    /// are left out; their names are returned for the caller to report.
+    pub fn flatten(&mut self) -> Vec<String> {
+        let mut joined: Vec<(String, Bytes)> = Vec::new();
+        let mut lost = Vec::new();
+        for (name, value) in self.fields.drain(..) {
+            if std::str::from_utf8(&value).is_err() {
+                lost.push(name);
+                continue;
+            }
+            match joined.iter_mut().find(|(n, _)| *n == name) {
+                Some(_) if name == "set-cookie" => lost.push(name),
+                Some((_, prev)) => {
+                    let separator: &[u8] = if name == "cookie" { b"; " } else { b", " };
+                    *prev = [&prev[..], separator, &value[..]].concat().into();
+                }
+                None => joined.push((name, value)),
+            }
+        }
+        self.fields = joined;
+        self.flat = true;
+        lost
+    }
+}
+
+/// A header value that is not UTF-8.
+#[derive(Serialize, Deserialize)]
+struct BinaryValue {
+    b64: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WireValue {
+    Text(String),
+    Binary(BinaryValue),
+}
+
+impl Serialize for Headers {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if self.flat {
+            // `flatten` left one UTF-8 value per name.
+            let mut map = s.serialize_map(Some(self.fields.len()))?;
+            for (name, value) in &self.fields {
+                if let Ok(value) = std::str::from_utf8(value) {
+                    map.serialize_entry(name, value)?;
+                }
+            }
+            return map.end();
+        }
+
+        let mut seq = s.serialize_seq(Some(self.fields.len()))?;
+        for (name, value) in &self.fields {
+            match std::str::from_utf8(value) {
+                Ok(text) => seq.serialize_element(&(name, text))?,
+                Err(_) => seq.serialize_element(&(name, BinaryValue { b64: B64.encode(value) }))?,
+            }
+        }
+        seq.end()
+    }
 }
 
+/// Takes the list or, from older peers, the map.
+impl<'de> Deserialize<'de> for Headers {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct HeadersVisitor;
+
+        impl<'de> Visitor<'de> for HeadersVisitor {
+            type Value = Headers;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of [name, value] pairs or a name → value map")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Headers, A::Error> {
+                let mut headers = Headers::default();
+                while let Some((name, value)) = seq.next_element::<(String, WireValue)>()? {
+                    let value = match value {
+                        WireValue::Text(text) => Bytes::from(text),
+                        WireValue::Binary(BinaryValue { b64 }) => {
+                            B64.decode(b64).map(Bytes::from).map_err(serde::de::Error::custom)?
+                        }
+                    };
+                    headers.push(&name, value);
+                }
+                Ok(headers)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Headers, A::Error> {
+                let mut headers = Headers::default();
+                while let Some((name, value)) = map.next_entry::<String, String>()? {
+                    headers.push(&name, value);
+                }
+                Ok(headers)
+            }
+        }
+
+        d.deserialize_any(HeadersVisitor)
+    }
+}
+
+// ── Body streaming ────────────────────────────────────────────────────────────
+
+/// Maximum body bytes carried by a single RequestChunk / ResponseChunk.
+/// Keeps frames far below the 16 MiB cap whatever the total body size.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Capacity (in chunks) of the per-request body channels on both sides, and
+/// with the `credit` capability the window each body or stream starts with.
+/// Bounds the memory held for a slow reader to BODY_CHANNEL_CAPACITY × CHUNK_SIZE.
+pub const BODY_CHANNEL_CAPACITY: usize = 16;
+
+/// A body or stream cut short: its tunnel went away, or its sender gave up
+/// part-way. What arrived of it must not pass for the whole.
+#[derive(Debug)]
+pub struct Truncated;
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("body cut short")
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+/// What travels through a body channel. Bodies end with `End`; a channel
+/// closed without one was cut short.
+enum Piece {
+    Data(Bytes),
+    End,
+}
+
+/// Why a chunk could not be handed to its reader.
+#[derive(Debug, PartialEq)]
+pub enum Undelivered {
+    /// The reader is gone.
+    Closed,
+    /// The peer sent more than the reader had given it room for.
+    Overrun,
+}
+
+/// Sending half of a body channel, held by a tunnel's read loop.
+pub struct BodySender {
+    tx: mpsc::Sender<Piece>,
+    /// The peer keeps to the reader's window, so the channel never fills.
+    credit: bool,
+}
+
+impl BodySender {
+    /// Hand a chunk received from the peer to the reader. With credit this
+    /// never waits; without, it waits for room, holding up the whole tunnel.
+    pub async fn send(&self, data: Bytes) -> Result<(), Undelivered> {
+        if !self.credit {
+            return self.tx.send(Piece::Data(data)).await.map_err(|_| Undelivered::Closed);
+        }
+        self.tx.try_send(Piece::Data(data)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => Undelivered::Overrun,
+            mpsc::error::TrySendError::Closed(_) => Undelivered::Closed,
+        })
+    }
+
+    /// The body is complete. Dropping the sender instead truncates it.
+    pub fn finish(self) {
+        if let Err(mpsc::error::TrySendError::Full(end)) = self.tx.try_send(Piece::End) {
+            // Behind chunks the reader has yet to take.
+            tokio::spawn(async move {
+                let _ = self.tx.send(end).await;
+            });
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// Receiving half of a body channel. `M` is the message type of the tunnel
+/// queue credit is returned through.
+pub struct BodyReceiver<M> {
+    rx: mpsc::Receiver<Piece>,
+    ended: bool,
+    credit: Option<Credit<M>>,
+}
+
+/// Chunks taken out of a body channel, not yet handed back as credit.
+struct Credit<M> {
+    id: String,
+    tx: mpsc::Sender<M>,
+    message: fn(String, u32) -> M,
+    taken: u32,
+}
+
+impl<M> BodyReceiver<M> {
+    /// A complete body known up front.
+    pub fn whole(data: Bytes) -> Self {
+        let (tx, rx) = mpsc::channel(2);
+        let _ = tx.try_send(Piece::Data(data));
+        let _ = tx.try_send(Piece::End);
+        Self { rx, ended: false, credit: None }
+    }
+
+    /// The next chunk; `None` once the body is complete, after a last
+    /// `Err(Truncated)` if it was cut short.
+    pub async fn recv(&mut self) -> Option<Result<Bytes, Truncated>> {
+        if self.ended {
+            return None;
+        }
+        match self.rx.recv().await {
+            Some(Piece::Data(data)) => {
+                if let Some(credit) = &mut self.credit {
+                    // Handed back in batches, to keep the messages few.
+                    credit.taken += 1;
+                    if credit.taken as usize >= BODY_CHANNEL_CAPACITY / 2 {
+                        let msg = (credit.message)(credit.id.clone(), std::mem::take(&mut credit.taken));
+                        let _ = credit.tx.send(msg).await;
+                    }
+                }
+                Some(Ok(data))
+            }
+            Some(Piece::End) => {
+                self.ended = true;
+                None
+            }
+            None => {
+                self.ended = true;
+                Some(Err(Truncated))
+            }
+        }
+    }
+}
+
+// ── Flow control ──────────────────────────────────────────────────────────────
+//
+// With the `credit` capability, each body or stream may have at most
+// BODY_CHANNEL_CAPACITY chunks in flight in either direction: the sender
+// waits for Credit before sending more, and the receiver hands credit back as
+// its reader takes chunks out of its buffer. A slow reader then only pauses
+// its own stream, and a read loop never waits on one. Without the
+// capability, read loops wait for room, and a slow reader holds up the
+// whole tunnel.
+
+/// One tunnel end's flow control, shared by its read loop and the tasks
+/// relaying each body or stream. `M` is the message type of its outgoing
+/// queue, which credit goes through.
+pub struct Flow<M> {
+    /// Whether the peer has the `credit` capability.
+    enabled: bool,
+    /// Room the peer has left for each body or stream we send it.
+    windows: Arc<DashMap<String, Arc<Semaphore>>>,
+    tx: mpsc::Sender<M>,
+    credit: fn(String, u32) -> M,
+}
+
+impl<M> Clone for Flow<M> {
+    fn clone(&self) -> Self {
+        Self { enabled: self.enabled, windows: self.windows.clone(), tx: self.tx.clone(), credit: self.credit }
+    }
+}
+
+impl<M> Flow<M> {
+    /// `credit` builds the Credit message for `tx`, the outgoing queue.
+    pub fn new(enabled: bool, tx: mpsc::Sender<M>, credit: fn(String, u32) -> M) -> Self {
+        Self { enabled, windows: Arc::default(), tx, credit }
+    }
+
+    /// A channel for body or stream `id`, received from the peer.
+    pub fn receive(&self, id: &str) -> (BodySender, BodyReceiver<M>) {
+        let (tx, rx) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+        let credit = self.enabled.then(|| Credit {
+            id: id.to_owned(),
+            tx: self.tx.clone(),
+            message: self.credit,
+            taken: 0,
+        });
+        (BodySender { tx, credit: self.enabled }, BodyReceiver { rx, ended: false, credit })
+    }
+
+    /// The window of body or stream `id`, about to be sent to the peer.
+    pub fn send(&self, id: &str) -> Window {
+        let room = self.enabled.then(|| {
+            let room = Arc::new(Semaphore::new(BODY_CHANNEL_CAPACITY));
+            self.windows.insert(id.to_owned(), room.clone());
+            room
+        });
+        Window { id: id.to_owned(), room, windows: self.windows.clone() }
+    }
+
+    /// The peer made room for `chunks` more of `id`. A window never grows
+    /// past where it started, whatever the peer claims.
+    pub fn grant(&self, id: &str, chunks: u32) {
+        if let Some(room) = self.windows.get(id) {
+            let chunks = (chunks as usize).min(BODY_CHANNEL_CAPACITY.saturating_sub(room.available_permits()));
+            room.add_permits(chunks);
+        }
+    }
+}
+
+/// Room the peer has for one body or stream we send it.
+pub struct Window {
+    id: String,
+    /// `None` for peers without flow control: no limit.
+    room: Option<Arc<Semaphore>>,
+    windows: Arc<DashMap<String, Arc<Semaphore>>>,
+}
+
+impl Window {
+    /// Wait until the peer has room for one more chunk. The room goes back
+    /// to the window unless spent.
+    pub async fn reserve(&self) -> Room<'_> {
+        match &self.room {
+            Some(room) => Room(room.acquire().await.ok()),
+            None => Room(None),
+        }
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        if self.room.is_some() {
+            self.windows.remove(&self.id);
+        }
+    }
+}
+
+/// Room for one chunk, see `Window::reserve`.
+pub struct Room<'a>(Option<SemaphorePermit<'a>>);
+
+impl Room<'_> {
+    /// A chunk was sent in it.
+    pub fn spend(self) {
+        if let Some(permit) = self.0 {
+            permit.forget();
+        }
+    }
+}
+
+/// Split a body into CHUNK_SIZE pieces without copying.
+pub fn chunks(data: Bytes) -> impl Iterator<Item = Bytes> {
+    (0..data.len())
+        .step_by(CHUNK_SIZE)
+        .map(move |start| data.slice(start..(start + CHUNK_SIZE).min(data.len())))
+}
+
+/// Largest frame either side accepts, whatever the framing.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
 // ── Framing: [u32 big-endian length][JSON bytes] ──────────────────────────────
 
 /// Write a framed JSON message to any AsyncWrite.
@@ -64,6 +779,18 @@ pub async fn read_msg<R, M>(reader: &mut R) -> std::io::Result<Option<M>>
 where
     R: AsyncReadExt + Unpin,
     M: for<'de> Deserialize<'de>,
+{
+    let Some(buf) = read_frame_bytes(reader).await? else {
+        return Ok(None);
+    };
+    let msg = serde_json::from_slice(&buf)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok(Some(msg))
+}
+
+async fn read_frame_bytes<R>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>>
+where
+    R: AsyncReadExt + Unpin,
 {
     let mut len_buf = [0u8; 4];
     match reader.read_exact(&mut len_buf).await {
@@ -72,7 +799,7 @@ where
         Err(e) => return Err(e),
     }
     let len = u32::from_be_bytes(len_buf) as usize;
-    if len > 16 * 1024 * 1024 {
+    if len > MAX_FRAME_LEN {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidData,
             format!("frame too large: {len} bytes"),
@@ -80,7 +807,248 @@ where
     }
     let mut buf = vec![0u8; len];
     reader.read_exact(&mut buf).await?;
-    let msg = serde_json::from_slice(&buf)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-    Ok(Some(msg))
+    Ok(Some(buf))
+}
+
+// ── Framing negotiation ───────────────────────────────────────────────────────
+//
+// Register and Registered are always JSON frames. The client lists the
+// framings it speaks in Register; the server picks one and announces it in
+// Registered, and both sides switch to it for the rest of the session.
+
+/// Wire format of the messages exchanged after registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// `[u32 length][JSON]` with base64 bodies — the original protocol.
+    Json,
+    /// Binary frames with raw payloads, see `write_frame`.
+    Binary,
+}
+
+impl Framing {
+    /// Framings this build speaks, in order of preference.
+    pub const SUPPORTED: [Framing; 2] = [Framing::Binary, Framing::Json];
+
+    /// Pick the framing for a session from the client's preference list.
+    pub fn negotiate(offered: &[Framing]) -> Framing {
+        offered
+            .iter()
+            .copied()
+            .find(|f| Self::SUPPORTED.contains(f))
+            .unwrap_or(Framing::Json)
+    }
+}
+
+// ── Framing: binary ───────────────────────────────────────────────────────────
+//
+// [u32 BE length of everything below]
+// [u8  version = BINARY_VERSION]
+// [u8  kind]    KIND_CONTROL, KIND_BODY or KIND_STREAM
+// [u8  flags]   FLAG_END marks the last frame of a body / stream
+// [u16 BE id length][id bytes]
+// [payload]     JSON message for KIND_CONTROL, raw bytes otherwise
+
+const BINARY_VERSION: u8 = 1;
+
+/// Any message without a raw payload, JSON-encoded.
+const KIND_CONTROL: u8 = 0;
+/// Request / response body chunk.
+const KIND_BODY: u8 = 1;
+/// Upgraded-connection data.
+const KIND_STREAM: u8 = 2;
+
+const FLAG_END: u8 = 0x01;
+
+/// Raw-payload view of a message, as carried by a binary data frame.
+pub struct DataFrame<'a> {
+    kind: u8,
+    id: &'a str,
+    end: bool,
+    payload: &'a [u8],
+}
+
+/// A message type that can travel in binary data frames.
+pub trait WireMessage: Serialize + for<'de> Deserialize<'de> {
+    /// The data-frame view of this message, or `None` for control messages.
+    fn as_data(&self) -> Option<DataFrame<'_>>;
+    /// Rebuild a message from a data frame. `None` for an unknown kind.
+    fn from_data(kind: u8, id: String, end: bool, payload: Bytes) -> Option<Self>;
+}
+
+impl WireMessage for ServerMessage {
+    fn as_data(&self) -> Option<DataFrame<'_>> {
+        let (kind, id, end, payload): (u8, &str, bool, &[u8]) = match self {
+            ServerMessage::RequestChunk { id, data } => (KIND_BODY, id, false, data),
+            ServerMessage::RequestEnd { id } => (KIND_BODY, id, true, &[]),
+            ServerMessage::StreamData { id, data } => (KIND_STREAM, id, false, data),
+            ServerMessage::StreamClose { id } => (KIND_STREAM, id, true, &[]),
+            _ => return None,
+        };
+        Some(DataFrame { kind, id, end, payload })
+    }
+
+    fn from_data(kind: u8, id: String, end: bool, data: Bytes) -> Option<Self> {
+        Some(match (kind, end) {
+            (KIND_BODY, false) => ServerMessage::RequestChunk { id, data },
+            (KIND_BODY, true) => ServerMessage::RequestEnd { id },
+            (KIND_STREAM, false) => ServerMessage::StreamData { id, data },
+            (KIND_STREAM, true) => ServerMessage::StreamClose { id },
+            _ => return None,
+        })
+    }
+}
+
+impl WireMessage for ClientMessage {
+    fn as_data(&self) -> Option<DataFrame<'_>> {
+        let (kind, id, end, payload): (u8, &str, bool, &[u8]) = match self {
+            ClientMessage::ResponseChunk { id, data } => (KIND_BODY, id, false, data),
+            ClientMessage::ResponseEnd { id } => (KIND_BODY, id, true, &[]),
+            ClientMessage::StreamData { id, data } => (KIND_STREAM, id, false, data),
+            ClientMessage::StreamClose { id } => (KIND_STREAM, id, true, &[]),
+            _ => return None,
+        };
+        Some(DataFrame { kind, id, end, payload })
+    }
+
+    fn from_data(kind: u8, id: String, end: bool, data: Bytes) -> Option<Self> {
+        Some(match (kind, end) {
+            (KIND_BODY, false) => ClientMessage::ResponseChunk { id, data },
+            (KIND_BODY, true) => ClientMessage::ResponseEnd { id },
+            (KIND_STREAM, false) => ClientMessage::StreamData { id, data },
+            (KIND_STREAM, true) => ClientMessage::StreamClose { id },
+            _ => return None,
+        })
+    }
+}
+
+/// Write a message using the negotiated framing.
+pub async fn write_frame<W, M>(writer: &mut W, msg: &M, framing: Framing) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+    M: WireMessage,
+{
+    if framing == Framing::Json {
+        return write_msg(writer, msg).await;
+    }
+
+    let json;
+    let data = match msg.as_data() {
+        Some(data) => data,
+        None => {
+            json = serde_json::to_vec(msg)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            DataFrame { kind: KIND_CONTROL, id: "", end: false, payload: &json }
+        }
+    };
+
+    let id_len = u16::try_from(data.id.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "id too long"))?;
+    let mut header = Vec::with_capacity(9 + data.id.len());
+    let len = 5 + data.id.len() + data.payload.len();
+    header.extend_from_slice(&(len as u32).to_be_bytes());
+    header.push(BINARY_VERSION);
+    header.push(data.kind);
+    header.push(if data.end { FLAG_END } else { 0 });
+    header.extend_from_slice(&id_len.to_be_bytes());
+    header.extend_from_slice(data.id.as_bytes());
+
+    writer.write_all(&header).await?;
+    writer.write_all(data.payload).await?;
+    Ok(())
+}
+
+/// Read a message using the negotiated framing.
+/// Returns `None` on clean EOF (connection closed by peer).
+pub async fn read_frame<R, M>(reader: &mut R, framing: Framing) -> std::io::Result<Option<M>>
+where
+    R: AsyncReadExt + Unpin,
+    M: WireMessage,
+{
+    if framing == Framing::Json {
+        return read_msg(reader).await;
+    }
+
+    let Some(buf) = read_frame_bytes(reader).await? else {
+        return Ok(None);
+    };
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_owned());
+
+    if buf.len() < 5 {
+        return Err(invalid("truncated binary frame"));
+    }
+    if buf[0] != BINARY_VERSION {
+        return Err(invalid("unsupported binary frame version"));
+    }
+    let (kind, flags) = (buf[1], buf[2]);
+    let id_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if buf.len() < 5 + id_len {
+        return Err(invalid("truncated binary frame"));
+    }
+
+    if kind == KIND_CONTROL {
+        let msg = serde_json::from_slice(&buf[5 + id_len..])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        return Ok(Some(msg));
+    }
+
+    let id = std::str::from_utf8(&buf[5..5 + id_len])
+        .map_err(|_| invalid("non UTF-8 frame id"))?
+        .to_owned();
+    let payload = Bytes::from(buf).slice(5 + id_len..);
+    M::from_data(kind, id, flags & FLAG_END != 0, payload)
+        .map(Some)
+        .ok_or_else(|| invalid("unknown binary frame kind"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn fields(headers: &Headers) -> Vec<(&str, &[u8])> {
+        headers.iter().collect()
+    }
+
+    fn sample() -> Headers {
+        let mut headers = Headers::default();
+        headers.push("Set-Cookie", "a=1; Path=/");
+        headers.push("set-cookie", "b=2; Expires=Wed, 21 Oct 2026 07:28:00 GMT");
+        headers.push("cookie", "x=1");
+        headers.push("cookie", "y=2");
+        headers.push("accept", "text/html");
+        headers.push("accept", "*/*");
+        headers.push("x-raw", &b"caf\xe9"[..]);
+        headers
+    }
+
+    #[test]
+    fn list_form_round_trips_repeated_and_binary_values() {
+        let headers = sample();
+        let wire = serde_json::to_value(&headers).unwrap();
+        assert_eq!(wire[0], json!(["set-cookie", "a=1; Path=/"]));
+        assert_eq!(wire[6], json!(["x-raw", { "b64": B64.encode(b"caf\xe9") }]));
+
+        let back: Headers = serde_json::from_value(wire).unwrap();
+        assert_eq!(fields(&back), fields(&headers));
+    }
+
+    #[test]
+    fn flat_form_joins_repeats_and_reports_what_it_drops() {
+        let mut headers = sample();
+        assert_eq!(headers.flatten(), ["set-cookie", "x-raw"]);
+
+        let wire = serde_json::to_value(&headers).unwrap();
+        assert_eq!(
+            wire,
+            json!({ "set-cookie": "a=1; Path=/", "cookie": "x=1; y=2", "accept": "text/html, */*" })
+        );
+
+        let back: Headers = serde_json::from_value(wire).unwrap();
+        assert_eq!(back.get("set-cookie"), Some("a=1; Path=/"));
+        assert_eq!(back.get("cookie"), Some("x=1; y=2"));
+        assert_eq!(back.get("accept"), Some("text/html, */*"));
+        assert!(!back.contains("x-raw"));
+    }
 }
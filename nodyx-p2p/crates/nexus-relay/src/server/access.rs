@@ -0,0 +1,273 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use base64::{Engine as _, engine::general_purpose::STANDARD as B64};
+use dashmap::DashMap;
+use hyper::header::AUTHORIZATION;
+use hyper::HeaderMap;
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use super::db::DbPool;
+
+/// How long a slug's policy is trusted before re-reading relay_access_policies.
+const POLICY_TTL: Duration = Duration::from_secs(60);
+/// Policies cached past which those older than POLICY_TTL are forgotten.
+const MAX_POLICIES: usize = 10_000;
+/// Wrong credentials an address may send for a slug within FAILURE_WINDOW;
+/// past that it is refused without a check until the window is over.
+const MAX_FAILURES: u32 = 10;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// Addresses tracked past which those with an expired window are forgotten.
+const MAX_TRACKED: usize = 10_000;
+
+/// Compare in constant time, so response timing tells nothing of `b` but its length.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// A secret stored as `directory_token_hash(salt, secret)` (migration 063).
+struct Secret {
+    salt: String,
+    hash: String,
+}
+
+impl Secret {
+    fn matches(&self, candidate: &[u8]) -> bool {
+        let digest = Sha256::new().chain_update(self.salt.as_bytes()).chain_update(candidate).finalize();
+        let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+        same(hex.as_bytes(), self.hash.as_bytes())
+    }
+}
+
+/// A basic auth password, stored as a bcrypt hash (migration 067).
+struct Password {
+    hash: String,
+    /// Digest of the last password that matched: browsers send it with
+    /// every request, and bcrypt is only paid once per policy refresh.
+    verified: Mutex<Option<[u8; 32]>>,
+}
+
+impl Password {
+    fn new(hash: String) -> Self {
+        Self { hash, verified: Mutex::new(None) }
+    }
+
+    async fn matches(&self, candidate: &[u8]) -> bool {
+        let digest: [u8; 32] = Sha256::digest(candidate).into();
+        if self.verified.lock().unwrap().is_some_and(|known| same(&known, &digest)) {
+            return true;
+        }
+        let (hash, candidate) = (self.hash.clone(), candidate.to_vec());
+        let matched = tokio::task::spawn_blocking(move || bcrypt::verify(candidate, &hash).unwrap_or(false))
+            .await
+            .unwrap_or(false);
+        if matched {
+            *self.verified.lock().unwrap() = Some(digest);
+        }
+        matched
+    }
+}
+
+/// A slug's row of relay_access_policies. The default lets everyone in.
+#[derive(Default)]
+struct Policy {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    basic_auth: Option<(String, Password)>,
+    /// Header name, lowercased, and its expected value.
+    secret_header: Option<(String, Secret)>,
+}
+
+impl Policy {
+    fn admits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+
+    async fn basic_auth_matches(&self, headers: &HeaderMap) -> bool {
+        let Some((user, password)) = &self.basic_auth else { return false };
+        let credentials = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "))
+            .and_then(|v| B64.decode(v.trim()).ok());
+        let Some(credentials) = credentials else { return false };
+        let Some(colon) = credentials.iter().position(|b| *b == b':') else { return false };
+        // Both checked, so a wrong user name takes as long as a wrong password.
+        let user_matches = same(&credentials[..colon], user.as_bytes());
+        password.matches(&credentials[colon + 1..]).await && user_matches
+    }
+
+    fn secret_header_matches(&self, headers: &HeaderMap) -> bool {
+        let Some((name, secret)) = &self.secret_header else { return false };
+        headers.get(name.as_str()).is_some_and(|v| secret.matches(v.as_bytes()))
+    }
+}
+
+/// Why a visitor was turned away.
+pub enum Denied {
+    /// The visitor's address is not allowed.
+    Address,
+    /// Missing or wrong credentials. `basic` when the browser should be asked
+    /// for a login.
+    Credentials { basic: bool },
+    /// Too many wrong credentials lately; carries the seconds until the
+    /// address may try again.
+    Throttled(u64),
+    /// The policy could not be read — nothing gets through until it can.
+    Unavailable,
+}
+
+impl Denied {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Denied::Address => "Access to this instance is restricted",
+            Denied::Credentials { .. } => "This instance requires credentials",
+            Denied::Throttled(_) => "Too many failed logins, try again later",
+            Denied::Unavailable => "Access policy unavailable, try again shortly",
+        }
+    }
+}
+
+// ── Access control ────────────────────────────────────────────────────────────
+
+/// Per-slug access policies — address lists, basic auth and a shared-secret
+/// header — applied at the relay before anything reaches the instance.
+/// Wrong credentials are throttled per slug and address, on each node.
+pub struct AccessControl {
+    pg: Arc<DbPool>,
+    policies: DashMap<String, (Arc<Policy>, Instant)>,
+    /// Wrong credentials per (slug, address), and when their window started.
+    failures: DashMap<(String, IpAddr), (u32, Instant)>,
+}
+
+impl AccessControl {
+    pub fn new(pg: Arc<DbPool>) -> Arc<Self> {
+        Arc::new(Self { pg, policies: DashMap::new(), failures: DashMap::new() })
+    }
+
+    /// Admit an HTTP request for `slug` from `client`. The credentials it
+    /// passed with are meant for the relay and are removed from `headers`.
+    pub async fn check_request(&self, slug: &str, client: IpAddr, headers: &mut HeaderMap) -> Result<(), Denied> {
+        let policy = self.policy(slug).await?;
+        if !policy.admits(client) {
+            return Err(Denied::Address);
+        }
+        if policy.basic_auth.is_none() && policy.secret_header.is_none() {
+            return Ok(());
+        }
+        let key = (slug.to_owned(), client);
+        if let Some(wait) = self.throttled(&key) {
+            return Err(Denied::Throttled(wait));
+        }
+
+        // A visitor sending nothing yet is asked to; only wrong credentials count.
+        let attempted = (policy.basic_auth.is_some() && headers.contains_key(AUTHORIZATION))
+            || policy.secret_header.as_ref().is_some_and(|(name, _)| headers.contains_key(name.as_str()));
+        // Authorization only goes when it held our login: behind a secret
+        // header, it may be the instance's own.
+        let by_header = policy.secret_header_matches(headers);
+        let by_login = !by_header && policy.basic_auth_matches(headers).await;
+        if let Some((name, _)) = &policy.secret_header {
+            headers.remove(name.as_str());
+        }
+        if by_login {
+            headers.remove(AUTHORIZATION);
+        }
+        if !by_header && !by_login {
+            if attempted {
+                self.record_failure(key);
+            }
+            return Err(Denied::Credentials { basic: policy.basic_auth.is_some() });
+        }
+        Ok(())
+    }
+
+    /// Seconds until `key` may try credentials again, if it is throttled.
+    fn throttled(&self, key: &(String, IpAddr)) -> Option<u64> {
+        let (failures, since) = *self.failures.get(key)?;
+        let elapsed = since.elapsed();
+        (failures >= MAX_FAILURES && elapsed < FAILURE_WINDOW)
+            .then(|| (FAILURE_WINDOW - elapsed).as_secs().max(1))
+    }
+
+    fn record_failure(&self, key: (String, IpAddr)) {
+        if self.failures.len() > MAX_TRACKED {
+            self.failures.retain(|_, (_, since)| since.elapsed() < FAILURE_WINDOW);
+        }
+        let mut entry = self.failures.entry(key).or_insert((0, Instant::now()));
+        if entry.1.elapsed() >= FAILURE_WINDOW {
+            *entry = (0, Instant::now());
+        }
+        entry.0 += 1;
+        if entry.0 == MAX_FAILURES {
+            let (slug, client) = entry.key();
+            warn!("Access to '{slug}': {MAX_FAILURES} failed logins from {client}, throttling it");
+        }
+    }
+
+    /// Admit a raw TCP connection for `slug` from `client`: only the address
+    /// lists apply.
+    pub async fn check_address(&self, slug: &str, client: IpAddr) -> Result<(), Denied> {
+        if !self.policy(slug).await?.admits(client) {
+            return Err(Denied::Address);
+        }
+        Ok(())
+    }
+
+    async fn policy(&self, slug: &str) -> Result<Arc<Policy>, Denied> {
+        if let Some(entry) = self.policies.get(slug) {
+            let (policy, at) = entry.value();
+            if at.elapsed() < POLICY_TTL {
+                return Ok(policy.clone());
+            }
+        }
+
+        let row = self
+            .pg
+            .query_opt(
+                "SELECT allow_ips::text[], deny_ips::text[], \
+                        basic_auth_user, basic_auth_hash, \
+                        secret_header, secret_header_salt, secret_header_hash \
+                 FROM relay_access_policies WHERE slug = $1",
+                &[&slug],
+            )
+            .await;
+        let policy = match row {
+            Ok(Some(row)) => {
+                let nets = |i: usize| -> Vec<IpNet> {
+                    row.get::<_, Vec<String>>(i).iter().filter_map(|n| n.parse().ok()).collect()
+                };
+                let text = |i: usize| row.get::<_, Option<String>>(i);
+                let secret_header = text(4).zip(text(5)).zip(text(6)).map(|((name, salt), hash)| {
+                    (name.to_ascii_lowercase(), Secret { salt, hash })
+                });
+                Policy {
+                    allow: nets(0),
+                    deny: nets(1),
+                    basic_auth: text(2).zip(text(3)).map(|(user, hash)| (user, Password::new(hash))),
+                    secret_header,
+                }
+            }
+            Ok(None) => Policy::default(),
+            Err(e) => {
+                // Keep applying the last known policy; without one, fail closed
+                // rather than expose an instance meant to be private.
+                warn!("Access policy lookup for '{slug}' failed: {e}");
+                return match self.policies.get(slug) {
+                    Some(entry) => Ok(entry.value().0.clone()),
+                    None => Err(Denied::Unavailable),
+                };
+            }
+        };
+        let policy = Arc::new(policy);
+        // Random subdomains each get an entry: forget the stale ones.
+        if self.policies.len() > MAX_POLICIES {
+            self.policies.retain(|_, (_, at)| at.elapsed() < POLICY_TTL);
+        }
+        self.policies.insert(slug.to_owned(), (policy.clone(), Instant::now()));
+        Ok(policy)
+    }
+}
@@ -0,0 +1,291 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::Context;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+/// Lines waiting to be written. Past that, entries are dropped rather than
+/// slowing requests down.
+const QUEUE_LEN: usize = 4096;
+
+/// Where access log lines go: a file, or stdout for `-`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("expected a file path, or - for stdout".into()),
+            "-" => Ok(LogTarget::Stdout),
+            path => Ok(LogTarget::File(path.into())),
+        }
+    }
+}
+
+/// Access log destination. Bound at startup, applied on restart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessLogConfig {
+    pub target: LogTarget,
+    /// Size at which the file is rotated; 0 = never.
+    pub max_bytes: u64,
+    /// Rotated files kept next to it (`access.log.1`, `.2`, …).
+    pub keep: usize,
+}
+
+// ── Access log ────────────────────────────────────────────────────────────────
+
+/// Per-request access log of relayed traffic, one JSON object per line,
+/// written by a thread of its own so disk I/O never holds up a request.
+pub struct AccessLog {
+    tx: mpsc::Sender<String>,
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    pub fn open(config: AccessLogConfig) -> anyhow::Result<Arc<Self>> {
+        let mut output = Output::open(config)?;
+        let (tx, mut rx) = mpsc::channel::<String>(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || {
+                while let Some(line) = rx.blocking_recv() {
+                    let mut result = output.write(&line);
+                    // Flush once whatever queued up meanwhile is written too.
+                    while let Ok(line) = rx.try_recv() {
+                        result = result.and_then(|()| output.write(&line));
+                    }
+                    if let Err(e) = result.and_then(|()| output.flush()) {
+                        error!("Writing the access log failed: {e}");
+                    }
+                }
+            })
+            .context("starting the access log writer")?;
+        Ok(Arc::new(Self { tx, dropped: AtomicU64::new(0) }))
+    }
+
+    /// Start the entry of a request. Whether it is written is drawn now, with
+    /// probability `sample`; server errors are written regardless.
+    pub fn entry(
+        self: &Arc<Self>,
+        sample: f64,
+        slug: &str,
+        method: &str,
+        path: &str,
+        client: IpAddr,
+    ) -> LogEntry {
+        let draw = Uuid::new_v4().as_u128() as u64;
+        Arc::new(Entry {
+            log: self.clone(),
+            sampled: sample >= 1.0 || (draw as f64) < sample * u64::MAX as f64,
+            at: SystemTime::now(),
+            started: Instant::now(),
+            slug: slug.to_owned(),
+            method: method.to_owned(),
+            path: path.to_owned(),
+            client,
+            status: AtomicU16::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            tunnel_latency: OnceLock::new(),
+        })
+    }
+
+    fn send(&self, line: String) {
+        if self.tx.try_send(line).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+            if dropped.is_multiple_of(10_000) {
+                warn!("Access log writer is falling behind — {} line(s) dropped so far", dropped + 1);
+            }
+        }
+    }
+}
+
+/// A request's access log entry, shared by whatever still serves the request.
+pub type LogEntry = Arc<Entry>;
+
+/// One request, written when the last handle to it is dropped: once its
+/// response body, or upgraded stream, is over.
+pub struct Entry {
+    log: Arc<AccessLog>,
+    sampled: bool,
+    at: SystemTime,
+    started: Instant,
+    slug: String,
+    method: String,
+    path: String,
+    client: IpAddr,
+    status: AtomicU16,
+    /// Body bytes through the tunnel, as metered for quotas.
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Time for the response head to come back through the tunnel.
+    tunnel_latency: OnceLock<Duration>,
+}
+
+impl Entry {
+    pub fn set_status(&self, status: u16) {
+        self.status.store(status, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn tunnel_latency(&self, latency: Duration) {
+        let _ = self.tunnel_latency.set(latency);
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    ts: String,
+    slug: &'a str,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel_ms: Option<f64>,
+    duration_ms: f64,
+    client: IpAddr,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let status = *self.status.get_mut();
+        if !self.sampled && status < 500 {
+            return;
+        }
+        let ms = |d: Duration| (d.as_secs_f64() * 1e6).round() / 1e3;
+        let line = Line {
+            ts: rfc3339(self.at),
+            slug: &self.slug,
+            method: &self.method,
+            path: &self.path,
+            status,
+            bytes_in: *self.bytes_in.get_mut(),
+            bytes_out: *self.bytes_out.get_mut(),
+            tunnel_ms: self.tunnel_latency.get().copied().map(ms),
+            duration_ms: ms(self.started.elapsed()),
+            client: self.client,
+        };
+        if let Ok(line) = serde_json::to_string(&line) {
+            self.log.send(line);
+        }
+    }
+}
+
+// ── Output ────────────────────────────────────────────────────────────────────
+
+enum Output {
+    Stdout(io::Stdout),
+    File {
+        config: AccessLogConfig,
+        path: PathBuf,
+        file: BufWriter<File>,
+        size: u64,
+    },
+}
+
+impl Output {
+    fn open(config: AccessLogConfig) -> anyhow::Result<Self> {
+        let LogTarget::File(path) = &config.target else { return Ok(Output::Stdout(io::stdout())) };
+        let path = path.clone();
+        let file = append(&path).with_context(|| format!("opening access log {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(Output::File { config, path, file: BufWriter::new(file), size })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stdout(out) => writeln!(out.lock(), "{line}"),
+            Output::File { config, path, file, size } => {
+                let len = line.len() as u64 + 1;
+                if config.max_bytes > 0 && *size > 0 && *size + len > config.max_bytes {
+                    file.flush()?;
+                    rotate(path, config.keep)?;
+                    *file = BufWriter::new(append(path)?);
+                    *size = 0;
+                }
+                writeln!(file, "{line}")?;
+                *size += len;
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(out) => out.flush(),
+            Output::File { file, .. } => file.flush(),
+        }
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Shift `path.1` … `path.<keep - 1>` up by one and move `path` to `path.1`;
+/// with `keep` = 0, just start over.
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let numbered = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    };
+    if keep == 0 {
+        return std::fs::remove_file(path);
+    }
+    for n in (1..keep).rev() {
+        match std::fs::rename(numbered(n), numbered(n + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    std::fs::rename(path, numbered(1))
+}
+
+/// `2026-10-18T09:59:35.843Z`.
+fn rfc3339(at: SystemTime) -> String {
+    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
@@ -0,0 +1,199 @@
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+use super::registry::Registry;
+use super::bans::{AuthBans, Lift};
+
+// ── Entry point ───────────────────────────────────────────────────────────────
+
+/// Admin API, on its own bind address so it is never exposed through Caddy.
+///
+///   GET    /tunnels              connected tunnels with live counters
+///   DELETE /tunnels/{slug}       drop every tunnel of a slug
+///   DELETE /tunnels/{slug}/{id}  drop one tunnel
+///   GET    /bans                 IPs banned from the relay TCP port
+///   DELETE /bans/{ip}            lift a temporary ban, on every relay server
+///
+/// Every request needs `Authorization: Bearer <admin token>`.
+pub async fn run(
+    bind: &str,
+    token: String,
+    registry: Registry,
+    bans: Arc<AuthBans>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    info!("Admin API on {bind}");
+
+    let token: Arc<str> = token.into();
+    loop {
+        let (stream, _addr) = listener.accept().await?;
+        let io = TokioIo::new(stream);
+        let token = token.clone();
+        let registry = registry.clone();
+        let bans = bans.clone();
+
+        tokio::spawn(async move {
+            let svc = service_fn(move |req| {
+                let token = token.clone();
+                let registry = registry.clone();
+                let bans = bans.clone();
+                async move {
+                    let resp = if authorized(&req, &token) {
+                        handle_request(&req, &registry, &bans).await
+                    } else {
+                        json_response(StatusCode::UNAUTHORIZED, json!({ "error": "unauthorized" }))
+                    };
+                    Ok::<_, Infallible>(resp)
+                }
+            });
+            if let Err(e) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(io, svc)
+                .await
+            {
+                error!("Admin API connection error: {e}");
+            }
+        });
+    }
+}
+
+// ── Routes ────────────────────────────────────────────────────────────────────
+
+async fn handle_request(
+    req: &Request<Incoming>,
+    registry: &Registry,
+    bans: &AuthBans,
+) -> Response<Full<Bytes>> {
+    let segments: Vec<&str> = req.uri().path().split('/').filter(|s| !s.is_empty()).collect();
+
+    match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["tunnels"]) => list_tunnels(registry),
+        (&Method::DELETE, ["tunnels", slug]) => disconnect(registry, slug, None),
+        (&Method::DELETE, ["tunnels", slug, id]) => match id.parse() {
+            Ok(id) => disconnect(registry, slug, Some(id)),
+            Err(_) => json_response(StatusCode::BAD_REQUEST, json!({ "error": "invalid tunnel id" })),
+        },
+        (&Method::GET, ["bans"]) => list_bans(bans),
+        (&Method::DELETE, ["bans", ip]) => match ip.parse::<IpAddr>() {
+            Ok(ip) => lift_ban(bans, ip).await,
+            Err(_) => json_response(StatusCode::BAD_REQUEST, json!({ "error": "invalid IP address" })),
+        },
+        _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    }
+}
+
+fn list_tunnels(registry: &Registry) -> Response<Full<Bytes>> {
+    let mut tunnels = registry.snapshot();
+    tunnels.sort_by(|(a, ta), (b, tb)| a.cmp(b).then(ta.id.cmp(&tb.id)));
+
+    let tunnels: Vec<Value> = tunnels
+        .into_iter()
+        .map(|(slug, t)| {
+            json!({
+                "slug":         slug,
+                "id":           t.id,
+                "peer":         t.peer.to_string(),
+                "connected_at": unix_secs(t.connected_at),
+                "in_flight":    t.in_flight(),
+                "bytes_in":     t.bytes_in(),
+                "bytes_out":    t.bytes_out(),
+                "draining":     t.is_draining(),
+                "capabilities": &*t.capabilities,
+            })
+        })
+        .collect();
+    json_response(StatusCode::OK, json!({ "tunnels": tunnels }))
+}
+
+fn disconnect(registry: &Registry, slug: &str, id: Option<u64>) -> Response<Full<Bytes>> {
+    let tunnels: Vec<_> = registry
+        .tunnels(slug)
+        .into_iter()
+        .filter(|t| id.is_none_or(|id| t.id == id))
+        .collect();
+    if tunnels.is_empty() {
+        return json_response(StatusCode::NOT_FOUND, json!({ "error": "no such tunnel" }));
+    }
+
+    for t in &tunnels {
+        t.disconnect();
+    }
+    warn!("Admin: disconnected {} tunnel(s) of '{slug}'", tunnels.len());
+    json_response(StatusCode::OK, json!({ "disconnected": tunnels.len() }))
+}
+
+fn list_bans(bans: &AuthBans) -> Response<Full<Bytes>> {
+    let mut banned = bans.banned();
+    banned.sort_by_key(|(ip, _)| *ip);
+
+    let bans: Vec<Value> = banned
+        .into_iter()
+        .map(|(ip, ban)| {
+            json!({
+                "ip":        ip.to_string(),
+                "until":     ban.until,
+                "strikes":   ban.strikes,
+                "permanent": ban.until.is_none(),
+            })
+        })
+        .collect();
+    json_response(StatusCode::OK, json!({ "bans": bans }))
+}
+
+async fn lift_ban(bans: &AuthBans, ip: IpAddr) -> Response<Full<Bytes>> {
+    match bans.lift(ip).await {
+        Ok(Lift::Lifted) => {
+            warn!("Admin: lifted auth ban on {ip}");
+            json_response(StatusCode::OK, json!({ "lifted": ip.to_string() }))
+        }
+        Ok(Lift::NotBanned) => {
+            json_response(StatusCode::NOT_FOUND, json!({ "error": "IP not in ban list" }))
+        }
+        Ok(Lift::Permanent) => json_response(
+            StatusCode::CONFLICT,
+            json!({ "error": "IP is in ip_bans; lift it from the instance admin" }),
+        ),
+        Err(e) => {
+            error!("Admin: lifting the ban on {ip} failed: {e}");
+            json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": "database error" }))
+        }
+    }
+}
+
+// ── Helpers ───────────────────────────────────────────────────────────────────
+
+/// Bearer token check, in constant time.
+fn authorized(req: &Request<Incoming>, token: &str) -> bool {
+    let Some(given) = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    given.len() == token.len()
+        && given.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
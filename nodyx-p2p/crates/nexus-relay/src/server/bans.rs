@@ -0,0 +1,203 @@
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use dashmap::DashMap;
+use tracing::warn;
+
+use super::db::DbPool;
+
+/// How often active bans are re-read from the database, which is how bans
+/// set by other relay servers (or lifted by an admin) reach this one.
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+/// How long after its last ban an IP starts over at the base ban duration.
+const STRIKE_MEMORY: Duration = Duration::from_secs(86_400);
+
+/// When failed registrations get an IP banned, and for how long.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BanPolicy {
+    /// Failures within `window` that trigger a ban.
+    pub max_failures: u32,
+    pub window: Duration,
+    /// Length of a first ban; each ban within a day of the previous one doubles it.
+    pub ban: Duration,
+    /// Upper bound on an escalated ban.
+    pub max_ban: Duration,
+}
+
+impl BanPolicy {
+    /// Length of the `strike`-th ban (1-based).
+    fn duration(&self, strike: u32) -> Duration {
+        let factor = 1u32.checked_shl(strike.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.ban.saturating_mul(factor).min(self.max_ban)
+    }
+}
+
+/// An IP refused on the relay TCP port.
+#[derive(Clone, Copy)]
+pub struct Ban {
+    /// Unix seconds; `None` for a permanent block from `ip_bans`.
+    pub until: Option<u64>,
+    /// Bans so far, which set the length of the next one.
+    pub strikes: u32,
+}
+
+/// Outcome of a failed registration.
+pub struct Failure {
+    /// Failures in the current window (reset by a ban).
+    pub failures: u32,
+    /// Set when this failure got the IP banned.
+    pub ban: Option<Duration>,
+}
+
+/// Outcome of an admin lifting a ban.
+pub enum Lift {
+    Lifted,
+    NotBanned,
+    /// In `ip_bans`, which is managed from the instance admin.
+    Permanent,
+}
+
+// ── Auth bans ─────────────────────────────────────────────────────────────────
+
+/// Brute-force protection for the relay TCP port. Failures and bans live in
+/// relay_auth_bans, shared by every relay server on the database and kept
+/// across restarts; the active ones, plus the permanent blocks of ip_bans,
+/// are mirrored in memory so refusing a banned IP costs no query.
+pub struct AuthBans {
+    pg: Arc<DbPool>,
+    policy: RwLock<BanPolicy>,
+    banned: DashMap<IpAddr, Ban>,
+}
+
+impl AuthBans {
+    pub fn new(pg: Arc<DbPool>, policy: BanPolicy) -> Arc<Self> {
+        let bans = Arc::new(Self { pg, policy: RwLock::new(policy), banned: DashMap::new() });
+
+        let syncer = bans.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = syncer.sync().await {
+                    warn!("Auth ban sync failed, keeping the current list: {e}");
+                }
+                tokio::time::sleep(SYNC_INTERVAL).await;
+            }
+        });
+        bans
+    }
+
+    /// Apply new thresholds; bans already in place keep their length.
+    pub fn set_policy(&self, policy: BanPolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.get(&ip).is_some_and(|ban| ban.until.is_none_or(|until| until > now_secs()))
+    }
+
+    /// IPs currently refused.
+    pub fn banned(&self) -> Vec<(IpAddr, Ban)> {
+        let now = now_secs();
+        self.banned
+            .iter()
+            .filter(|e| e.until.is_none_or(|until| until > now))
+            .map(|e| (*e.key(), *e.value()))
+            .collect()
+    }
+
+    /// Count a failed registration from `ip`, banning it once it reaches
+    /// the policy's threshold.
+    pub async fn record_failure(&self, ip: IpAddr) -> anyhow::Result<Failure> {
+        let policy = *self.policy.read().unwrap();
+        let row = self
+            .pg
+            .query_opt(
+                "INSERT INTO relay_auth_bans AS b (ip, failures, window_start) VALUES ($1, 1, NOW()) \
+                 ON CONFLICT (ip) DO UPDATE SET \
+                   failures     = CASE WHEN b.window_start < NOW() - make_interval(secs => $2) \
+                                       THEN 1 ELSE b.failures + 1 END, \
+                   window_start = CASE WHEN b.window_start < NOW() - make_interval(secs => $2) \
+                                       THEN NOW() ELSE b.window_start END \
+                 RETURNING failures, strikes, \
+                   COALESCE(banned_until > NOW() - make_interval(secs => $3), FALSE)",
+                &[&ip, &policy.window.as_secs_f64(), &STRIKE_MEMORY.as_secs_f64()],
+            )
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("relay_auth_bans upsert returned no row"))?;
+        let failures = row.get::<_, i32>(0).max(0) as u32;
+        if failures < policy.max_failures {
+            return Ok(Failure { failures, ban: None });
+        }
+
+        let strikes = if row.get::<_, bool>(2) { row.get::<_, i32>(1).max(0) as u32 + 1 } else { 1 };
+        let ban = policy.duration(strikes);
+        self.pg
+            .execute(
+                "UPDATE relay_auth_bans \
+                 SET failures = 0, strikes = $2, banned_until = NOW() + make_interval(secs => $3) \
+                 WHERE ip = $1",
+                &[&ip, &(strikes as i32), &ban.as_secs_f64()],
+            )
+            .await?;
+        self.banned.insert(ip, Ban { until: Some(now_secs() + ban.as_secs()), strikes });
+        Ok(Failure { failures, ban: Some(ban) })
+    }
+
+    /// Lift a temporary ban everywhere and forget the IP's strikes.
+    pub async fn lift(&self, ip: IpAddr) -> anyhow::Result<Lift> {
+        if self.banned.get(&ip).is_some_and(|ban| ban.until.is_none()) {
+            return Ok(Lift::Permanent);
+        }
+        let lifted = self
+            .pg
+            .execute(
+                "DELETE FROM relay_auth_bans WHERE ip = $1 AND banned_until > NOW()",
+                &[&ip],
+            )
+            .await?;
+        self.banned.remove(&ip);
+        Ok(if lifted > 0 { Lift::Lifted } else { Lift::NotBanned })
+    }
+
+    /// Replace the in-memory list with the active bans of the database, and
+    /// drop rows that no longer matter for escalation.
+    async fn sync(&self) -> anyhow::Result<()> {
+        let rows = self
+            .pg
+            .query(
+                "SELECT ip, strikes, EXTRACT(EPOCH FROM banned_until)::BIGINT \
+                 FROM relay_auth_bans WHERE banned_until > NOW() \
+                 UNION ALL \
+                 SELECT ip, 0, NULL FROM ip_bans",
+                &[],
+            )
+            .await?;
+        let active: DashMap<IpAddr, Ban> = rows
+            .iter()
+            .map(|row| {
+                let ban = Ban {
+                    until: row.get::<_, Option<i64>>(2).map(|until| until.max(0) as u64),
+                    strikes: row.get::<_, i32>(1).max(0) as u32,
+                };
+                (row.get::<_, IpAddr>(0), ban)
+            })
+            .collect();
+        self.banned.retain(|ip, _| active.contains_key(ip));
+        for (ip, ban) in active {
+            self.banned.insert(ip, ban);
+        }
+
+        self.pg
+            .execute(
+                "DELETE FROM relay_auth_bans \
+                 WHERE window_start < NOW() - make_interval(secs => $1) \
+                   AND (banned_until IS NULL OR banned_until < NOW() - make_interval(secs => $1))",
+                &[&STRIKE_MEMORY.as_secs_f64()],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
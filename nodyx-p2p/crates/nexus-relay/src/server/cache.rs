@@ -0,0 +1,250 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use bytes::{Bytes, BytesMut};
+use hyper::body::Incoming;
+use hyper::{Method, Request};
+
+use crate::protocol::Headers;
+
+/// How long past its freshness lifetime a response may still stand in for an
+/// offline instance, unless it sets `stale-if-error` itself.
+const DEFAULT_STALE_IF_ERROR: Duration = Duration::from_secs(3600);
+
+/// Largest share of the cache a single response may take.
+const MAX_ENTRY_SHARE: usize = 8;
+
+type Key = (String, String);
+
+/// A GET for a tunnelled slug, as far as the cache is concerned.
+pub struct CacheRequest {
+    key: Key,
+    accept_encoding: Option<String>,
+    /// Sent `Authorization` or `Cookie`, so may get a page meant for one user.
+    credentials: bool,
+}
+
+impl CacheRequest {
+    /// `None` for anything but a GET.
+    pub fn new(slug: &str, req: &Request<Incoming>) -> Option<Self> {
+        if req.method() != Method::GET {
+            return None;
+        }
+        let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+        Some(Self {
+            key: (slug.to_owned(), req.uri().to_string()),
+            accept_encoding: header("accept-encoding").map(str::to_owned),
+            credentials: req.headers().contains_key("authorization") || req.headers().contains_key("cookie"),
+        })
+    }
+}
+
+/// A stored response.
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Bytes,
+    pub stored_at: Instant,
+    usable_until: Instant,
+    /// Request accept-encoding, when the response varies on it.
+    encoding: Option<Option<String>>,
+    generation: u64,
+}
+
+/// Recent cacheable GET responses of tunnelled slugs, served only while the
+/// slug has no tunnel so read-only pages survive short outages. Bounded in
+/// bytes; the oldest entries go first.
+pub struct ResponseCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Key, Arc<CachedResponse>>,
+    /// Insertion order, for eviction. Entries replaced since are skipped by
+    /// generation.
+    order: VecDeque<(Key, u64)>,
+    size: usize,
+    next_generation: u64,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, inner: Mutex::default() }
+    }
+
+    /// A stored response usable for `req` while its slug is offline.
+    pub fn lookup(&self, req: &CacheRequest) -> Option<Arc<CachedResponse>> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(&req.key)?.clone();
+        if entry.usable_until < Instant::now() {
+            inner.remove(&req.key);
+            return None;
+        }
+        if entry.encoding.as_ref().is_some_and(|e| *e != req.accept_encoding) {
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// Start recording a response if its headers allow a shared cache to
+    /// keep it. Only complete bodies of known length are stored.
+    pub fn admit(
+        self: &Arc<Self>,
+        req: CacheRequest,
+        status: u16,
+        headers: &Headers,
+    ) -> Option<CacheFill> {
+        if status != 200 || headers.contains("set-cookie") {
+            return None;
+        }
+        let policy = CachePolicy::parse(headers.get("cache-control")?);
+        if policy.no_store || !(policy.public || policy.max_age.is_some()) {
+            return None;
+        }
+        // Responses to credentialed requests are per-user unless explicitly shared.
+        if req.credentials && !policy.public {
+            return None;
+        }
+
+        // Only Accept-Encoding is matched on lookup; anything else it may
+        // vary on (Cookie, …) could hand one visitor's page to another.
+        let mut vary = headers.iter().filter(|(name, _)| *name == "vary").peekable();
+        let encoding = if vary.peek().is_some() {
+            let only_encoding = vary.all(|(_, value)| {
+                std::str::from_utf8(value)
+                    .is_ok_and(|v| v.split(',').all(|v| v.trim().eq_ignore_ascii_case("accept-encoding")))
+            });
+            if !only_encoding {
+                return None;
+            }
+            Some(req.accept_encoding.clone())
+        } else {
+            None
+        };
+
+        let length: usize = headers.get("content-length")?.parse().ok()?;
+        if length > self.capacity / MAX_ENTRY_SHARE {
+            return None;
+        }
+
+        let now = Instant::now();
+        let max_age = Duration::from_secs(policy.max_age.unwrap_or(0));
+        let stale = policy.stale_if_error.map_or(DEFAULT_STALE_IF_ERROR, Duration::from_secs);
+        Some(CacheFill {
+            cache: self.clone(),
+            key: req.key,
+            status,
+            headers: headers.clone(),
+            encoding,
+            usable_until: now + max_age + stale,
+            length,
+            body: BytesMut::with_capacity(length),
+        })
+    }
+
+    fn store(&self, key: Key, mut entry: CachedResponse) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+
+        entry.generation = inner.next_generation;
+        inner.next_generation += 1;
+        inner.size += entry.body.len();
+        inner.order.push_back((key.clone(), entry.generation));
+        inner.entries.insert(key, Arc::new(entry));
+
+        while inner.size > self.capacity {
+            let Some((key, generation)) = inner.order.pop_front() else { break };
+            if inner.entries.get(&key).is_some_and(|e| e.generation == generation) {
+                inner.remove(&key);
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &Key) {
+        if let Some(old) = self.entries.remove(key) {
+            self.size -= old.body.len();
+        }
+    }
+}
+
+/// A response being recorded as it streams to the browser.
+pub struct CacheFill {
+    cache: Arc<ResponseCache>,
+    key: Key,
+    status: u16,
+    headers: Headers,
+    encoding: Option<Option<String>>,
+    usable_until: Instant,
+    length: usize,
+    body: BytesMut,
+}
+
+impl CacheFill {
+    /// Record a body chunk. The response is stored as soon as its body
+    /// reaches the announced Content-Length (hyper stops reading there), and
+    /// given up if it goes past it; both consume the fill.
+    pub fn push(mut self, data: &Bytes) -> Option<Self> {
+        self.body.extend_from_slice(data);
+        match self.body.len().cmp(&self.length) {
+            Ordering::Less => Some(self),
+            Ordering::Equal => {
+                self.finish();
+                None
+            }
+            Ordering::Greater => None,
+        }
+    }
+
+    fn finish(self) {
+        let entry = CachedResponse {
+            status: self.status,
+            headers: self.headers,
+            body: self.body.freeze(),
+            stored_at: Instant::now(),
+            usable_until: self.usable_until,
+            encoding: self.encoding,
+            generation: 0,
+        };
+        self.cache.store(self.key, entry);
+    }
+}
+
+/// The Cache-Control directives that matter to a shared cache.
+#[derive(Default)]
+struct CachePolicy {
+    public: bool,
+    /// `no-store`, `no-cache` or `private`.
+    no_store: bool,
+    /// `s-maxage`, or else `max-age`.
+    max_age: Option<u64>,
+    stale_if_error: Option<u64>,
+}
+
+impl CachePolicy {
+    fn parse(header: &str) -> Self {
+        let mut policy = Self::default();
+        let mut s_maxage = None;
+        for directive in header.split(',') {
+            let (name, value) = match directive.split_once('=') {
+                Some((n, v)) => (n.trim(), Some(v.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = value.and_then(|v| v.parse().ok());
+            match name.to_ascii_lowercase().as_str() {
+                "public" => policy.public = true,
+                "no-store" | "no-cache" | "private" => policy.no_store = true,
+                "max-age" => policy.max_age = seconds,
+                "s-maxage" => s_maxage = seconds,
+                "stale-if-error" => policy.stale_if_error = seconds,
+                _ => {}
+            }
+        }
+        policy.max_age = s_maxage.or(policy.max_age);
+        policy
+    }
+}
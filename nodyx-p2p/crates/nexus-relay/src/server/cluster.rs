@@ -0,0 +1,168 @@
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use hyper::body::Incoming;
+use hyper::HeaderMap;
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use tracing::{info, warn};
+
+use super::db::DbPool;
+use super::registry::Registry;
+
+/// Slug a forwarded request is for, set by the node that received it.
+pub const SLUG_HEADER: &str = "x-nodyx-relay-slug";
+/// Shared secret proving a request comes from another node of the cluster.
+pub const SECRET_HEADER: &str = "x-nodyx-relay-secret";
+
+/// How often this node refreshes its entries in relay_cluster_tunnels.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(2);
+/// Entries not refreshed for this long belong to a node that went away.
+pub const NODE_EXPIRY: Duration = Duration::from_secs(15);
+/// How long where a slug's tunnel lives (or that none does) is remembered.
+const LOCATE_TTL: Duration = Duration::from_secs(2);
+
+/// Cluster mode settings. Every node uses the same database and secret.
+#[derive(Clone, PartialEq)]
+pub struct ClusterConfig {
+    /// Bind address of the listener other nodes forward requests to.
+    pub bind: String,
+    /// "host:port" other nodes reach that listener on; identifies this node.
+    pub advertise: String,
+    pub secret: String,
+}
+
+/// Whether `ip` is only reachable on a private network: loopback, RFC 1918,
+/// shared address space (100.64.0.0/10) or IPv6 unique local. Requests
+/// between nodes, and the secret they carry, are not encrypted.
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local(),
+    }
+}
+
+// ── Cluster ───────────────────────────────────────────────────────────────────
+
+/// Cross-node routing: this node publishes the slugs it holds tunnels for in
+/// relay_cluster_tunnels, and looks up which node holds a slug it doesn't.
+pub struct Cluster {
+    /// Our advertised address.
+    node: String,
+    secret: String,
+    pg: Arc<DbPool>,
+    /// For requests forwarded to other nodes.
+    pub client: Client<HttpConnector, Incoming>,
+    located: DashMap<String, (Option<String>, Instant)>,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig, pg: Arc<DbPool>) -> Arc<Self> {
+        Arc::new(Self {
+            node: config.advertise.clone(),
+            secret: config.secret.clone(),
+            pg,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            located: DashMap::new(),
+        })
+    }
+
+    /// Keep this node's entries in line with `registry`. Runs until cancelled;
+    /// call `withdraw` afterwards.
+    pub async fn publish(&self, registry: &Registry) -> std::io::Result<()> {
+        info!("Cluster node {} publishing its tunnels", self.node);
+        let mut published = None;
+        loop {
+            let slugs: BTreeSet<String> = registry.snapshot().into_iter().map(|(slug, _)| slug).collect();
+            if let Err(e) = self.sync(&slugs, published.as_ref() != Some(&slugs)).await {
+                warn!("Cluster publish failed: {e}");
+                published = None;
+            } else {
+                published = Some(slugs);
+            }
+            tokio::time::sleep(PUBLISH_INTERVAL).await;
+        }
+    }
+
+    /// Refresh our entries, rewriting them when the slug set `changed`.
+    async fn sync(&self, slugs: &BTreeSet<String>, changed: bool) -> anyhow::Result<()> {
+        if changed {
+            let slugs: Vec<&str> = slugs.iter().map(String::as_str).collect();
+            self.pg
+                .execute(
+                    "DELETE FROM relay_cluster_tunnels WHERE node = $1 AND slug <> ALL($2)",
+                    &[&self.node, &slugs],
+                )
+                .await?;
+            self.pg
+                .execute(
+                    "INSERT INTO relay_cluster_tunnels (slug, node) SELECT unnest($2::text[]), $1 \
+                     ON CONFLICT (slug, node) DO UPDATE SET seen_at = NOW()",
+                    &[&self.node, &slugs],
+                )
+                .await?;
+        }
+        self.pg
+            .execute("UPDATE relay_cluster_tunnels SET seen_at = NOW() WHERE node = $1", &[&self.node])
+            .await?;
+        Ok(())
+    }
+
+    /// Remove this node's entries, so other nodes stop forwarding to it.
+    pub async fn withdraw(&self) {
+        match self.pg.execute("DELETE FROM relay_cluster_tunnels WHERE node = $1", &[&self.node]).await {
+            Ok(_) => info!("Cluster node {} withdrawn", self.node),
+            Err(e) => warn!("Cluster withdraw failed: {e}"),
+        }
+    }
+
+    /// Another node holding a tunnel for `slug`, if any.
+    pub async fn locate(&self, slug: &str) -> Option<String> {
+        if let Some(entry) = self.located.get(slug) {
+            let (node, at) = entry.value();
+            if at.elapsed() < LOCATE_TTL {
+                return node.clone();
+            }
+        }
+
+        let row = self
+            .pg
+            .query_opt(
+                "SELECT node FROM relay_cluster_tunnels \
+                 WHERE slug = $1 AND node <> $2 AND seen_at > NOW() - make_interval(secs => $3) \
+                 ORDER BY random() LIMIT 1",
+                &[&slug, &self.node, &NODE_EXPIRY.as_secs_f64()],
+            )
+            .await;
+        let node = match row {
+            Ok(row) => row.map(|row| row.get::<_, String>(0)),
+            Err(e) => {
+                warn!("Cluster lookup for '{slug}' failed: {e}");
+                return None;
+            }
+        };
+        self.located.insert(slug.to_owned(), (node.clone(), Instant::now()));
+        node
+    }
+
+    /// Stop forwarding `slug` to the node it was located on, which failed.
+    pub fn forget(&self, slug: &str) {
+        self.located.remove(slug);
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    /// Whether `headers` carry the cluster secret, compared in constant time.
+    pub fn authenticate(&self, headers: &HeaderMap) -> bool {
+        let Some(given) = headers.get(SECRET_HEADER).map(|v| v.as_bytes()) else {
+            return false;
+        };
+        let secret = self.secret.as_bytes();
+        given.len() == secret.len()
+            && given.iter().zip(secret).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
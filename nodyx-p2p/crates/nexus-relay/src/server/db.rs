@@ -59,6 +59,66 @@ impl DbPool {
         }
     }
 
+    /// Run a query returning any number of rows. On failure, reconnects once
+    /// and retries.
+    pub async fn query(
+        &self,
+        sql: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> anyhow::Result<Vec<Row>> {
+        {
+            let guard = self.client.lock().await;
+            if let Some(c) = guard.as_ref() {
+                match c.query(sql, params).await {
+                    Ok(rows) => return Ok(rows),
+                    Err(e) => warn!("DB query failed ({e}), reconnecting…"),
+                }
+            }
+        }
+
+        match Self::new_connection(&self.database_url).await {
+            Ok(fresh) => {
+                let rows = fresh.query(sql, params).await?;
+                *self.client.lock().await = Some(fresh);
+                Ok(rows)
+            }
+            Err(e) => {
+                *self.client.lock().await = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Run a statement, returning the number of rows affected. On failure,
+    /// reconnects once and retries.
+    pub async fn execute(
+        &self,
+        sql: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> anyhow::Result<u64> {
+        {
+            let guard = self.client.lock().await;
+            if let Some(c) = guard.as_ref() {
+                match c.execute(sql, params).await {
+                    Ok(n) => return Ok(n),
+                    Err(e) => warn!("DB statement failed ({e}), reconnecting…"),
+                }
+            }
+        }
+
+        match Self::new_connection(&self.database_url).await {
+            Ok(fresh) => {
+                let n = fresh.execute(sql, params).await?;
+                *self.client.lock().await = Some(fresh);
+                Ok(n)
+            }
+            Err(e) => {
+                *self.client.lock().await = None;
+                Err(e)
+            }
+        }
+    }
+
     async fn new_connection(database_url: &str) -> anyhow::Result<Client> {
         let (client, conn) = tokio_postgres::connect(database_url, NoTls).await?;
         tokio::spawn(async move {
@@ -0,0 +1,84 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use tracing::warn;
+
+use super::db::DbPool;
+
+/// How long a custom-domain lookup (hit or miss) is trusted before
+/// asking PostgreSQL again.
+const CUSTOM_DOMAIN_TTL: Duration = Duration::from_secs(60);
+
+/// Host → slug routing for the HTTP proxy.
+///
+/// `slug.<base domain>` maps to `slug` for every configured base domain.
+/// Any other host is looked up in `relay_custom_domains`.
+pub struct DomainRouter {
+    /// Base domains, lowercase, without leading dot. The first one is canonical.
+    domains: Vec<String>,
+    pg: Arc<DbPool>,
+    /// host → (slug, looked up at).
+    cache: DashMap<String, (Option<String>, Instant)>,
+}
+
+impl DomainRouter {
+    pub fn new(domains: Vec<String>, pg: Arc<DbPool>) -> Self {
+        let domains = domains
+            .into_iter()
+            .map(|d| d.trim().trim_start_matches('.').to_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+        Self { domains, pg, cache: DashMap::new() }
+    }
+
+    /// The canonical base domain, used for redirects to the main instance.
+    pub fn primary_domain(&self) -> &str {
+        self.domains.first().map(String::as_str).unwrap_or("nodyx.org")
+    }
+
+    /// Resolve a Host header (with optional port) to a slug.
+    /// `None` for a base domain itself or an unknown host.
+    pub async fn resolve(&self, host: &str) -> Option<String> {
+        let host = host.split(':').next().unwrap_or(host).to_lowercase();
+
+        for domain in &self.domains {
+            if host == *domain {
+                return None;
+            }
+            if let Some(slug) = host.strip_suffix(domain.as_str()).and_then(|h| h.strip_suffix('.')) {
+                return (!slug.is_empty() && !slug.contains('.')).then(|| slug.to_owned());
+            }
+        }
+
+        self.resolve_custom(&host).await
+    }
+
+    async fn resolve_custom(&self, host: &str) -> Option<String> {
+        if let Some(entry) = self.cache.get(host) {
+            let (slug, at) = entry.value();
+            if at.elapsed() < CUSTOM_DOMAIN_TTL {
+                return slug.clone();
+            }
+        }
+
+        let slug = match self
+            .pg
+            .query_opt("SELECT slug FROM relay_custom_domains WHERE domain = $1", &[&host])
+            .await
+        {
+            Ok(row) => row.map(|r| r.get::<_, String>(0)),
+            Err(e) => {
+                // Don't cache failures — the next request retries.
+                warn!("Custom domain lookup for '{host}' failed: {e}");
+                return None;
+            }
+        };
+
+        // Random hosts can be thrown at the proxy; keep the cache bounded.
+        if self.cache.len() > 10_000 {
+            self.cache.retain(|_, (_, at)| at.elapsed() < CUSTOM_DOMAIN_TTL);
+        }
+        self.cache.insert(host.to_owned(), (slug.clone(), Instant::now()));
+        slug
+    }
+}
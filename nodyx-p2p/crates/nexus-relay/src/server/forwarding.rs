@@ -0,0 +1,161 @@
+use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use hyper::header::{HeaderValue, HOST};
+use hyper::HeaderMap;
+use ipnet::IpNet;
+
+/// Headers describing where a request came from. Set by the relay on every
+/// public request, whatever the browser sent.
+const FORWARDING_HEADERS: &[&str] =
+    &["forwarded", "x-forwarded-for", "x-forwarded-host", "x-forwarded-proto", "x-real-ip"];
+
+/// An address or network whose forwarding headers are believed: the Caddy
+/// in front of the relay, a load balancer, …
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrustedProxy(IpNet);
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    /// Parses `ADDRESS` or `ADDRESS/PREFIX`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Self(net.trunc()));
+        }
+        s.parse::<IpAddr>()
+            .map(|ip| Self(IpNet::from(ip)))
+            .map_err(|_| format!("expected an IP address or network (CIDR), got '{s}'"))
+    }
+}
+
+fn trusted(proxies: &[TrustedProxy], ip: IpAddr) -> bool {
+    proxies.iter().any(|proxy| proxy.0.contains(&ip))
+}
+
+// ── Forwarding chain ──────────────────────────────────────────────────────────
+
+/// Where a request came from, as far as it can be told.
+pub struct Forwarding {
+    /// The visitor first, then each proxy down to the one connected to us.
+    chain: Vec<IpAddr>,
+    /// Host the visitor asked for.
+    host: Option<String>,
+    /// Scheme the visitor used.
+    proto: &'static str,
+}
+
+impl Forwarding {
+    /// Work out the chain of a request received from `peer`.
+    ///
+    /// X-Forwarded-For is read right to left for as long as the address that
+    /// reported an entry is a trusted proxy; the first address that isn't is
+    /// the visitor, and anything to its left (which the visitor may have made
+    /// up) is dropped. X-Forwarded-Proto and X-Forwarded-Host are only taken
+    /// from a trusted `peer`.
+    pub fn resolve(headers: &HeaderMap, peer: SocketAddr, proxies: &[TrustedProxy]) -> Self {
+        let peer_ip = peer.ip().to_canonical();
+        let peer_trusted = trusted(proxies, peer_ip);
+
+        let mut chain = vec![peer_ip];
+        if peer_trusted {
+            let listed: Vec<&str> = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .collect();
+            for entry in listed.into_iter().rev() {
+                if !trusted(proxies, chain[chain.len() - 1]) {
+                    break;
+                }
+                let Some(ip) = parse_node(entry) else { break };
+                chain.push(ip);
+            }
+        }
+        chain.reverse();
+
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+        let proto = match header("x-forwarded-proto").filter(|_| peer_trusted) {
+            Some(p) if p.eq_ignore_ascii_case("https") => "https",
+            _ => "http",
+        };
+        let host = header("x-forwarded-host")
+            .filter(|_| peer_trusted)
+            .or_else(|| header(HOST.as_str()))
+            .filter(|h| !h.is_empty())
+            .map(str::to_owned);
+
+        Self { chain, host, proto }
+    }
+
+    pub fn client(&self) -> IpAddr {
+        self.chain[0]
+    }
+
+    /// Replace the forwarding headers of `headers` with this chain: the
+    /// X-Forwarded-* family, X-Real-IP, and their RFC 7239 `Forwarded`
+    /// equivalent.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in FORWARDING_HEADERS {
+            headers.remove(*name);
+        }
+
+        let xff = self.chain.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ");
+        let mut forwarded = String::new();
+        for (i, ip) in self.chain.iter().enumerate() {
+            if i > 0 {
+                forwarded.push_str(", ");
+            }
+            match ip {
+                IpAddr::V4(ip) => write!(forwarded, "for={ip}"),
+                IpAddr::V6(ip) => write!(forwarded, "for=\"[{ip}]\""),
+            }
+            .unwrap();
+            if i == 0 {
+                if let Some(host) = &self.host {
+                    write!(forwarded, ";host={}", quote(host)).unwrap();
+                }
+                write!(forwarded, ";proto={}", self.proto).unwrap();
+            }
+        }
+
+        let mut set = |name: &'static str, value: &str| {
+            if let Ok(v) = HeaderValue::try_from(value) {
+                headers.insert(name, v);
+            }
+        };
+        set("x-forwarded-for", &xff);
+        set("x-forwarded-proto", self.proto);
+        if let Some(host) = &self.host {
+            set("x-forwarded-host", host);
+        }
+        set("x-real-ip", &self.client().to_string());
+        set("forwarded", &forwarded);
+    }
+}
+
+/// An X-Forwarded-For entry: an IP address, possibly with a port.
+fn parse_node(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+    entry
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| entry.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .map(|ip| ip.to_canonical())
+}
+
+/// RFC 7230 quoted-string.
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
@@ -1,177 +1,787 @@
-use std::sync::Arc;
+use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
-use hyper::{body::Incoming, Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use hyper::header::{HeaderValue, HOST, LOCATION};
+use hyper::{body::{Frame, Incoming}, Request, Response, StatusCode, Uri};
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
-use super::db::DbPool;
-use tracing::{error, info};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use super::access::{AccessControl, Denied};
+use super::access_log::{AccessLog, LogEntry};
+use super::cache::{CacheFill, CacheRequest, CachedResponse, ResponseCache};
+use super::cluster::{Cluster, SECRET_HEADER, SLUG_HEADER};
+use super::domains::DomainRouter;
+use super::forwarding::{Forwarding, TrustedProxy};
+use super::metrics::Metrics;
+use super::offline::OfflinePages;
+use super::quotas::{Meter, QuotaExceeded, Quotas};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
 use uuid::Uuid;
-use base64::{Engine as _, engine::general_purpose::STANDARD as B64};
 
-use crate::protocol::ServerMessage;
-use super::registry::{PendingRequest, Registry};
+use crate::protocol::{self, BodyReceiver, CHUNK_SIZE, Flow, Headers, ServerMessage, Window, capability};
+use crate::timeouts::{ServerTimeouts, REPLY_GRACE};
+use super::registry::{InFlight, PendingRequest, Registry, RelayResponse};
+
+/// Response body type: a small in-memory body, a stream of chunks coming
+/// back through the tunnel, or the upstream's body passed through as-is.
+/// An error makes hyper drop the connection, so a body cut short never
+/// passes for a complete one.
+type ProxyBody = BoxBody<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Shared by every connection of the HTTP proxy. Replaced as a whole when
+/// the configuration is reloaded; requests keep the state they started with.
+pub struct ProxyState {
+    pub registry: Registry,
+    pub router: Arc<DomainRouter>,
+    pub main_slug: String,
+    pub upstream: Option<Upstream>,
+    pub metrics: Arc<Metrics>,
+    pub quotas: Arc<Quotas>,
+    /// Per-slug access policies set by instance owners.
+    pub access: Arc<AccessControl>,
+    pub timeouts: ServerTimeouts,
+    pub offline: OfflinePages,
+    /// Responses kept for offline slugs; disabled when `None`.
+    pub cache: Option<Arc<ResponseCache>>,
+    /// Other relay servers to forward to; `None` outside cluster mode.
+    pub cluster: Option<Arc<Cluster>>,
+    /// Proxies in front of the relay whose X-Forwarded-* headers are believed.
+    pub trusted_proxies: Vec<TrustedProxy>,
+    /// Where relayed requests are logged; disabled when `None`.
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Share of requests written to the access log.
+    pub access_log_sample: f64,
+}
+
+/// Who a proxy listener serves.
+#[derive(Clone, Copy)]
+pub enum Entry {
+    /// Browsers, through Caddy.
+    Public,
+    /// Other nodes of the cluster, forwarding requests for slugs whose
+    /// tunnel is connected here. Never forwarded again.
+    Cluster,
+}
+
+/// Local HTTP server (nexus-core) serving the main slug and unknown hosts.
+pub struct Upstream {
+    /// "host:port", also sent as the Host header.
+    authority: String,
+    client: Client<HttpConnector, Incoming>,
+}
+
+impl Upstream {
+    pub fn new(authority: String) -> Self {
+        Self {
+            authority,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+}
 
 // ── Entry point ───────────────────────────────────────────────────────────────
 
+/// Serve until `shutdown`, then stop accepting. Open connections finish
+/// their current request and close; they are tracked in `connections`.
 pub async fn run(
     bind: &str,
-    registry: Registry,
-    pg: Arc<DbPool>,
-    main_slug: String,
+    entry: Entry,
+    state: Arc<RwLock<Arc<ProxyState>>>,
+    connections: TaskTracker,
+    shutdown: CancellationToken,
 ) -> std::io::Result<()> {
     let listener = TcpListener::bind(bind).await?;
-    info!("HTTP proxy on {bind}");
+    match entry {
+        Entry::Public => info!("HTTP proxy on {bind}"),
+        Entry::Cluster => info!("Cluster listener on {bind}"),
+    }
 
-    loop {
-        let (stream, _addr) = listener.accept().await?;
+    while let Some(accepted) = shutdown.run_until_cancelled(listener.accept()).await {
+        let (stream, peer) = accepted?;
         let io = TokioIo::new(stream);
-        let registry = registry.clone();
-        let pg = pg.clone();
-        let main_slug = main_slug.clone();
+        let state = state.clone();
+        let shutdown = shutdown.clone();
 
-        tokio::spawn(async move {
+        connections.spawn(async move {
+            // Read per request, so keep-alive connections pick up reloads too.
             let svc = service_fn(move |req| {
-                handle_request(req, registry.clone(), pg.clone(), main_slug.clone())
+                let state = state.read().unwrap().clone();
+                handle_request(req, state, peer, entry)
             });
-            if let Err(e) = hyper::server::conn::http1::Builder::new()
+            let conn = hyper::server::conn::http1::Builder::new()
                 .serve_connection(io, svc)
-                .await
-            {
+                .with_upgrades();
+            tokio::pin!(conn);
+            let result = match shutdown.run_until_cancelled(conn.as_mut()).await {
+                Some(result) => result,
+                None => {
+                    // No keep-alive past the response in progress.
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
                 error!("HTTP proxy connection error: {e}");
             }
         });
     }
+    info!("HTTP proxy closed");
+    Ok(())
 }
 
 // ── Request handler ───────────────────────────────────────────────────────────
 
 async fn handle_request(
-    req: Request<Incoming>,
-    registry: Registry,
-    pg: Arc<DbPool>,
-    main_slug: String,
-) -> Result<Response<Full<Bytes>>, hyper::Error> {
-    // Extract slug from Host header (slug.nodyx.org → slug).
+    mut req: Request<Incoming>,
+    state: Arc<ProxyState>,
+    peer: SocketAddr,
+    entry: Entry,
+) -> Result<Response<ProxyBody>, hyper::Error> {
+    if let Entry::Cluster = entry {
+        // The forwarding node already set the forwarding headers.
+        return Ok(from_node(req, &state).await);
+    }
+
+    // Whatever forwarding headers the browser sent are replaced, so the
+    // instance sees the visitor's address rather than ours.
+    let forwarding = Forwarding::resolve(req.headers(), peer, &state.trusted_proxies);
+    forwarding.apply(req.headers_mut());
+
+    // Resolve slug from Host header (slug.<base domain> or a custom domain).
     let host = req
         .headers()
         .get("host")
         .and_then(|v| v.to_str().ok())
         .unwrap_or("");
 
-    let slug = extract_slug(host);
+    let slug = state.router.resolve(host).await;
+
+    // Only slugs with a tunnel get their own metrics label, so random hosts
+    // can't create unbounded series.
+    let label = match &slug {
+        None => state.main_slug.clone(),
+        Some(s) if *s == state.main_slug || state.registry.contains(s) => s.clone(),
+        Some(_) => "-".to_owned(),
+    };
+
+    // Relayed requests are logged once their response is over; the entry
+    // travels with the request down to the tunnel.
+    let relayed = slug.as_deref().filter(|s| *s != state.main_slug);
+    let log = relayed.zip(state.access_log.as_ref()).map(|(relayed, log)| {
+        log.entry(state.access_log_sample, relayed, req.method().as_str(), req.uri().path(), forwarding.client())
+    });
+    if let Some(log) = &log {
+        req.extensions_mut().insert(log.clone());
+    }
+
+    // The instance owner's access policy, before anything of theirs is served.
+    if let Some(relayed) = relayed {
+        let client = forwarding.client();
+        if let Err(denied) = state.access.check_request(relayed, client, req.headers_mut()).await {
+            let resp = access_denied(relayed, &denied);
+            state.metrics.request(&label, resp.status());
+            if let Some(log) = log {
+                log.set_status(resp.status().as_u16());
+            }
+            return Ok(resp);
+        }
+    }
+
+    let resp = route_request(req, &state, slug).await;
+    state.metrics.request(&label, resp.status());
+    if let Some(log) = log {
+        log.set_status(resp.status().as_u16());
+    }
+    Ok(resp)
+}
 
+async fn route_request(
+    req: Request<Incoming>,
+    state: &ProxyState,
+    slug: Option<String>,
+) -> Response<ProxyBody> {
     // The main community slug serves this VPS directly — forward to nexus-core.
-    if slug.is_none() || slug.as_deref() == Some(&main_slug) {
-        return Ok(proxy_to_nodyx_core(req).await);
+    if slug.is_none() || slug.as_deref() == Some(&state.main_slug) {
+        return proxy_to_nodyx_core(req, state).await;
     }
     let slug = slug.unwrap();
 
     // If an active relay tunnel exists for this slug, proxy through it.
-    if let Some(handle) = registry.get(&slug) {
-        return Ok(proxy_through_tunnel(req, handle.tx, slug).await);
+    if state.registry.contains(&slug) {
+        return proxy_to_tunnel(req, state, slug).await;
+    }
+
+    // No relay. An instance hosted elsewhere gets a 302 to its own URL; one
+    // that lives behind this relay is just offline for now.
+    let Some(instance) = state.offline.instance(&slug).await else {
+        // Unknown slug.
+        return not_found();
+    };
+    let url_host = instance.url.parse::<Uri>().ok().and_then(|u| u.host().map(str::to_owned));
+    let relayed = match url_host {
+        Some(host) => state.router.resolve(&host).await.as_deref() == Some(slug.as_str()),
+        None => false,
+    };
+    if !relayed {
+        let target = format!("{}{}", instance.url.trim_end_matches('/'), req.uri());
+        return redirect(target);
+    }
+    // Its tunnel may be connected to another node of the cluster.
+    if let Some(cluster) = &state.cluster {
+        if let Some(node) = cluster.locate(&slug).await {
+            return proxy_to_node(req, cluster, &node, &slug).await;
+        }
     }
+    offline(state, &slug, &req).await
+}
 
-    // No relay — look up URL in DB for 302 redirect.
-    if let Ok(Some(row)) = pg
-        .query_opt(
-            "SELECT url FROM directory_instances WHERE slug = $1 AND status = 'active'",
-            &[&slug],
-        )
-        .await
-    {
-        let url: String = row.get(0);
-        let target = format!("{}{}", url.trim_end_matches('/'), req.uri());
-        return Ok(redirect(target));
+/// Through one of the slug's tunnels, within its quotas.
+async fn proxy_to_tunnel(req: Request<Incoming>, state: &ProxyState, slug: String) -> Response<ProxyBody> {
+    let meter = match state.quotas.check(&slug, &state.registry).await {
+        Ok(meter) => meter.logging(req.extensions().get::<LogEntry>().cloned()),
+        Err(exceeded) => return too_many_requests(&exceeded),
+    };
+    if is_upgrade_request(&req) {
+        if state.registry.pick(&slug, Some(capability::UPGRADE)).is_none() {
+            return not_implemented("This relay client does not support WebSockets — please upgrade nodyx-relay");
+        }
+        return proxy_upgrade_through_tunnel(req, state, slug, meter).await;
     }
+    proxy_through_tunnel(req, state, slug, meter).await
+}
 
-    // Unknown slug.
-    Ok(not_found())
+/// Stand-in for a slug without a working tunnel: a cached copy of the page
+/// if there is one, else the instance's maintenance page.
+async fn offline(state: &ProxyState, slug: &str, req: &Request<Incoming>) -> Response<ProxyBody> {
+    let hit = state
+        .cache
+        .as_ref()
+        .zip(CacheRequest::new(slug, req))
+        .and_then(|(cache, creq)| cache.lookup(&creq));
+    if let Some(hit) = hit {
+        return cached_response(&hit);
+    }
+    match state.offline.instance(slug).await {
+        Some(instance) => offline_page(state.offline.render(slug, &instance)),
+        None => service_unavailable(slug),
+    }
 }
 
 // ── Proxy through relay tunnel ────────────────────────────────────────────────
 
+/// A request handed to one of the slug's tunnels.
+struct Dispatched {
+    tx: mpsc::Sender<PendingRequest>,
+    /// The tunnel's flow control, for the request body or stream.
+    flow: Flow<PendingRequest>,
+    reply_rx: oneshot::Receiver<RelayResponse>,
+    /// Held until the response (or stream) is over.
+    in_flight: InFlight,
+    /// Deadline the relay client was given for the response head.
+    deadline: Duration,
+}
+
+/// Tells the relay client to abandon a request when dropped before being
+/// disarmed: the browser went away, or the reply timed out.
+struct CancelGuard {
+    id: String,
+    tx: Option<mpsc::Sender<PendingRequest>>,
+}
+
+impl CancelGuard {
+    fn new(id: &str, tx: &mpsc::Sender<PendingRequest>) -> Self {
+        Self { id: id.to_owned(), tx: Some(tx.clone()) }
+    }
+
+    /// The request ended normally; nothing to cancel.
+    fn disarm(&mut self) {
+        self.tx = None;
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        let Some(tx) = self.tx.take() else { return };
+        let msg = ServerMessage::Cancel { id: std::mem::take(&mut self.id) };
+        // Drop can't wait for room in the tunnel's queue.
+        tokio::spawn(async move {
+            let _ = tx.send(PendingRequest { msg, reply_tx: None }).await;
+        });
+    }
+}
+
+/// Send the opening message of a request to a tunnel of `slug`. A tunnel that
+/// has just disconnected is dropped from the registry and the next one tried,
+/// so a node going away never fails requests it had not yet received.
+///
+/// The deadline depends on the chosen tunnel's timeout settings, so it is
+/// settled here and written into the message.
+async fn dispatch(
+    state: &ProxyState,
+    slug: &str,
+    mut msg: ServerMessage,
+    capability: Option<&str>,
+) -> Option<Dispatched> {
+    loop {
+        let handle = state.registry.pick(slug, capability)?;
+        let deadline = match &mut msg {
+            ServerMessage::Request { path, timeout_ms, .. }
+            | ServerMessage::StreamOpen { path, timeout_ms, .. } => {
+                let deadline = state.timeouts.deadline(&handle.timeouts, path);
+                *timeout_ms = Some(deadline.as_millis() as u64);
+                deadline
+            }
+            _ => state.timeouts.max,
+        };
+        let in_flight = handle.start_request();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        match handle.tx.send(PendingRequest { msg, reply_tx: Some(reply_tx) }).await {
+            Ok(()) => return Some(Dispatched { tx: handle.tx, flow: handle.flow, reply_rx, in_flight, deadline }),
+            Err(mpsc::error::SendError(pending)) => {
+                state.registry.remove(slug, handle.id);
+                msg = pending.msg;
+            }
+        }
+    }
+}
+
 async fn proxy_through_tunnel(
     req: Request<Incoming>,
-    tx: tokio::sync::mpsc::Sender<PendingRequest>,
+    state: &ProxyState,
     slug: String,
-) -> Response<Full<Bytes>> {
+    meter: Meter,
+) -> Response<ProxyBody> {
     let id = Uuid::new_v4().to_string();
     let method = req.method().to_string();
     let path = req.uri().to_string();
 
     // Collect headers (skip hop-by-hop).
-    let mut headers = std::collections::HashMap::new();
-    for (k, v) in req.headers() {
-        let key = k.as_str().to_lowercase();
-        if !is_hop_by_hop(&key) {
-            if let Ok(val) = v.to_str() {
-                headers.insert(key, val.to_owned());
+    let headers = Headers::from_http(req.headers(), |k| !is_hop_by_hop(k));
+
+    let msg = ServerMessage::Request { id: id.clone(), method, path, headers, timeout_ms: None, body_b64: None };
+    let cache_req = state.cache.as_ref().and_then(|_| CacheRequest::new(&slug, &req));
+
+    // The dispatched request carries a one-shot channel for the response head.
+    let started = Instant::now();
+    let Some(Dispatched { tx, flow, reply_rx, in_flight, deadline }) = dispatch(state, &slug, msg, None).await else {
+        // The last tunnel went away in the meantime.
+        return offline(state, &slug, &req).await;
+    };
+    // From here on, giving up on the request (including this future being
+    // dropped when the browser disconnects) cancels it on the client.
+    let cancel = CancelGuard::new(&id, &tx);
+
+    // Stream the request body through the tunnel in CHUNK_SIZE pieces.
+    if stream_request_body(req.into_body(), &id, &flow.send(&id), &tx, &meter).await.is_err() {
+        return internal_error("Failed to read request body");
+    }
+
+    // Wait for the relay client to send the response head. The client gives
+    // up on its local server at `deadline` and answers 504 itself; the grace
+    // period leaves room for that answer to arrive. The body then streams for
+    // as long as it takes.
+    match tokio::time::timeout(deadline + REPLY_GRACE, reply_rx).await {
+        Ok(Ok(relay_resp)) => {
+            state.metrics.tunnel_latency(started.elapsed());
+            meter.tunnel_latency(started.elapsed());
+            let mut builder = Response::builder().status(relay_resp.status);
+            if let Some(headers) = builder.headers_mut() {
+                relay_resp.headers.append_to(headers, |k| !is_hop_by_hop(k));
             }
+            let fill = state
+                .cache
+                .as_ref()
+                .zip(cache_req)
+                .and_then(|(cache, creq)| cache.admit(creq, relay_resp.status, &relay_resp.headers));
+            builder
+                .body(relay_body(relay_resp.body, in_flight, meter, cancel, fill))
+                .unwrap_or_else(|_| internal_error("Response build error"))
+        }
+        _ => {
+            state.metrics.gateway_timeout(&slug);
+            gateway_timeout()
         }
     }
+}
+
+/// Response body streamed from the tunnel. The request stays in flight, and
+/// its bytes metered, until the body has been fully streamed; a browser that
+/// stops reading before then cancels it, and a body cut short on the way
+/// fails. With `fill`, the body is also recorded for the offline cache; an
+/// incomplete body is never stored.
+fn relay_body(
+    body: BodyReceiver<PendingRequest>,
+    in_flight: InFlight,
+    meter: Meter,
+    cancel: CancelGuard,
+    fill: Option<CacheFill>,
+) -> ProxyBody {
+    let chunks = futures_util::stream::unfold(
+        (body, in_flight, meter, cancel, fill),
+        |(mut rx, in_flight, meter, mut cancel, mut fill)| async move {
+            let data = match rx.recv().await {
+                Some(Ok(data)) => data,
+                Some(Err(truncated)) => {
+                    // Over on the relay client's side already.
+                    cancel.disarm();
+                    return Some((Err(truncated.into()), (rx, in_flight, meter, cancel, None)));
+                }
+                None => {
+                    cancel.disarm();
+                    return None;
+                }
+            };
+            meter.bytes_out(data.len());
+            fill = fill.and_then(|f| f.push(&data));
+            Some((Ok(Frame::data(data)), (rx, in_flight, meter, cancel, fill)))
+        },
+    );
+    StreamBody::new(chunks).boxed()
+}
 
-    // Collect body.
-    let body_bytes = match req.collect().await {
-        Ok(b) => b.to_bytes(),
-        Err(_) => return internal_error("Failed to read request body"),
+/// Forward the incoming body as RequestChunk messages, then RequestEnd, as
+/// fast as `window` lets it. A body that fails to read gets no RequestEnd:
+/// the caller cancels the request instead, so the local server never takes
+/// it for complete.
+async fn stream_request_body(
+    mut body: Incoming,
+    id: &str,
+    window: &Window,
+    tx: &mpsc::Sender<PendingRequest>,
+    meter: &Meter,
+) -> Result<(), ()> {
+    while let Some(frame) = body.frame().await {
+        let Ok(data) = frame.map(Frame::into_data) else {
+            return Err(());
+        };
+        // Trailers are not forwarded.
+        let Ok(data) = data else { continue };
+        meter.bytes_in(data.len());
+        for data in protocol::chunks(data) {
+            let room = window.reserve().await;
+            let msg = ServerMessage::RequestChunk { id: id.to_owned(), data };
+            if tx.send(PendingRequest { msg, reply_tx: None }).await.is_err() {
+                return Err(());
+            }
+            room.spend();
+        }
+    }
+    let end = ServerMessage::RequestEnd { id: id.to_owned() };
+    tx.send(PendingRequest { msg: end, reply_tx: None }).await.map_err(|_| ())
+}
+
+// ── Upgraded connections (WebSocket, …) through relay tunnel ─────────────────
+
+async fn proxy_upgrade_through_tunnel(
+    mut req: Request<Incoming>,
+    state: &ProxyState,
+    slug: String,
+    meter: Meter,
+) -> Response<ProxyBody> {
+    let id = Uuid::new_v4().to_string();
+    let method = req.method().to_string();
+    let path = req.uri().to_string();
+
+    // Same as a plain request, but `upgrade` / `connection` must reach the
+    // local server for it to agree to switch protocols.
+    let mut headers = Headers::from_http(req.headers(), |k| !is_hop_by_hop(k) || k == "upgrade");
+    headers.push("connection", "upgrade");
+
+    let msg = ServerMessage::StreamOpen { id: id.clone(), method, path, headers, timeout_ms: None };
+    let started = Instant::now();
+    let Some(Dispatched { tx, flow, reply_rx, in_flight, deadline }) =
+        dispatch(state, &slug, msg, Some(capability::UPGRADE)).await
+    else {
+        return offline(state, &slug, &req).await;
     };
-    let body_b64 = B64.encode(&body_bytes);
+    let mut cancel = CancelGuard::new(&id, &tx);
 
-    let msg = ServerMessage::Request { id: id.clone(), method, path, headers, body_b64 };
+    let relay_resp = match tokio::time::timeout(deadline + REPLY_GRACE, reply_rx).await {
+        Ok(Ok(r)) => r,
+        _ => {
+            state.metrics.gateway_timeout(&slug);
+            return gateway_timeout();
+        }
+    };
+    state.metrics.tunnel_latency(started.elapsed());
+    meter.tunnel_latency(started.elapsed());
 
-    // Create a one-shot channel to receive the relay client's response.
-    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    let mut builder = Response::builder().status(relay_resp.status);
+    if let Some(headers) = builder.headers_mut() {
+        relay_resp.headers.append_to(headers, |k| relay_resp.status == 101 || !is_hop_by_hop(k));
+    }
 
-    if tx.send(PendingRequest { msg, reply_tx }).await.is_err() {
-        return service_unavailable(&slug);
+    if relay_resp.status != 101 {
+        // Upgrade refused locally — pass the plain response through.
+        return builder
+            .body(relay_body(relay_resp.body, in_flight, meter, cancel, None))
+            .unwrap_or_else(|_| internal_error("Response build error"));
     }
 
-    // Wait for the relay client to respond (15 s timeout).
-    // Must exceed the relay client reqwest timeout (12s) to avoid racing.
-    match tokio::time::timeout(
-        tokio::time::Duration::from_secs(15),
-        reply_rx,
-    )
-    .await
-    {
-        Ok(Ok(relay_resp)) => {
-            let mut builder = Response::builder().status(relay_resp.status);
-            for (k, v) in &relay_resp.headers {
-                if !is_hop_by_hop(k) {
-                    builder = builder.header(k, v);
+    // The pump below closes the stream itself.
+    cancel.disarm();
+
+    // hyper completes the upgrade only after the 101 below has been written.
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let data_rx = relay_resp.body;
+    tokio::spawn(async move {
+        let _in_flight = in_flight;
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let window = flow.send(&id);
+                pump_stream(TokioIo::new(upgraded), id, data_rx, window, &tx, &meter).await
+            }
+            Err(e) => {
+                error!("Upgrade for '{slug}' failed: {e}");
+                let _ = tx.send(PendingRequest { msg: ServerMessage::StreamClose { id }, reply_tx: None }).await;
+            }
+        }
+    });
+
+    builder
+        .body(full(Bytes::new()))
+        .unwrap_or_else(|_| internal_error("Response build error"))
+}
+
+/// Copy bytes between `io` and tunnel stream `id`, whose data arrives on
+/// `data_rx`, until either side closes; then close the stream. `io` is only
+/// read while `window` has room.
+pub(super) async fn pump_stream<S>(
+    io: S,
+    id: String,
+    mut data_rx: BodyReceiver<PendingRequest>,
+    window: Window,
+    tx: &mpsc::Sender<PendingRequest>,
+    meter: &Meter,
+)
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut rd, mut wr) = tokio::io::split(io);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        tokio::select! {
+            (room, n) = async { (window.reserve().await, rd.read(&mut buf).await) } => {
+                let n = match n {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                meter.bytes_in(n);
+                let msg = ServerMessage::StreamData { id: id.clone(), data: Bytes::copy_from_slice(&buf[..n]) };
+                if tx.send(PendingRequest { msg, reply_tx: None }).await.is_err() {
+                    break;
+                }
+                room.spend();
+            }
+            data = data_rx.recv() => {
+                let Some(Ok(data)) = data else { break };
+                meter.bytes_out(data.len());
+                if wr.write_all(&data).await.is_err() {
+                    break;
                 }
             }
-            builder
-                .body(Full::new(Bytes::from(relay_resp.body)))
-                .unwrap_or_else(|_| internal_error("Response build error"))
         }
-        _ => gateway_timeout(),
     }
+    let _ = wr.shutdown().await;
+    let _ = tx.send(PendingRequest { msg: ServerMessage::StreamClose { id }, reply_tx: None }).await;
+}
+
+// ── Cross-node routing (cluster mode) ─────────────────────────────────────────
+
+/// Forward a request to the cluster node holding `slug`'s tunnel, as-is apart
+/// from hop-by-hop headers; upgrades are spliced through.
+async fn proxy_to_node(
+    mut req: Request<Incoming>,
+    cluster: &Cluster,
+    node: &str,
+    slug: &str,
+) -> Response<ProxyBody> {
+    let upgrade = is_upgrade_request(&req);
+    let client_upgrade = upgrade.then(|| hyper::upgrade::on(&mut req));
+
+    let (mut parts, body) = req.into_parts();
+    let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    parts.uri = match Uri::try_from(format!("http://{node}{path}")) {
+        Ok(uri) => uri,
+        Err(_) => return internal_error("Invalid cluster node URI"),
+    };
+
+    let headers = &mut parts.headers;
+    strip_hop_by_hop(headers, upgrade);
+    if upgrade {
+        headers.insert(hyper::header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+    match (HeaderValue::try_from(slug), HeaderValue::try_from(cluster.secret())) {
+        (Ok(slug), Ok(secret)) => {
+            headers.insert(SLUG_HEADER, slug);
+            headers.insert(SECRET_HEADER, secret);
+        }
+        _ => return internal_error("Invalid cluster headers"),
+    }
+
+    let mut resp = match cluster.client.request(Request::from_parts(parts, body)).await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Cluster node {node} unreachable for '{slug}': {e}");
+            cluster.forget(slug);
+            return bad_gateway("Relay node unreachable");
+        }
+    };
+
+    if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+        if let Some(client_upgrade) = client_upgrade {
+            splice_upgrade(client_upgrade, &mut resp, "Cross-node");
+        }
+        return resp.map(|_| full(Bytes::new()));
+    }
+
+    strip_hop_by_hop(resp.headers_mut(), false);
+    resp.map(|body| body.map_err(Into::into).boxed())
+}
+
+/// A request forwarded by another node: served through a local tunnel only.
+async fn from_node(mut req: Request<Incoming>, state: &ProxyState) -> Response<ProxyBody> {
+    let Some(cluster) = &state.cluster else { return not_found() };
+    if !cluster.authenticate(req.headers()) {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(full("Forbidden"))
+            .unwrap();
+    }
+    let slug = req.headers().get(SLUG_HEADER).and_then(|v| v.to_str().ok()).map(str::to_owned);
+    // Not for the relay client's eyes.
+    req.headers_mut().remove(SLUG_HEADER);
+    req.headers_mut().remove(SECRET_HEADER);
+
+    let Some(slug) = slug else { return not_found() };
+    if !state.registry.contains(&slug) {
+        return service_unavailable(&slug);
+    }
+    let resp = proxy_to_tunnel(req, state, slug.clone()).await;
+    state.metrics.request(&slug, resp.status());
+    resp
 }
 
 // ── Forward to local nexus-core (for main slug / fallback) ────────────────────
 
-async fn proxy_to_nodyx_core(_req: Request<Incoming>) -> Response<Full<Bytes>> {
-    // For now redirect to the main domain — nexus-core handles it.
-    redirect("https://nodyx.org".to_string())
+async fn proxy_to_nodyx_core(req: Request<Incoming>, state: &ProxyState) -> Response<ProxyBody> {
+    match &state.upstream {
+        Some(upstream) => proxy_to_upstream(req, upstream).await,
+        // No upstream configured — redirect to the main domain.
+        None => redirect(format!("https://{}", state.router.primary_domain())),
+    }
 }
 
-// ── Helper responses ──────────────────────────────────────────────────────────
+async fn proxy_to_upstream(mut req: Request<Incoming>, upstream: &Upstream) -> Response<ProxyBody> {
+    let upgrade = is_upgrade_request(&req);
+    let client_upgrade = upgrade.then(|| hyper::upgrade::on(&mut req));
+
+    let (mut parts, body) = req.into_parts();
+    let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    parts.uri = match Uri::try_from(format!("http://{}{path}", upstream.authority)) {
+        Ok(uri) => uri,
+        Err(_) => return internal_error("Invalid upstream URI"),
+    };
+
+    let headers = &mut parts.headers;
+    let original_host = headers
+        .get("x-forwarded-host")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let proto = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("http")
+        .to_owned();
+
+    strip_hop_by_hop(headers, upgrade);
+    if upgrade {
+        headers.insert(hyper::header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+    // The forwarding headers were set on arrival; only Host changes.
+    if let Ok(v) = HeaderValue::try_from(upstream.authority.as_str()) {
+        headers.insert(HOST, v);
+    }
+
+    let mut resp = match upstream.client.request(Request::from_parts(parts, body)).await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Upstream {} unreachable: {e}", upstream.authority);
+            return bad_gateway("Upstream unreachable");
+        }
+    };
+
+    // Absolute redirects to the upstream itself must point back at the public host.
+    let local_prefix = format!("http://{}", upstream.authority);
+    let rewritten = resp
+        .headers()
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|loc| loc.strip_prefix(&local_prefix))
+        .map(|rest| format!("{proto}://{original_host}{rest}"));
+    if let Some(loc) = rewritten.and_then(|l| HeaderValue::try_from(l).ok()) {
+        resp.headers_mut().insert(LOCATION, loc);
+    }
+
+    if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+        if let Some(client_upgrade) = client_upgrade {
+            splice_upgrade(client_upgrade, &mut resp, "Upstream");
+        }
+        return resp.map(|_| full(Bytes::new()));
+    }
+
+    strip_hop_by_hop(resp.headers_mut(), false);
+    resp.map(|body| body.map_err(Into::into).boxed())
+}
 
-fn extract_slug(host: &str) -> Option<String> {
-    // Match "slug.nodyx.org" (with optional port).
-    let host = host.split(':').next().unwrap_or(host);
-    let suffix = ".nodyx.org";
-    if host.ends_with(suffix) {
-        let slug = &host[..host.len() - suffix.len()];
-        if !slug.is_empty() && !slug.contains('.') {
-            return Some(slug.to_lowercase());
+/// Once both sides of an accepted protocol switch are upgraded, copy bytes
+/// between them until either closes.
+fn splice_upgrade(client_upgrade: OnUpgrade, resp: &mut Response<Incoming>, what: &'static str) {
+    let server_upgrade = hyper::upgrade::on(resp);
+    tokio::spawn(async move {
+        match tokio::try_join!(client_upgrade, server_upgrade) {
+            Ok((client, server)) => {
+                let mut client = TokioIo::new(client);
+                let mut server = TokioIo::new(server);
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+            }
+            Err(e) => warn!("{what} upgrade failed: {e}"),
         }
+    });
+}
+
+/// Remove hop-by-hop headers, keeping `upgrade` for protocol switches.
+fn strip_hop_by_hop(headers: &mut hyper::HeaderMap, keep_upgrade: bool) {
+    let names: Vec<_> = headers
+        .keys()
+        .filter(|k| is_hop_by_hop(k.as_str()) && !(keep_upgrade && *k == hyper::header::UPGRADE))
+        .cloned()
+        .collect();
+    for name in names {
+        headers.remove(name);
     }
-    None
+}
+
+// ── Helper responses ──────────────────────────────────────────────────────────
+
+fn is_upgrade_request(req: &Request<Incoming>) -> bool {
+    req.headers().contains_key(hyper::header::UPGRADE)
+        && req
+            .headers()
+            .get_all(hyper::header::CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade")))
 }
 
 fn is_hop_by_hop(name: &str) -> bool {
@@ -188,38 +798,106 @@ fn is_hop_by_hop(name: &str) -> bool {
     )
 }
 
-fn redirect(location: String) -> Response<Full<Bytes>> {
+fn full(body: impl Into<Bytes>) -> ProxyBody {
+    Full::new(body.into()).map_err(|never: Infallible| match never {}).boxed()
+}
+
+fn redirect(location: String) -> Response<ProxyBody> {
     Response::builder()
         .status(StatusCode::FOUND)
         .header("location", location)
-        .body(Full::new(Bytes::new()))
+        .body(full(Bytes::new()))
         .unwrap()
 }
 
-fn not_found() -> Response<Full<Bytes>> {
+fn not_found() -> Response<ProxyBody> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
-        .body(Full::new(Bytes::from("Not Found")))
+        .body(full("Not Found"))
         .unwrap()
 }
 
-fn internal_error(msg: &str) -> Response<Full<Bytes>> {
+fn internal_error(msg: &str) -> Response<ProxyBody> {
     Response::builder()
         .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Full::new(Bytes::from(msg.to_owned())))
+        .body(full(msg.to_owned()))
         .unwrap()
 }
 
-fn service_unavailable(slug: &str) -> Response<Full<Bytes>> {
+fn not_implemented(msg: &str) -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::NOT_IMPLEMENTED)
+        .body(full(msg.to_owned()))
+        .unwrap()
+}
+
+fn bad_gateway(msg: &str) -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(full(msg.to_owned()))
+        .unwrap()
+}
+
+fn too_many_requests(exceeded: &QuotaExceeded) -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("retry-after", exceeded.retry_after())
+        .body(full(exceeded.message()))
+        .unwrap()
+}
+
+fn access_denied(slug: &str, denied: &Denied) -> Response<ProxyBody> {
+    let status = match denied {
+        Denied::Address => StatusCode::FORBIDDEN,
+        Denied::Credentials { .. } => StatusCode::UNAUTHORIZED,
+        Denied::Throttled(_) => StatusCode::TOO_MANY_REQUESTS,
+        Denied::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let mut resp = Response::builder().status(status);
+    match denied {
+        Denied::Credentials { basic: true } => {
+            resp = resp.header("www-authenticate", format!("Basic realm=\"{slug}\", charset=\"UTF-8\""));
+        }
+        Denied::Throttled(wait) => resp = resp.header("retry-after", *wait),
+        Denied::Unavailable => resp = resp.header("retry-after", 5),
+        _ => {}
+    }
+    resp.body(full(denied.message())).unwrap()
+}
+
+fn service_unavailable(slug: &str) -> Response<ProxyBody> {
     Response::builder()
         .status(StatusCode::SERVICE_UNAVAILABLE)
-        .body(Full::new(Bytes::from(format!("Relay for '{slug}' is unavailable"))))
+        .body(full(format!("Relay for '{slug}' is unavailable")))
         .unwrap()
 }
 
-fn gateway_timeout() -> Response<Full<Bytes>> {
+/// Maintenance page for an instance whose relay client is not connected.
+fn offline_page(html: String) -> Response<ProxyBody> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("content-type", "text/html; charset=utf-8")
+        .header("cache-control", "no-store")
+        .header("retry-after", "30")
+        .body(full(html))
+        .unwrap()
+}
+
+/// A response from the offline cache, with its age.
+fn cached_response(hit: &CachedResponse) -> Response<ProxyBody> {
+    let mut builder = Response::builder().status(hit.status);
+    if let Some(headers) = builder.headers_mut() {
+        hit.headers.append_to(headers, |k| !is_hop_by_hop(k) && k != "age");
+    }
+    builder
+        .header("age", hit.stored_at.elapsed().as_secs())
+        .body(full(hit.body.clone()))
+        .unwrap_or_else(|_| internal_error("Response build error"))
+}
+
+fn gateway_timeout() -> Response<ProxyBody> {
     Response::builder()
         .status(StatusCode::GATEWAY_TIMEOUT)
-        .body(Full::new(Bytes::from("Relay client did not respond in time")))
+        .body(full("Relay client did not respond in time"))
         .unwrap()
 }
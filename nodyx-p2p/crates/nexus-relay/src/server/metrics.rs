@@ -0,0 +1,223 @@
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use bytes::Bytes;
+use dashmap::DashMap;
+use http_body_util::Full;
+use hyper::service::service_fn;
+use hyper::{Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use super::registry::Registry;
+use super::bans::AuthBans;
+
+/// Upper bounds (seconds) of the tunnel latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0];
+
+// ── Counters ──────────────────────────────────────────────────────────────────
+
+/// Relay counters, rendered in the Prometheus text format. Gauges (tunnels,
+/// bans) are read from the registry and ban list at scrape time instead.
+#[derive(Default)]
+pub struct Metrics {
+    /// (slug, status class) → requests.
+    requests: DashMap<(String, &'static str), u64>,
+    /// slug → relay reply timeouts.
+    gateway_timeouts: DashMap<String, u64>,
+    tunnel_latency: Histogram,
+    auth_failures: AtomicU64,
+    bans: AtomicU64,
+    banned_connections: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a proxied request by slug and status class.
+    pub fn request(&self, slug: &str, status: StatusCode) {
+        let class = match status.as_u16() {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            _ => "5xx",
+        };
+        *self.requests.entry((slug.to_owned(), class)).or_default() += 1;
+    }
+
+    /// Time from dispatching a request to receiving its response head.
+    pub fn tunnel_latency(&self, elapsed: Duration) {
+        self.tunnel_latency.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn gateway_timeout(&self, slug: &str) {
+        *self.gateway_timeouts.entry(slug.to_owned()).or_default() += 1;
+    }
+
+    pub fn auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ban(&self) {
+        self.bans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn banned_connection(&self) {
+        self.banned_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, registry: &Registry, bans: &AuthBans) -> String {
+        let mut out = String::new();
+
+        let tunnels = registry.snapshot();
+        let mut slugs: Vec<&str> = tunnels.iter().map(|(s, _)| s.as_str()).collect();
+        slugs.dedup();
+        gauge(&mut out, "nodyx_relay_tunnels", "Connected relay tunnels.", tunnels.len() as u64);
+        gauge(&mut out, "nodyx_relay_slugs", "Slugs with at least one tunnel.", slugs.len() as u64);
+        gauge(
+            &mut out,
+            "nodyx_relay_in_flight_requests",
+            "Requests and streams in flight through tunnels.",
+            tunnels.iter().map(|(_, t)| t.in_flight() as u64).sum(),
+        );
+
+        header(&mut out, "nodyx_relay_requests_total", "HTTP requests by slug and status class.", "counter");
+        let mut requests: Vec<_> = self.requests.iter().map(|e| (e.key().clone(), *e.value())).collect();
+        requests.sort();
+        for ((slug, class), n) in requests {
+            let _ = writeln!(out, "nodyx_relay_requests_total{{slug=\"{}\",status=\"{class}\"}} {n}", escape(&slug));
+        }
+
+        header(&mut out, "nodyx_relay_gateway_timeouts_total", "Requests whose tunnel reply timed out.", "counter");
+        let mut timeouts: Vec<_> = self.gateway_timeouts.iter().map(|e| (e.key().clone(), *e.value())).collect();
+        timeouts.sort();
+        for (slug, n) in timeouts {
+            let _ = writeln!(out, "nodyx_relay_gateway_timeouts_total{{slug=\"{}\"}} {n}", escape(&slug));
+        }
+
+        self.tunnel_latency.render(
+            &mut out,
+            "nodyx_relay_tunnel_latency_seconds",
+            "Time to the response head through a tunnel.",
+        );
+
+        counter(&mut out, "nodyx_relay_auth_failures_total", "Rejected relay registrations.", &self.auth_failures);
+        counter(&mut out, "nodyx_relay_bans_total", "IPs banned after repeated auth failures.", &self.bans);
+        counter(
+            &mut out,
+            "nodyx_relay_banned_connections_total",
+            "Connections dropped because the IP is banned.",
+            &self.banned_connections,
+        );
+        gauge(&mut out, "nodyx_relay_banned_ips", "IPs currently banned.", bans.banned().len() as u64);
+
+        out
+    }
+}
+
+/// Cumulative histogram with fixed buckets.
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// Sum of observations, in microseconds.
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, secs: f64) {
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add((secs * 1e6) as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {}", bucket.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {}", self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+// ── Entry point ───────────────────────────────────────────────────────────────
+
+/// Serve `GET /metrics` for Prometheus on its own bind address.
+pub async fn run(
+    bind: &str,
+    metrics: std::sync::Arc<Metrics>,
+    registry: Registry,
+    bans: std::sync::Arc<AuthBans>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    info!("Metrics on {bind}");
+
+    loop {
+        let (stream, _addr) = listener.accept().await?;
+        let io = TokioIo::new(stream);
+        let metrics = metrics.clone();
+        let registry = registry.clone();
+        let bans = bans.clone();
+
+        tokio::spawn(async move {
+            let svc = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                let resp = if req.uri().path() == "/metrics" {
+                    Response::builder()
+                        .header("content-type", "text/plain; version=0.0.4")
+                        .body(Full::new(Bytes::from(metrics.render(&registry, &bans))))
+                        .unwrap()
+                } else {
+                    Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Full::new(Bytes::from_static(b"Not Found")))
+                        .unwrap()
+                };
+                async move { Ok::<_, Infallible>(resp) }
+            });
+            if let Err(e) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(io, svc)
+                .await
+            {
+                error!("Metrics connection error: {e}");
+            }
+        });
+    }
+}
+
+// ── Helpers ───────────────────────────────────────────────────────────────────
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
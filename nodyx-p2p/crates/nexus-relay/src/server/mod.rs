@@ -1,33 +1,380 @@
+pub mod access;
+pub mod access_log;
+pub mod admin;
+pub mod bans;
+pub mod cache;
+pub mod cluster;
 pub mod db;
+pub mod domains;
+pub mod forwarding;
 pub mod http_proxy;
+pub mod metrics;
+pub mod offline;
+pub mod quotas;
 pub mod registry;
+pub mod services;
 pub mod tcp_listener;
 
-use std::sync::Arc;
-use tracing::info;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use anyhow::Context;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
 
+use access::AccessControl;
+use access_log::{AccessLog, AccessLogConfig, LogTarget};
+use bans::{AuthBans, BanPolicy};
+use cache::ResponseCache;
+use cluster::{Cluster, ClusterConfig};
 use db::DbPool;
-use registry::Registry;
+use domains::DomainRouter;
+use forwarding::TrustedProxy;
+use http_proxy::{Entry, ProxyState, Upstream};
+use metrics::Metrics;
+use offline::OfflinePages;
+use quotas::Quotas;
+use registry::{Balance, PendingRequest, Registry};
+use services::{ServicesConfig, TcpServices};
 
-pub async fn run(tcp_port: u16, http_port: u16, database_url: &str, main_slug: &str) -> anyhow::Result<()> {
+use crate::protocol::ServerMessage;
+use crate::timeouts::ServerTimeouts;
+
+/// Relay server settings, from the `server` subcommand and its config file.
+pub struct ServerConfig {
+    pub tcp_port: u16,
+    pub http_port: u16,
+    pub database_url: String,
+    /// The community hosted on this VPS itself (never relayed).
+    pub main_slug: String,
+    /// Base domains: `slug.<domain>` is routed to `slug`. The first is canonical.
+    pub domains: Vec<String>,
+    /// Local HTTP server ("host:port") for the main slug and unknown hosts.
+    /// `None` redirects them to the primary domain instead.
+    pub upstream: Option<String>,
+    /// Proxies in front of the HTTP port whose forwarding headers are believed.
+    pub trusted_proxies: Vec<TrustedProxy>,
+    /// How requests are spread over several tunnels of the same slug.
+    pub balance: Balance,
+    /// Admin API bind address and bearer token; disabled when `None`.
+    pub admin: Option<(String, String)>,
+    /// Bind address for the Prometheus `/metrics` endpoint; disabled when `None`.
+    pub metrics_bind: Option<String>,
+    /// Deadlines for tunnelled requests, combined with each client's own.
+    pub timeouts: ServerTimeouts,
+    /// HTML template for the page shown while an instance is offline.
+    pub offline_page: Option<PathBuf>,
+    /// Bytes of cacheable GET responses kept for offline instances (0 = off).
+    pub offline_cache: usize,
+    /// How long a shutdown waits for in-flight requests.
+    pub shutdown_timeout: Duration,
+    /// Brute-force protection of the relay TCP port.
+    pub bans: BanPolicy,
+    /// Cross-node routing with the other relay servers; disabled when `None`.
+    pub cluster: Option<ClusterConfig>,
+    /// Raw TCP services exposed by relay clients.
+    pub services: ServicesConfig,
+    /// JSON lines access log of relayed requests; disabled when `None`.
+    pub access_log: Option<AccessLogConfig>,
+    /// Share of requests written to the access log, from 0 to 1.
+    pub access_log_sample: f64,
+    pub tls: Option<TlsAcceptor>,
+}
+
+/// Re-reads the configuration, on SIGHUP.
+pub type Reload = Box<dyn Fn() -> anyhow::Result<ServerConfig> + Send + Sync>;
+
+pub async fn run(config: ServerConfig, reload: Option<Reload>) -> anyhow::Result<()> {
     info!("Starting nodyx-relay server");
-    info!("  TCP relay port  : {tcp_port}");
-    info!("  HTTP proxy port : {http_port}");
-    info!("  Main slug       : {main_slug}");
+    info!("  TCP relay port  : {} ({})", config.tcp_port, if config.tls.is_some() { "TLS" } else { "plain TCP" });
+    info!("  HTTP proxy port : {}", config.http_port);
+    info!("  Main slug       : {}", config.main_slug);
+    info!("  Domains         : {}", config.domains.join(", "));
+    info!("  Upstream        : {}", config.upstream.as_deref().unwrap_or("none (redirect)"));
+    info!("  Balancing       : {:?}", config.balance);
+    info!("  Admin API       : {}", config.admin.as_ref().map_or("disabled", |(bind, _)| bind.as_str()));
+    info!("  Metrics         : {}", config.metrics_bind.as_deref().unwrap_or("disabled"));
+    info!(
+        "  Request timeout : {}s (max {}s, {} override(s))",
+        config.timeouts.policy.default_ms.unwrap_or_default() / 1000,
+        config.timeouts.max.as_secs(),
+        config.timeouts.policy.rules.len(),
+    );
+    info!("  Offline page    : {}", config.offline_page.as_ref().map_or("built-in".into(), |p| p.display().to_string()));
+    info!("  Offline cache   : {}", if config.offline_cache > 0 { format!("{} KB", config.offline_cache / 1024) } else { "disabled".into() });
+    info!("  Shutdown drain  : {}s", config.shutdown_timeout.as_secs());
+    info!(
+        "  Cluster         : {}",
+        config.cluster.as_ref().map_or("disabled".into(), |c| format!("{} (listening on {})", c.advertise, c.bind)),
+    );
+    info!(
+        "  TCP services    : ports {}, TLS passthrough {}",
+        config.services.ports.map_or("disabled".into(), |r| format!("{}-{}", r.first, r.last)),
+        config.services.sni_bind.as_deref().unwrap_or("disabled"),
+    );
+    info!(
+        "  Access log      : {}",
+        match &config.access_log {
+            None => "disabled".into(),
+            Some(log) => format!(
+                "{} ({}% sampled)",
+                match &log.target {
+                    LogTarget::Stdout => "stdout".into(),
+                    LogTarget::File(path) => path.display().to_string(),
+                },
+                config.access_log_sample * 100.0,
+            ),
+        },
+    );
+    info!(
+        "  Auth bans       : {} failure(s) in {}s → {}s, up to {}s",
+        config.bans.max_failures,
+        config.bans.window.as_secs(),
+        config.bans.ban.as_secs(),
+        config.bans.max_ban.as_secs(),
+    );
 
     // Auto-reconnecting PostgreSQL pool.
-    let pg = Arc::new(DbPool::connect(database_url).await?);
+    let pg = Arc::new(DbPool::connect(&config.database_url).await?);
+
+    let shared = Shared {
+        registry: Registry::new(config.balance),
+        metrics: Arc::new(Metrics::default()),
+        quotas: Quotas::new(pg.clone()),
+        access: AccessControl::new(pg.clone()),
+        bans: AuthBans::new(pg.clone(), config.bans),
+        cluster: config.cluster.as_ref().map(|c| Cluster::new(c, pg.clone())),
+        cache: (config.offline_cache > 0).then(|| Arc::new(ResponseCache::new(config.offline_cache))),
+        access_log: config.access_log.clone().map(AccessLog::open).transpose()?,
+        pg,
+    };
+    let state = Arc::new(RwLock::new(Arc::new(shared.proxy_state(&config)?)));
+    let fixed = Fixed::of(&config);
+    let Shared { registry, metrics, pg, bans, cluster, .. } = &shared;
 
-    let registry = Registry::new();
+    let tcp_bind  = format!("0.0.0.0:{}", config.tcp_port);
+    let http_bind = format!("127.0.0.1:{}", config.http_port);
 
-    let tcp_bind  = format!("0.0.0.0:{tcp_port}");
-    let http_bind = format!("127.0.0.1:{http_port}");
-    let main_slug = main_slug.to_owned();
+    let shutdown = CancellationToken::new();
+    tokio::spawn(cancel_on_terminate(shutdown.clone()));
+    let connections = TaskTracker::new();
+    let services = TcpServices::new(config.services.clone(), pg.clone(), state.clone(), shutdown.clone());
 
+    // Every branch returns once `shutdown` is cancelled.
     tokio::try_join!(
-        tcp_listener::run(&tcp_bind, registry.clone(), pg.clone()),
-        http_proxy::run(&http_bind, registry.clone(), pg.clone(), main_slug),
+        tcp_listener::run(&tcp_bind, &shared, services.clone(), config.tls, shutdown.clone()),
+        services.clone().run_sni(),
+        services.clone().reclaim(),
+        http_proxy::run(&http_bind, Entry::Public, state.clone(), connections.clone(), shutdown.clone()),
+        async {
+            match (&config.cluster, cluster) {
+                (Some(config), Some(cluster)) => {
+                    let publish = shutdown.run_until_cancelled(cluster.publish(registry));
+                    let peers = http_proxy::run(&config.bind, Entry::Cluster, state.clone(), connections.clone(), shutdown.clone());
+                    let (published, served) = tokio::join!(publish, peers);
+                    cluster.withdraw().await;
+                    published.unwrap_or(Ok(())).and(served)
+                }
+                _ => Ok(()),
+            }
+        },
+        async {
+            match config.admin {
+                Some((bind, token)) => {
+                    let api = admin::run(&bind, token, registry.clone(), bans.clone());
+                    shutdown.run_until_cancelled(api).await.unwrap_or(Ok(()))
+                }
+                None => Ok(()),
+            }
+        },
+        async {
+            match config.metrics_bind {
+                Some(bind) => {
+                    let endpoint = metrics::run(&bind, metrics.clone(), registry.clone(), bans.clone());
+                    shutdown.run_until_cancelled(endpoint).await.unwrap_or(Ok(()))
+                }
+                None => Ok(()),
+            }
+        },
+        async {
+            let reloads = reload_on_hangup(reload, &fixed, &shared, &state);
+            shutdown.run_until_cancelled(reloads).await.unwrap_or(Ok(()))
+        },
     )?;
 
+    drain(registry, connections, config.shutdown_timeout).await;
+    Ok(())
+}
+
+// ── Shutdown ──────────────────────────────────────────────────────────────────
+
+/// Cancel `shutdown` on SIGTERM or Ctrl-C.
+async fn cancel_on_terminate(shutdown: CancellationToken) {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("Cannot listen for SIGTERM: {e}");
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => info!("SIGTERM received — shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("Interrupt received — shutting down"),
+    }
+    shutdown.cancel();
+}
+
+/// With the listeners closed: let open proxy connections and tunnelled
+/// requests finish (up to `timeout`), then tell every relay client goodbye so
+/// it reconnects elsewhere.
+async fn drain(registry: &Registry, connections: TaskTracker, timeout: Duration) {
+    let in_flight = || registry.snapshot().iter().map(|(_, t)| t.in_flight()).sum::<usize>();
+    info!(
+        "Draining {} connection(s), {} tunnelled request(s) in flight",
+        connections.len(),
+        in_flight(),
+    );
+
+    connections.close();
+    let finished = tokio::time::timeout(timeout, async {
+        connections.wait().await;
+        // Upgraded streams outlive their HTTP connection.
+        while in_flight() > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+    if finished.is_err() {
+        warn!(
+            "Shutdown timeout reached with {} connection(s), {} tunnelled request(s) still open",
+            connections.len(),
+            in_flight(),
+        );
+    }
+
+    let tunnels = registry.snapshot();
+    info!("Saying goodbye to {} relay client(s)", tunnels.len());
+    for (_, tunnel) in &tunnels {
+        let _ = tunnel.tx.send(PendingRequest { msg: ServerMessage::Goodbye, reply_tx: None }).await;
+    }
+    let _ = tokio::time::timeout(Duration::from_secs(5), async {
+        while !registry.snapshot().is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    info!("Relay server stopped");
+}
+
+// ── Reload ────────────────────────────────────────────────────────────────────
+
+/// Parts of the proxy state that outlive configuration reloads.
+struct Shared {
+    registry: Registry,
+    pg: Arc<DbPool>,
+    metrics: Arc<Metrics>,
+    quotas: Arc<Quotas>,
+    access: Arc<AccessControl>,
+    bans: Arc<AuthBans>,
+    cache: Option<Arc<ResponseCache>>,
+    cluster: Option<Arc<Cluster>>,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl Shared {
+    /// Proxy state for the reloadable settings of `config`.
+    fn proxy_state(&self, config: &ServerConfig) -> anyhow::Result<ProxyState> {
+        let offline_template = match &config.offline_page {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("reading offline page {}", path.display()))?,
+            ),
+            None => None,
+        };
+        Ok(ProxyState {
+            registry: self.registry.clone(),
+            router: Arc::new(DomainRouter::new(config.domains.clone(), self.pg.clone())),
+            main_slug: config.main_slug.clone(),
+            upstream: config.upstream.clone().map(Upstream::new),
+            metrics: self.metrics.clone(),
+            quotas: self.quotas.clone(),
+            access: self.access.clone(),
+            timeouts: config.timeouts.clone(),
+            offline: OfflinePages::new(offline_template, self.pg.clone()),
+            cache: self.cache.clone(),
+            cluster: self.cluster.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            access_log: self.access_log.clone(),
+            access_log_sample: config.access_log_sample,
+        })
+    }
+}
+
+/// Settings bound to listeners or startup resources, which only a restart applies.
+#[derive(PartialEq)]
+struct Fixed {
+    tcp_port: u16,
+    http_port: u16,
+    database_url: String,
+    admin: Option<(String, String)>,
+    metrics_bind: Option<String>,
+    offline_cache: usize,
+    shutdown_timeout: Duration,
+    cluster: Option<ClusterConfig>,
+    services: ServicesConfig,
+    access_log: Option<AccessLogConfig>,
+}
+
+impl Fixed {
+    fn of(config: &ServerConfig) -> Self {
+        Self {
+            tcp_port: config.tcp_port,
+            http_port: config.http_port,
+            database_url: config.database_url.clone(),
+            admin: config.admin.clone(),
+            metrics_bind: config.metrics_bind.clone(),
+            offline_cache: config.offline_cache,
+            shutdown_timeout: config.shutdown_timeout,
+            cluster: config.cluster.clone(),
+            services: config.services.clone(),
+            access_log: config.access_log.clone(),
+        }
+    }
+}
+
+/// Apply the configuration again on every SIGHUP. A configuration that fails
+/// to load leaves the current one in place.
+async fn reload_on_hangup(
+    reload: Option<Reload>,
+    fixed: &Fixed,
+    shared: &Shared,
+    state: &RwLock<Arc<ProxyState>>,
+) -> std::io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        let Some(reload) = &reload else {
+            warn!("SIGHUP received, but there is no --config file to reload");
+            continue;
+        };
+        let applied = reload().and_then(|config| {
+            let next = shared.proxy_state(&config)?;
+            shared.registry.set_balance(config.balance);
+            shared.bans.set_policy(config.bans);
+            *state.write().unwrap() = Arc::new(next);
+            Ok(config)
+        });
+        match applied {
+            Ok(config) => {
+                info!("Configuration reloaded");
+                if Fixed::of(&config) != *fixed {
+                    warn!("Port, bind address, database, cache size, shutdown timeout, cluster, TCP service or access log destination changes take effect after a restart");
+                }
+            }
+            Err(e) => error!("Configuration reload failed, keeping the current settings: {e:#}"),
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use tracing::warn;
+
+use super::db::DbPool;
+
+/// How long a directory lookup (hit or miss) is trusted.
+const INSTANCE_TTL: Duration = Duration::from_secs(60);
+
+/// Built-in maintenance page. `--offline-page` replaces it; both may use the
+/// `{{name}}`, `{{slug}}` and `{{logo}}` placeholders.
+const DEFAULT_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta http-equiv="refresh" content="30">
+<title>{{name}} — temporarily offline</title>
+<style>
+  body { margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center;
+         font-family: system-ui, sans-serif; background: #0f1117; color: #e6e6e6; text-align: center; }
+  main { max-width: 32rem; padding: 2rem; }
+  img  { max-width: 96px; max-height: 96px; border-radius: 16px; margin-bottom: 1rem; }
+  p    { color: #9aa0aa; line-height: 1.5; }
+</style>
+</head>
+<body>
+<main>
+  {{logo}}
+  <h1>{{name}}</h1>
+  <p>This community is temporarily offline. It will be back as soon as its server reconnects —
+     this page refreshes by itself.</p>
+</main>
+</body>
+</html>
+"#;
+
+/// Directory entry of an instance, as needed when it has no tunnel.
+pub struct Instance {
+    pub name: String,
+    /// Where the instance says it lives.
+    pub url: String,
+    logo_url: Option<String>,
+}
+
+/// Maintenance pages for instances whose relay client is not connected,
+/// branded with the name and logo from `directory_instances`.
+pub struct OfflinePages {
+    template: String,
+    pg: Arc<DbPool>,
+    /// slug → (active instance, looked up at).
+    cache: DashMap<String, (Option<Arc<Instance>>, Instant)>,
+}
+
+impl OfflinePages {
+    pub fn new(template: Option<String>, pg: Arc<DbPool>) -> Self {
+        Self {
+            template: template.unwrap_or_else(|| DEFAULT_PAGE.to_owned()),
+            pg,
+            cache: DashMap::new(),
+        }
+    }
+
+    /// The active directory entry for `slug`, if any.
+    pub async fn instance(&self, slug: &str) -> Option<Arc<Instance>> {
+        if let Some(entry) = self.cache.get(slug) {
+            let (instance, at) = entry.value();
+            if at.elapsed() < INSTANCE_TTL {
+                return instance.clone();
+            }
+        }
+
+        let instance = match self
+            .pg
+            .query_opt(
+                "SELECT name, url, logo_url FROM directory_instances WHERE slug = $1 AND status = 'active'",
+                &[&slug],
+            )
+            .await
+        {
+            Ok(row) => row.map(|r| {
+                Arc::new(Instance { name: r.get(0), url: r.get(1), logo_url: r.get(2) })
+            }),
+            Err(e) => {
+                // Don't cache failures — the next request retries.
+                warn!("Instance lookup for '{slug}' failed: {e}");
+                return None;
+            }
+        };
+
+        // Slugs come from arbitrary Host headers; keep the cache bounded.
+        if self.cache.len() > 10_000 {
+            self.cache.retain(|_, (_, at)| at.elapsed() < INSTANCE_TTL);
+        }
+        self.cache.insert(slug.to_owned(), (instance.clone(), Instant::now()));
+        instance
+    }
+
+    pub fn render(&self, slug: &str, instance: &Instance) -> String {
+        let logo = instance
+            .logo_url
+            .as_deref()
+            .map(|url| format!(r#"<img src="{}" alt="">"#, escape(url)))
+            .unwrap_or_default();
+        self.template
+            .replace("{{name}}", &escape(&instance.name))
+            .replace("{{slug}}", &escape(slug))
+            .replace("{{logo}}", &logo)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,361 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use dashmap::DashMap;
+use tracing::warn;
+
+use super::access_log::LogEntry;
+use super::cluster::NODE_EXPIRY;
+use super::db::DbPool;
+use super::registry::Registry;
+
+/// How long per-slug limits are trusted before re-reading directory_instances.
+const LIMITS_TTL: Duration = Duration::from_secs(60);
+/// How often usage counters are written to relay_usage.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// How often a slug's bytes for the day are re-read from relay_usage, which
+/// every node of a cluster adds to.
+const USAGE_SYNC: Duration = Duration::from_secs(60);
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Per-slug limits from directory_instances. `None` = unlimited. In a
+/// cluster, the rate and concurrency limits are this node's share: they are
+/// divided between the nodes holding a tunnel for the slug.
+#[derive(Clone, Copy, Default)]
+struct Limits {
+    rps: Option<u32>,
+    concurrent: Option<u32>,
+    bytes_per_day: Option<u64>,
+}
+
+/// Enforcement state for one slug.
+struct Live {
+    /// UTC day (days since the epoch) `bytes_today` belongs to.
+    day: u64,
+    bytes_today: u64,
+    /// When `bytes_today` was last re-read from relay_usage.
+    synced_at: Instant,
+    /// Token bucket for the request rate, refilled at `rps` per second.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Usage not yet written to relay_usage.
+#[derive(Default)]
+struct Pending {
+    requests: i64,
+    rejected: i64,
+    bytes_in: i64,
+    bytes_out: i64,
+}
+
+/// Why a request was refused.
+pub enum QuotaExceeded {
+    Rate,
+    Concurrency,
+    /// Carries the seconds until the daily quota resets.
+    Bandwidth(u64),
+}
+
+impl QuotaExceeded {
+    pub fn message(&self) -> &'static str {
+        match self {
+            QuotaExceeded::Rate => "Request rate limit exceeded for this instance",
+            QuotaExceeded::Concurrency => "Too many concurrent requests for this instance",
+            QuotaExceeded::Bandwidth(_) => "Daily bandwidth quota exceeded for this instance",
+        }
+    }
+
+    /// Value for the Retry-After header, in seconds.
+    pub fn retry_after(&self) -> u64 {
+        match self {
+            QuotaExceeded::Rate | QuotaExceeded::Concurrency => 1,
+            QuotaExceeded::Bandwidth(secs) => *secs,
+        }
+    }
+}
+
+// ── Quotas ────────────────────────────────────────────────────────────────────
+
+/// Per-slug request-rate, concurrency and daily bandwidth quotas for
+/// tunnelled traffic, with usage counters persisted in relay_usage.
+pub struct Quotas {
+    pg: Arc<DbPool>,
+    limits: DashMap<String, (Limits, Instant)>,
+    live: DashMap<String, Live>,
+    /// (slug, day) → counters since the last flush.
+    pending: DashMap<(String, u64), Pending>,
+}
+
+impl Quotas {
+    pub fn new(pg: Arc<DbPool>) -> Arc<Self> {
+        let quotas = Arc::new(Self {
+            pg,
+            limits: DashMap::new(),
+            live: DashMap::new(),
+            pending: DashMap::new(),
+        });
+
+        let flusher = quotas.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(FLUSH_INTERVAL).await;
+                flusher.flush().await;
+            }
+        });
+        quotas
+    }
+
+    /// Admit a request for `slug`, or say which quota it exceeds. The returned
+    /// meter counts the request's body bytes against the daily quota.
+    pub async fn check(
+        self: &Arc<Self>,
+        slug: &str,
+        registry: &Registry,
+    ) -> Result<Meter, QuotaExceeded> {
+        let limits = self.limits(slug).await;
+        let today = today();
+        let state = self.live.get(slug).map(|l| (l.day == today, l.synced_at.elapsed() < USAGE_SYNC));
+        match state {
+            Some((true, true)) => {}
+            Some((true, false)) => {
+                // Catch up with what other nodes recorded meanwhile, plus what
+                // this one has not flushed yet.
+                if let Some(recorded) = self.recorded_bytes(slug, today).await {
+                    let bytes_today = recorded + self.unflushed_bytes(slug, today);
+                    if let Some(mut live) = self.live.get_mut(slug) {
+                        live.bytes_today = bytes_today;
+                        live.synced_at = Instant::now();
+                    }
+                }
+            }
+            _ => {
+                // First request of the day (or since start-up): resume from what
+                // is already recorded, so a restart doesn't reset the quota.
+                let recorded = self.recorded_bytes(slug, today).await.unwrap_or(0);
+                self.live.insert(
+                    slug.to_owned(),
+                    Live {
+                        day: today,
+                        bytes_today: recorded + self.unflushed_bytes(slug, today),
+                        synced_at: Instant::now(),
+                        tokens: limits.rps.unwrap_or(0) as f64,
+                        refilled_at: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        let verdict = self.admit(slug, &limits, registry);
+        let mut pending = self.pending.entry((slug.to_owned(), today)).or_default();
+        match verdict {
+            Ok(()) => {
+                pending.requests += 1;
+                Ok(Meter { quotas: self.clone(), slug: slug.into(), log: None })
+            }
+            Err(e) => {
+                pending.rejected += 1;
+                Err(e)
+            }
+        }
+    }
+
+    fn admit(&self, slug: &str, limits: &Limits, registry: &Registry) -> Result<(), QuotaExceeded> {
+        if let Some(max) = limits.concurrent {
+            let in_flight: usize = registry.tunnels(slug).iter().map(|t| t.in_flight()).sum();
+            if in_flight >= max as usize {
+                return Err(QuotaExceeded::Concurrency);
+            }
+        }
+
+        let Some(mut live) = self.live.get_mut(slug) else { return Ok(()) };
+
+        if let Some(max) = limits.bytes_per_day {
+            if live.bytes_today >= max {
+                let secs = SECS_PER_DAY - now_secs() % SECS_PER_DAY;
+                return Err(QuotaExceeded::Bandwidth(secs));
+            }
+        }
+
+        if let Some(rps) = limits.rps {
+            // Bucket of one second's worth of requests: allows short bursts.
+            let rps = rps as f64;
+            let now = Instant::now();
+            let elapsed = now.duration_since(live.refilled_at).as_secs_f64();
+            live.tokens = (live.tokens + elapsed * rps).min(rps);
+            live.refilled_at = now;
+            if live.tokens < 1.0 {
+                return Err(QuotaExceeded::Rate);
+            }
+            live.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    fn add_bytes(&self, slug: &str, bytes_in: usize, bytes_out: usize) {
+        let day = match self.live.get_mut(slug) {
+            Some(mut live) => {
+                live.bytes_today += (bytes_in + bytes_out) as u64;
+                live.day
+            }
+            None => today(),
+        };
+        let mut pending = self.pending.entry((slug.to_owned(), day)).or_default();
+        pending.bytes_in += bytes_in as i64;
+        pending.bytes_out += bytes_out as i64;
+    }
+
+    async fn limits(&self, slug: &str) -> Limits {
+        if let Some(entry) = self.limits.get(slug) {
+            let (limits, at) = *entry.value();
+            if at.elapsed() < LIMITS_TTL {
+                return limits;
+            }
+        }
+
+        let row = self
+            .pg
+            .query_opt(
+                "SELECT relay_rps_limit, relay_concurrent_limit, relay_bytes_per_day, \
+                   (SELECT COUNT(*) FROM relay_cluster_tunnels \
+                    WHERE slug = $1 AND seen_at > NOW() - make_interval(secs => $2)) \
+                 FROM directory_instances WHERE slug = $1",
+                &[&slug, &NODE_EXPIRY.as_secs_f64()],
+            )
+            .await;
+        let limits = match row {
+            Ok(Some(row)) => {
+                // Outside cluster mode no node publishes its tunnels.
+                let nodes = row.get::<_, i64>(3).clamp(1, i64::from(u32::MAX)) as u32;
+                Limits {
+                    rps: row.get::<_, Option<i32>>(0).map(|v| (v.max(1) as u32).div_ceil(nodes)),
+                    concurrent: row.get::<_, Option<i32>>(1).map(|v| (v.max(0) as u32).div_ceil(nodes)),
+                    bytes_per_day: row.get::<_, Option<i64>>(2).map(|v| v.max(0) as u64),
+                }
+            }
+            Ok(None) => Limits::default(),
+            Err(e) => {
+                // Fail open, and don't cache — the next request retries.
+                warn!("Quota lookup for '{slug}' failed: {e}");
+                return Limits::default();
+            }
+        };
+        self.limits.insert(slug.to_owned(), (limits, Instant::now()));
+        limits
+    }
+
+    /// `None` if relay_usage could not be read.
+    async fn recorded_bytes(&self, slug: &str, day: u64) -> Option<u64> {
+        let row = self
+            .pg
+            .query_opt(
+                "SELECT bytes_in + bytes_out FROM relay_usage \
+                 WHERE slug = $1 AND day = DATE '1970-01-01' + $2::integer",
+                &[&slug, &(day as i32)],
+            )
+            .await;
+        match row {
+            Ok(Some(row)) => Some(row.get::<_, i64>(0).max(0) as u64),
+            Ok(None) => Some(0),
+            Err(e) => {
+                warn!("Usage lookup for '{slug}' failed: {e}");
+                None
+            }
+        }
+    }
+
+    /// Bytes counted on this node and not yet written to relay_usage.
+    fn unflushed_bytes(&self, slug: &str, day: u64) -> u64 {
+        self.pending
+            .get(&(slug.to_owned(), day))
+            .map_or(0, |p| (p.bytes_in + p.bytes_out).max(0) as u64)
+    }
+
+    /// Write pending counters to relay_usage. Counters that fail to flush are
+    /// put back for the next round, until their day is over.
+    async fn flush(&self) {
+        let today = today();
+        let keys: Vec<(String, u64)> = self.pending.iter().map(|e| e.key().clone()).collect();
+        for key in keys {
+            let Some((key, p)) = self.pending.remove(&key) else { continue };
+            let (slug, day) = &key;
+            let result = self
+                .pg
+                .execute(
+                    "INSERT INTO relay_usage (slug, day, requests, rejected, bytes_in, bytes_out) \
+                     VALUES ($1, DATE '1970-01-01' + $2::integer, $3, $4, $5, $6) \
+                     ON CONFLICT (slug, day) DO UPDATE SET \
+                       requests  = relay_usage.requests  + EXCLUDED.requests, \
+                       rejected  = relay_usage.rejected  + EXCLUDED.rejected, \
+                       bytes_in  = relay_usage.bytes_in  + EXCLUDED.bytes_in, \
+                       bytes_out = relay_usage.bytes_out + EXCLUDED.bytes_out",
+                    &[slug, &(*day as i32), &p.requests, &p.rejected, &p.bytes_in, &p.bytes_out],
+                )
+                .await;
+            if let Err(e) = result {
+                warn!("Flushing relay usage for '{slug}' failed: {e}");
+                if *day == today {
+                    let mut back = self.pending.entry(key.clone()).or_default();
+                    back.requests += p.requests;
+                    back.rejected += p.rejected;
+                    back.bytes_in += p.bytes_in;
+                    back.bytes_out += p.bytes_out;
+                }
+            }
+        }
+
+        // Forget enforcement state from previous days.
+        self.live.retain(|_, live| live.day == today);
+    }
+}
+
+/// Counts one request's body bytes against its slug's usage.
+#[derive(Clone)]
+pub struct Meter {
+    quotas: Arc<Quotas>,
+    slug: Arc<str>,
+    /// The request's access log entry, counted along.
+    log: Option<LogEntry>,
+}
+
+impl Meter {
+    /// Count into the request's access log entry as well. It is written
+    /// once the meter is dropped, at the end of the response.
+    pub fn logging(self, log: Option<LogEntry>) -> Self {
+        Self { log, ..self }
+    }
+
+    /// Request body bytes, from the visitor.
+    pub fn bytes_in(&self, n: usize) {
+        self.quotas.add_bytes(&self.slug, n, 0);
+        if let Some(log) = &self.log {
+            log.bytes_in(n);
+        }
+    }
+
+    /// Response body bytes, to the visitor.
+    pub fn bytes_out(&self, n: usize) {
+        self.quotas.add_bytes(&self.slug, 0, n);
+        if let Some(log) = &self.log {
+            log.bytes_out(n);
+        }
+    }
+
+    /// Time until the response head came back through the tunnel.
+    pub fn tunnel_latency(&self, latency: Duration) {
+        if let Some(log) = &self.log {
+            log.tunnel_latency(latency);
+        }
+    }
+}
+
+// ── Helpers ───────────────────────────────────────────────────────────────────
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Current UTC day, as days since the epoch.
+fn today() -> u64 {
+    now_secs() / SECS_PER_DAY
+}
@@ -1,54 +1,251 @@
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use dashmap::DashMap;
 use tokio::sync::{mpsc, oneshot};
-use crate::protocol::ServerMessage;
+use tokio_util::sync::CancellationToken;
+use crate::protocol::{BodyReceiver, Expose, Flow, Headers, ServerMessage, ServiceSpec, capability};
+use crate::timeouts::TimeoutPolicy;
 
 // ── Types ─────────────────────────────────────────────────────────────────────
 
-/// A pending HTTP request waiting for the relay client to respond.
+/// A message queued for a tunnel's write task.
 pub struct PendingRequest {
     pub msg: ServerMessage,
-    /// Channel to send the relay client's response back to the HTTP proxy.
-    pub reply_tx: oneshot::Sender<RelayResponse>,
+    /// Set for `Request` messages only: channel to send the relay client's
+    /// response head back to the HTTP proxy. Body chunks and pings carry `None`.
+    pub reply_tx: Option<oneshot::Sender<RelayResponse>>,
 }
 
 /// The relay client's HTTP response, as received over the TCP tunnel.
+/// The body is streamed, and ends in an error if it was cut short.
 pub struct RelayResponse {
     pub status: u16,
-    pub headers: std::collections::HashMap<String, String>,
-    pub body: Vec<u8>,
+    pub headers: Headers,
+    pub body: BodyReceiver<PendingRequest>,
 }
 
 /// A handle to a connected relay client — send requests, receive responses.
 #[derive(Clone)]
 pub struct TunnelHandle {
+    /// Unique per connection, so one of several tunnels of a slug can be removed.
+    pub id: u64,
     pub tx: mpsc::Sender<PendingRequest>,
+    /// Flow control of the bodies and streams relayed through this tunnel.
+    pub flow: Flow<PendingRequest>,
+    /// Capabilities negotiated with this client at registration.
+    pub capabilities: Arc<[String]>,
+    /// Request deadlines the client asked for at registration.
+    pub timeouts: Arc<TimeoutPolicy>,
+    /// TCP services this client exposes, as granted at registration.
+    pub services: Arc<[ServiceSpec]>,
+    /// Relay client address.
+    pub peer: SocketAddr,
+    pub connected_at: SystemTime,
+    state: Arc<TunnelState>,
+}
+
+/// Live counters and flags shared by all clones of a handle.
+#[derive(Default)]
+struct TunnelState {
+    in_flight: AtomicUsize,
+    /// Body bytes received from / sent to the relay client.
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    draining: AtomicBool,
+    closed: CancellationToken,
+}
+
+impl TunnelHandle {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    pub fn offers(&self, service: &str, expose: Expose) -> bool {
+        self.services.iter().any(|s| s.name == service && s.expose == expose)
+    }
+
+    /// Requests and streams currently dispatched to this tunnel.
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Count a request against this tunnel until the returned guard is dropped.
+    pub fn start_request(&self) -> InFlight {
+        self.state.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.state.clone())
+    }
+
+    pub fn add_bytes_in(&self, n: usize) {
+        self.state.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, n: usize) {
+        self.state.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.state.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.state.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Stop dispatching new requests here; in-flight ones are unaffected.
+    pub fn drain(&self) {
+        self.state.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.state.draining.load(Ordering::Relaxed)
+    }
+
+    /// Ask the connection's handler to drop the relay client.
+    pub fn disconnect(&self) {
+        self.state.closed.cancel();
+    }
+
+    /// Resolves once `disconnect` has been called.
+    pub async fn disconnected(&self) {
+        self.state.closed.cancelled().await
+    }
+}
+
+/// Keeps a request counted in its tunnel's in-flight total while alive.
+pub struct InFlight(Arc<TunnelState>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How a request picks one of several tunnels registered for the same slug.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    /// Each tunnel in turn.
+    RoundRobin,
+    /// The tunnel with the fewest in-flight requests (round-robin among ties).
+    #[default]
+    LeastPending,
 }
 
 // ── Registry ──────────────────────────────────────────────────────────────────
 
-/// Thread-safe map of slug → active tunnel handle.
+/// Thread-safe map of slug → active tunnels. A slug may have several relay
+/// clients connected at once (several nodes of one instance); requests are
+/// spread across them.
 #[derive(Clone, Default)]
-pub struct Registry(Arc<DashMap<String, TunnelHandle>>);
+pub struct Registry {
+    tunnels: Arc<DashMap<String, Vec<TunnelHandle>>>,
+    next_id: Arc<AtomicU64>,
+    cursor: Arc<AtomicUsize>,
+    balance: Arc<Mutex<Balance>>,
+}
 
 impl Registry {
-    pub fn new() -> Self {
-        Self(Arc::new(DashMap::new()))
+    pub fn new(balance: Balance) -> Self {
+        Self { balance: Arc::new(Mutex::new(balance)), ..Default::default() }
     }
 
-    pub fn insert(&self, slug: String, handle: TunnelHandle) {
-        self.0.insert(slug, handle);
+    /// Change the balancing strategy for subsequent requests.
+    pub fn set_balance(&self, balance: Balance) {
+        *self.balance.lock().unwrap() = balance;
+    }
+
+    /// Add a tunnel for `slug` alongside any already connected.
+    pub fn register(
+        &self,
+        slug: String,
+        tx: mpsc::Sender<PendingRequest>,
+        capabilities: Vec<String>,
+        timeouts: TimeoutPolicy,
+        services: Vec<ServiceSpec>,
+        peer: SocketAddr,
+    ) -> TunnelHandle {
+        let credit = capabilities.iter().any(|c| c == capability::CREDIT);
+        let handle = TunnelHandle {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            flow: Flow::new(credit, tx.clone(), |id, chunks| PendingRequest {
+                msg: ServerMessage::Credit { id, chunks },
+                reply_tx: None,
+            }),
+            tx,
+            capabilities: capabilities.into(),
+            timeouts: Arc::new(timeouts),
+            services: services.into(),
+            peer,
+            connected_at: SystemTime::now(),
+            state: Arc::default(),
+        };
+        self.tunnels.entry(slug).or_default().push(handle.clone());
+        handle
+    }
+
+    /// Remove one tunnel of `slug`; the slug goes away with its last tunnel.
+    pub fn remove(&self, slug: &str, id: u64) {
+        self.tunnels.remove_if_mut(slug, |_, tunnels| {
+            tunnels.retain(|t| t.id != id);
+            tunnels.is_empty()
+        });
+    }
+
+    /// Choose a tunnel of `slug` for a new request, skipping draining tunnels
+    /// and, if given, those that did not negotiate `capability`.
+    pub fn pick(&self, slug: &str, capability: Option<&str>) -> Option<TunnelHandle> {
+        self.pick_where(slug, |t| capability.is_none_or(|c| t.supports(c)))
+    }
+
+    /// Choose a tunnel of `slug` exposing TCP `service` as `expose`.
+    pub fn pick_service(&self, slug: &str, service: &str, expose: Expose) -> Option<TunnelHandle> {
+        self.pick_where(slug, |t| t.offers(service, expose))
+    }
+
+    fn pick_where(&self, slug: &str, eligible: impl Fn(&TunnelHandle) -> bool) -> Option<TunnelHandle> {
+        let tunnels = self.tunnels.get(slug)?;
+        let candidates: Vec<&TunnelHandle> = tunnels
+            .iter()
+            .filter(|t| !t.is_draining() && eligible(t))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let n = candidates.len();
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        let balance = *self.balance.lock().unwrap();
+        let chosen = match balance {
+            Balance::RoundRobin => candidates[start % n],
+            // min_by_key keeps the first minimum, so rotating the start
+            // spreads ties evenly.
+            Balance::LeastPending => (0..n)
+                .map(|i| candidates[(start + i) % n])
+                .min_by_key(|t| t.in_flight())
+                .unwrap(),
+        };
+        Some(chosen.clone())
     }
 
-    pub fn remove(&self, slug: &str) {
-        self.0.remove(slug);
+    /// Tunnels of `slug`, draining ones included.
+    pub fn tunnels(&self, slug: &str) -> Vec<TunnelHandle> {
+        self.tunnels.get(slug).map(|t| t.clone()).unwrap_or_default()
     }
 
-    pub fn get(&self, slug: &str) -> Option<TunnelHandle> {
-        self.0.get(slug).map(|h| h.clone())
+    /// Every tunnel, by slug.
+    pub fn snapshot(&self) -> Vec<(String, TunnelHandle)> {
+        self.tunnels
+            .iter()
+            .flat_map(|e| e.value().iter().map(|t| (e.key().clone(), t.clone())).collect::<Vec<_>>())
+            .collect()
     }
 
+    /// Whether `slug` has at least one tunnel accepting new requests.
     pub fn contains(&self, slug: &str) -> bool {
-        self.0.contains_key(slug)
+        self.tunnels
+            .get(slug)
+            .is_some_and(|tunnels| tunnels.iter().any(|t| !t.is_draining()))
     }
 }
@@ -0,0 +1,527 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::protocol::{Expose, ServerMessage, ServiceGrant, ServiceSpec};
+use super::db::DbPool;
+use super::http_proxy::{pump_stream, ProxyState};
+use super::registry::PendingRequest;
+
+/// How long a relay client has to connect to its local service.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a TLS client has to send its ClientHello on the SNI port.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest TLS record payload; a ClientHello must fit in one.
+const MAX_RECORD_LEN: usize = 16 * 1024;
+/// Services a slug may expose, and `port` services it may hold a port for.
+const MAX_SERVICES_PER_SLUG: usize = 16;
+/// How long the port of a service nobody exposes any more stays reserved.
+const PORT_RETENTION: Duration = Duration::from_secs(30 * 24 * 3600);
+/// How often open ports are marked seen and expired ones reclaimed.
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Public ports handed out to `port` services, bounds included.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    /// Parses `FIRST-LAST`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected FIRST-LAST, got '{s}'"))?;
+        let port = |p: &str| p.trim().parse::<u16>().map_err(|_| format!("invalid port in '{s}'"));
+        let (first, last) = (port(first)?, port(last)?);
+        if first == 0 || first > last {
+            return Err(format!("invalid port range '{s}'"));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+/// Raw TCP service settings. Both are bound listeners, applied on restart.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServicesConfig {
+    /// Ports for `port` services; refused when `None`.
+    pub ports: Option<PortRange>,
+    /// Bind address of the TLS passthrough listener for `sni` services;
+    /// refused when `None`.
+    pub sni_bind: Option<String>,
+}
+
+/// A (slug, service name) pair.
+type ServiceKey = (String, String);
+
+/// A public port listening for one (slug, service).
+struct PortListener {
+    port: u16,
+    /// Tunnels exposing the service; the port closes with the last one.
+    tunnels: usize,
+    accept: AbortHandle,
+}
+
+// ── TCP services ──────────────────────────────────────────────────────────────
+
+/// Raw TCP services exposed by relay clients, multiplexed over their tunnels
+/// like upgraded HTTP connections: each visitor connection is a TcpOpen then
+/// StreamData both ways.
+///
+/// A `port` service gets a public port of its own, remembered in
+/// relay_tcp_services so it stays the same across reconnections and relay
+/// restarts. An `sni` service shares the TLS passthrough port: connections
+/// are routed on the ClientHello's server name, `<service>.<slug host>`, and
+/// the TLS session itself is left to the instance.
+pub struct TcpServices {
+    config: ServicesConfig,
+    pg: Arc<DbPool>,
+    state: Arc<RwLock<Arc<ProxyState>>>,
+    listeners: Mutex<HashMap<ServiceKey, PortListener>>,
+    /// Held while a port is being opened, so that tunnels of a slug
+    /// registering the same service at once open it only once.
+    opening: Mutex<HashMap<ServiceKey, Arc<tokio::sync::Mutex<()>>>>,
+    shutdown: CancellationToken,
+}
+
+/// Keeps the ports of a tunnel's services open until dropped.
+pub struct Lease {
+    services: Arc<TcpServices>,
+    ports: Vec<ServiceKey>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut listeners = self.services.listeners.lock().unwrap();
+        for key in &self.ports {
+            let Some(listener) = listeners.get_mut(key) else { continue };
+            listener.tunnels -= 1;
+            if listener.tunnels == 0 {
+                listener.accept.abort();
+                info!("TCP service '{}' of '{}' closed (port {})", key.1, key.0, listener.port);
+                listeners.remove(key);
+            }
+        }
+    }
+}
+
+impl TcpServices {
+    pub fn new(
+        config: ServicesConfig,
+        pg: Arc<DbPool>,
+        state: Arc<RwLock<Arc<ProxyState>>>,
+        shutdown: CancellationToken,
+    ) -> Arc<Self> {
+        Arc::new(Self { config, pg, state, listeners: Mutex::default(), opening: Mutex::default(), shutdown })
+    }
+
+    /// Expose `specs` for a tunnel of `slug`. Returns the answer for each, the
+    /// specs that were granted, and the lease holding their ports open.
+    pub async fn open(
+        self: &Arc<Self>,
+        slug: &str,
+        specs: Vec<ServiceSpec>,
+    ) -> (Vec<ServiceGrant>, Vec<ServiceSpec>, Lease) {
+        let mut lease = Lease { services: self.clone(), ports: Vec::new() };
+        let mut grants = Vec::new();
+        let mut granted: Vec<ServiceSpec> = Vec::new();
+        let host = self.host(slug);
+
+        for spec in specs {
+            let address = if !ServiceSpec::valid_name(&spec.name) {
+                Err("service names are DNS labels: a-z, 0-9 and '-'".to_owned())
+            } else if granted.iter().any(|s| s.name == spec.name) {
+                Err("duplicate service name".to_owned())
+            } else if granted.len() >= MAX_SERVICES_PER_SLUG {
+                Err(format!("at most {MAX_SERVICES_PER_SLUG} services per instance"))
+            } else {
+                match spec.expose {
+                    Expose::Port => self.open_port(slug, &spec.name).await.map(|port| {
+                        lease.ports.push((slug.to_owned(), spec.name.clone()));
+                        format!("{host}:{port}")
+                    }),
+                    Expose::Sni => match self.sni_port() {
+                        Some(port) => Ok(format!("{}.{host}:{port}", spec.name)),
+                        None => Err("TLS passthrough is not enabled on this relay".to_owned()),
+                    },
+                }
+            };
+            match address {
+                Ok(address) => {
+                    info!("TCP service '{}' of '{slug}' exposed at {address}", spec.name);
+                    grants.push(ServiceGrant { name: spec.name.clone(), address: Some(address), error: None });
+                    granted.push(spec);
+                }
+                Err(error) => {
+                    warn!("TCP service '{}' of '{slug}' refused: {error}", spec.name);
+                    grants.push(ServiceGrant { name: spec.name, address: None, error: Some(error) });
+                }
+            }
+        }
+        (grants, granted, lease)
+    }
+
+    /// `slug`'s public host name, under the relay's primary domain.
+    pub fn host(&self, slug: &str) -> String {
+        format!("{slug}.{}", self.state.read().unwrap().router.primary_domain())
+    }
+
+    fn sni_port(&self) -> Option<u16> {
+        let bind = self.config.sni_bind.as_ref()?;
+        bind.rsplit_once(':')?.1.parse().ok()
+    }
+
+    /// Listen on the port of `slug`'s service `name`, or count one more
+    /// tunnel on it if it is already open. Waits for another tunnel of the
+    /// slug opening it at the same time.
+    async fn open_port(self: &Arc<Self>, slug: &str, name: &str) -> Result<u16, String> {
+        let key = (slug.to_owned(), name.to_owned());
+        let gate = self.opening.lock().unwrap().entry(key.clone()).or_default().clone();
+        let opened = {
+            let _opening = gate.lock().await;
+            self.open_port_once(key.clone()).await
+        };
+        let mut opening = self.opening.lock().unwrap();
+        // Nobody else is waiting on it: ours and the map's are the only references.
+        if Arc::strong_count(&gate) == 2 {
+            opening.remove(&key);
+        }
+        opened
+    }
+
+    async fn open_port_once(self: &Arc<Self>, key: ServiceKey) -> Result<u16, String> {
+        let (slug, name) = (key.0.as_str(), key.1.as_str());
+        if let Some(listener) = self.listeners.lock().unwrap().get_mut(&key) {
+            listener.tunnels += 1;
+            return Ok(listener.port);
+        }
+
+        let range = self.config.ports.ok_or("TCP ports are not enabled on this relay")?;
+        let port = match self.allocate(slug, name, range).await {
+            Ok(Ok(port)) => port,
+            Ok(Err(refused)) => return Err(refused.to_owned()),
+            Err(e) => {
+                warn!("TCP port allocation for '{slug}' failed: {e}");
+                return Err("port allocation failed".to_owned());
+            }
+        };
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .await
+            .map_err(|e| format!("port {port} unavailable: {e}"))?;
+
+        let accept = tokio::spawn(self.clone().accept_port(listener, key.clone())).abort_handle();
+        self.listeners.lock().unwrap().insert(key, PortListener { port, tunnels: 1, accept });
+        Ok(port)
+    }
+
+    /// The port of `slug`'s service `name`: the one it had, if still in
+    /// `range`, else the lowest free one.
+    async fn allocate(
+        &self,
+        slug: &str,
+        name: &str,
+        range: PortRange,
+    ) -> anyhow::Result<Result<u16, &'static str>> {
+        let (first, last) = (i32::from(range.first), i32::from(range.last));
+        let kept = self
+            .pg
+            .query_opt(
+                "UPDATE relay_tcp_services SET seen_at = NOW() \
+                 WHERE slug = $1 AND name = $2 AND port BETWEEN $3 AND $4 \
+                 RETURNING port",
+                &[&slug, &name, &first, &last],
+            )
+            .await?;
+        if let Some(row) = kept {
+            return Ok(Ok(row.get::<_, i32>(0) as u16));
+        }
+
+        // A new service, or the range changed since its port was allocated.
+        self.pg
+            .execute("DELETE FROM relay_tcp_services WHERE slug = $1 AND name = $2", &[&slug, &name])
+            .await?;
+        let held = self
+            .pg
+            .query_opt("SELECT COUNT(*) FROM relay_tcp_services WHERE slug = $1", &[&slug])
+            .await?
+            .map_or(0, |row| row.get::<_, i64>(0));
+        if held >= MAX_SERVICES_PER_SLUG as i64 {
+            return Ok(Err("this instance already holds too many ports"));
+        }
+        let row = self
+            .pg
+            .query_opt(
+                "INSERT INTO relay_tcp_services (slug, name, port) \
+                 SELECT $1, $2, p FROM generate_series($3::int, $4::int) AS p \
+                 WHERE NOT EXISTS (SELECT 1 FROM relay_tcp_services WHERE port = p) \
+                 ORDER BY p LIMIT 1 \
+                 ON CONFLICT DO NOTHING \
+                 RETURNING port",
+                &[&slug, &name, &first, &last],
+            )
+            .await?;
+        Ok(row.map(|row| row.get::<_, i32>(0) as u16).ok_or("no free port left on this relay"))
+    }
+
+    /// Keep the ports this node has open from expiring, and free those of
+    /// services not exposed anywhere for PORT_RETENTION. Runs until `shutdown`.
+    pub async fn reclaim(self: Arc<Self>) -> std::io::Result<()> {
+        if self.config.ports.is_none() {
+            return Ok(());
+        }
+        let shutdown = self.shutdown.clone();
+        shutdown
+            .run_until_cancelled(async {
+                loop {
+                    match self.reclaim_once().await {
+                        Ok(0) => {}
+                        Ok(n) => info!("Reclaimed {n} TCP service port(s) left unused"),
+                        Err(e) => warn!("TCP service port reclaim failed: {e}"),
+                    }
+                    tokio::time::sleep(RECLAIM_INTERVAL).await;
+                }
+            })
+            .await;
+        Ok(())
+    }
+
+    async fn reclaim_once(&self) -> anyhow::Result<u64> {
+        let (slugs, names): (Vec<String>, Vec<String>) =
+            self.listeners.lock().unwrap().keys().cloned().unzip();
+        if !slugs.is_empty() {
+            self.pg
+                .execute(
+                    "UPDATE relay_tcp_services SET seen_at = NOW() \
+                     WHERE (slug, name) IN (SELECT * FROM unnest($1::text[], $2::text[]))",
+                    &[&slugs, &names],
+                )
+                .await?;
+        }
+        let reclaimed = self
+            .pg
+            .execute(
+                "DELETE FROM relay_tcp_services WHERE seen_at < NOW() - make_interval(secs => $1)",
+                &[&PORT_RETENTION.as_secs_f64()],
+            )
+            .await?;
+        Ok(reclaimed)
+    }
+
+    async fn accept_port(self: Arc<Self>, listener: TcpListener, (slug, name): ServiceKey) {
+        while let Some(accepted) = self.shutdown.run_until_cancelled(listener.accept()).await {
+            match accepted {
+                Ok((stream, peer)) => {
+                    let services = self.clone();
+                    let (slug, name) = (slug.clone(), name.clone());
+                    tokio::spawn(async move {
+                        services.serve(stream, peer, &slug, &name, Expose::Port, Bytes::new()).await;
+                    });
+                }
+                Err(e) => warn!("TCP service '{name}' of '{slug}': accept error: {e}"),
+            }
+        }
+    }
+
+    /// The TLS passthrough listener, when configured. Runs until `shutdown`.
+    pub async fn run_sni(self: Arc<Self>) -> std::io::Result<()> {
+        let Some(bind) = &self.config.sni_bind else { return Ok(()) };
+        let listener = TcpListener::bind(bind).await?;
+        info!("TLS passthrough listener on {bind}");
+
+        while let Some(accepted) = self.shutdown.run_until_cancelled(listener.accept()).await {
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("TLS passthrough accept error: {e}");
+                    continue;
+                }
+            };
+            let services = self.clone();
+            tokio::spawn(async move { services.route_sni(stream, peer).await });
+        }
+        info!("TLS passthrough listener closed");
+        Ok(())
+    }
+
+    /// Route a TLS connection on the server name of its ClientHello.
+    async fn route_sni(&self, mut stream: TcpStream, peer: SocketAddr) {
+        let (hello, host) = match tokio::time::timeout(HELLO_TIMEOUT, read_client_hello(&mut stream)).await {
+            Ok(Ok(Some(hello))) => hello,
+            _ => {
+                debug!("TLS passthrough: no usable ClientHello from {peer}");
+                return;
+            }
+        };
+        let Some((service, slug_host)) = host.split_once('.') else { return };
+        let router = self.state.read().unwrap().router.clone();
+        let Some(slug) = router.resolve(slug_host).await else {
+            debug!("TLS passthrough: unknown host '{host}' from {peer}");
+            return;
+        };
+        self.serve(stream, peer, &slug, service, Expose::Sni, hello).await;
+    }
+
+    /// Bridge a visitor connection to a tunnel exposing the service. `first`
+    /// holds bytes already read from it.
+    async fn serve(
+        &self,
+        stream: TcpStream,
+        peer: SocketAddr,
+        slug: &str,
+        service: &str,
+        expose: Expose,
+        first: Bytes,
+    ) {
+        let state = self.state.read().unwrap().clone();
+        if let Err(denied) = state.access.check_address(slug, peer.ip().to_canonical()).await {
+            debug!("TCP service '{service}' of '{slug}': {} — dropping {peer}", denied.message());
+            return;
+        }
+        let Some(handle) = state.registry.pick_service(slug, service, expose) else {
+            debug!("TCP service '{service}' of '{slug}' has no tunnel — dropping {peer}");
+            return;
+        };
+        let meter = match state.quotas.check(slug, &state.registry).await {
+            Ok(meter) => meter,
+            Err(exceeded) => {
+                debug!("TCP service '{service}' of '{slug}': {} — dropping {peer}", exceeded.message());
+                return;
+            }
+        };
+        let _in_flight = handle.start_request();
+
+        let id = Uuid::new_v4().to_string();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let msg = ServerMessage::TcpOpen { id: id.clone(), service: service.to_owned(), peer: peer.to_string() };
+        if handle.tx.send(PendingRequest { msg, reply_tx: Some(reply_tx) }).await.is_err() {
+            return;
+        }
+        let opened = match tokio::time::timeout(OPEN_TIMEOUT, reply_rx).await {
+            Ok(Ok(opened)) if opened.status == 200 => opened,
+            Ok(Ok(refused)) => {
+                debug!("TCP service '{service}' of '{slug}' refused {peer} (status {})", refused.status);
+                return;
+            }
+            _ => {
+                debug!("TCP service '{service}' of '{slug}' did not open in time for {peer}");
+                let _ = handle.tx.send(PendingRequest { msg: ServerMessage::Cancel { id }, reply_tx: None }).await;
+                return;
+            }
+        };
+
+        let window = handle.flow.send(&id);
+        if !first.is_empty() {
+            meter.bytes_in(first.len());
+            let room = window.reserve().await;
+            let msg = ServerMessage::StreamData { id: id.clone(), data: first };
+            if handle.tx.send(PendingRequest { msg, reply_tx: None }).await.is_err() {
+                return;
+            }
+            room.spend();
+        }
+        pump_stream(stream, id, opened.body, window, &handle.tx, &meter).await;
+    }
+}
+
+// ── TLS ClientHello ───────────────────────────────────────────────────────────
+
+/// Read the first TLS record of a connection, which must carry a whole
+/// ClientHello. Returns the record, to replay to the service, and the
+/// requested server name, lowercased.
+async fn read_client_hello(stream: &mut TcpStream) -> std::io::Result<Option<(Bytes, String)>> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header).await?;
+    // Content type 22: handshake.
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if header[0] != 22 || len > MAX_RECORD_LEN {
+        return Ok(None);
+    }
+    let mut record = vec![0u8; header.len() + len];
+    record[..header.len()].copy_from_slice(&header);
+    stream.read_exact(&mut record[header.len()..]).await?;
+
+    let name = server_name(&record[header.len()..]).map(|name| name.to_ascii_lowercase());
+    Ok(name.map(|name| (Bytes::from(record), name)))
+}
+
+/// The host name in the server_name extension of a ClientHello message.
+fn server_name(handshake: &[u8]) -> Option<String> {
+    let mut msg = Reader(handshake);
+    // Handshake type 1: client_hello.
+    if msg.u8()? != 1 {
+        return None;
+    }
+    let len = msg.u24()?;
+    let mut hello = Reader(msg.take(len)?);
+
+    hello.take(2 + 32)?; // version, random
+    let len = hello.u8()?;
+    hello.take(len)?; // session id
+    let len = hello.u16()?;
+    hello.take(len)?; // cipher suites
+    let len = hello.u8()?;
+    hello.take(len)?; // compression methods
+
+    let len = hello.u16()?;
+    let mut extensions = Reader(hello.take(len)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()?;
+        let data = extensions.take(len)?;
+        if kind != 0 {
+            continue;
+        }
+        let mut list = Reader(data);
+        let len = list.u16()?;
+        let mut names = Reader(list.take(len)?);
+        while !names.0.is_empty() {
+            let kind = names.u8()?;
+            let len = names.u16()?;
+            let name = names.take(len)?;
+            // Name type 0: host_name.
+            if kind == 0 {
+                return std::str::from_utf8(name).ok().map(str::to_owned);
+            }
+        }
+    }
+    None
+}
+
+/// Big-endian reads off a byte slice, `None` past its end.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| b[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3).map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
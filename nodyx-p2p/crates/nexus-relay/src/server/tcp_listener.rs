@@ -1,87 +1,50 @@
-use dashmap::DashMap;
-use std::net::{IpAddr, SocketAddr};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use super::bans::{AuthBans, Failure};
 use super::db::DbPool;
+use super::metrics::Metrics;
 use tracing::{error, info, warn};
 use base64::{Engine as _, engine::general_purpose::STANDARD as B64};
 
-use crate::protocol::{ClientMessage, ServerMessage, read_msg, write_msg};
-use super::registry::{PendingRequest, Registry, RelayResponse, TunnelHandle};
-
-// ── Auth failure rate limiter ─────────────────────────────────────────────────
-// Protects against token brute-force attempts on the TCP relay port (7443).
-
-/// Max failed auth attempts from a single IP within AUTH_WINDOW_SECS before banning.
-const MAX_AUTH_FAILURES:  u32 = 5;
-/// Time window for counting failures (seconds).
-const AUTH_WINDOW_SECS:   u64 = 60;
-/// How long a banned IP is refused connections (seconds).
-const BAN_DURATION_SECS:  u64 = 300;  // 5 minutes
-
-/// Maps source IP → (failed_attempts, first_failure_unix_secs).
-type BanMap = Arc<DashMap<IpAddr, (u32, u64)>>;
-
-fn auth_now_secs() -> u64 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
-}
-
-fn is_auth_banned(ban_map: &DashMap<IpAddr, (u32, u64)>, ip: IpAddr) -> bool {
-    if let Some(entry) = ban_map.get(&ip) {
-        let (attempts, since) = *entry;
-        attempts >= MAX_AUTH_FAILURES && auth_now_secs().saturating_sub(since) < BAN_DURATION_SECS
-    } else {
-        false
-    }
-}
-
-fn record_auth_failure(ban_map: &DashMap<IpAddr, (u32, u64)>, ip: IpAddr) {
-    let now = auth_now_secs();
-    ban_map.entry(ip)
-        .and_modify(|(count, since)| {
-            if now.saturating_sub(*since) > AUTH_WINDOW_SECS {
-                // Reset: first failure in a new window
-                *count = 1;
-                *since = now;
-            } else {
-                *count += 1;
-            }
-        })
-        .or_insert((1, now));
-}
+use crate::protocol::{
+    BodyReceiver, BodySender, ClientMessage, Framing, Headers, LEGACY_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, ServerMessage, ServiceGrant, ServiceSpec, Undelivered, capability, read_frame, read_msg,
+    write_frame, write_msg,
+};
+use crate::timeouts::TimeoutPolicy;
+use super::registry::{PendingRequest, Registry, RelayResponse};
+use super::Shared;
+use super::services::TcpServices;
 
 // ── Entry point ───────────────────────────────────────────────────────────────
 
-pub async fn run(
+pub(super) async fn run(
     bind: &str,
-    registry: Registry,
-    pg: Arc<DbPool>,
+    shared: &Shared,
+    services: Arc<TcpServices>,
+    tls: Option<TlsAcceptor>,
+    shutdown: CancellationToken,
 ) -> std::io::Result<()> {
+    let Shared { registry, pg, bans, metrics, .. } = shared;
     let listener = TcpListener::bind(bind).await?;
     info!("TCP relay listener on {bind}");
 
-    let ban_map: BanMap = Arc::new(DashMap::new());
-
-    // Periodic cleanup: remove ban entries that have fully expired.
-    {
-        let ban_map_c = ban_map.clone();
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(tokio::time::Duration::from_secs(BAN_DURATION_SECS)).await;
-                let now = auth_now_secs();
-                ban_map_c.retain(|_, (_, since)| now.saturating_sub(*since) < BAN_DURATION_SECS);
-            }
-        });
-    }
-
-    loop {
-        match listener.accept().await {
+    // Connected tunnels are left alone: the server says goodbye to them
+    // once their requests are done.
+    while let Some(accepted) = shutdown.run_until_cancelled(listener.accept()).await {
+        match accepted {
             Ok((stream, addr)) => {
                 // Reject connections from banned IPs before doing any I/O or DB work.
-                if is_auth_banned(&ban_map, addr.ip()) {
+                if bans.is_banned(addr.ip()) {
                     warn!("Relay: auth-banned IP {} — dropping connection", addr.ip());
+                    metrics.banned_connection();
                     drop(stream);
                     continue;
                 }
@@ -89,9 +52,30 @@ pub async fn run(
                 info!("Relay client connected from {addr}");
                 let registry = registry.clone();
                 let pg       = pg.clone();
-                let ban_map  = ban_map.clone();
+                let bans     = bans.clone();
+                let metrics  = metrics.clone();
+                let services = services.clone();
+                let tls      = tls.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client(stream, addr, registry, pg, ban_map).await {
+                    let result = match tls {
+                        Some(acceptor) => {
+                            // Bound the handshake so half-open connections don't pile up.
+                            match tokio::time::timeout(
+                                tokio::time::Duration::from_secs(10),
+                                acceptor.accept(stream),
+                            )
+                            .await
+                            {
+                                Ok(Ok(tls_stream)) => {
+                                    handle_client(tls_stream, addr, registry, pg, bans, metrics, services).await
+                                }
+                                Ok(Err(e)) => Err(anyhow::anyhow!("TLS handshake failed: {e}")),
+                                Err(_) => Err(anyhow::anyhow!("TLS handshake timed out")),
+                            }
+                        }
+                        None => handle_client(stream, addr, registry, pg, bans, metrics, services).await,
+                    };
+                    if let Err(e) = result {
                         warn!("Relay client {addr} disconnected: {e}");
                     }
                 });
@@ -99,88 +83,229 @@ pub async fn run(
             Err(e) => error!("Accept error: {e}"),
         }
     }
+    info!("TCP relay listener closed");
+    Ok(())
 }
 
 // ── Per-client handler ────────────────────────────────────────────────────────
 
-async fn handle_client(
-    mut stream: TcpStream,
+/// A client's Register, in a protocol version this server speaks.
+struct Registration {
+    slug: String,
+    token: String,
+    version: u32,
+    /// Capabilities enabled for the session: those both sides support.
+    capabilities: Vec<String>,
+    framing: Framing,
+    timeouts: TimeoutPolicy,
+    services: Vec<ServiceSpec>,
+    /// Public host name, told to the client once it is authenticated.
+    host: Option<String>,
+}
+
+async fn handle_client<S>(
+    mut stream: S,
     addr: SocketAddr,
     registry: Registry,
     pg: Arc<DbPool>,
-    ban_map: BanMap,
-) -> anyhow::Result<()> {
+    bans: Arc<AuthBans>,
+    metrics: Arc<Metrics>,
+    services: Arc<TcpServices>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     // 1. Expect Register as the very first message.
-    let Some(ClientMessage::Register { slug, token }) =
-        read_msg::<_, ClientMessage>(&mut stream).await?
-    else {
-        write_msg(
-            &mut stream,
-            &ServerMessage::Registered {
-                ok: false,
-                error: Some("Expected register message".into()),
-            },
-        )
-        .await?;
+    let Some(mut registration) = read_register(&mut stream, addr).await? else {
         return Ok(());
     };
+    let Registration { slug, token, .. } = &registration;
 
-    // 2. Validate token against directory_instances.
+    // 2. Validate token against directory_instances: the current one, or the
+    //    previous one during a rotation's grace period (tokens are stored hashed).
     let row = pg
         .query_opt(
-            "SELECT id FROM directory_instances WHERE slug = $1 AND token = $2 AND status = 'active'",
-            &[&slug, &token],
+            "SELECT id FROM directory_instances d \
+             WHERE slug = $1 AND status = 'active' AND directory_token_valid(d, $2)",
+            &[slug, token],
         )
         .await?;
 
     if row.is_none() {
-        record_auth_failure(&ban_map, addr.ip());
-        warn!("Relay: auth failure from {} (slug='{}') — {} attempt(s)",
-              addr.ip(), slug,
-              ban_map.get(&addr.ip()).map(|e| e.0).unwrap_or(1));
-        write_msg(
-            &mut stream,
-            &ServerMessage::Registered {
-                ok: false,
-                error: Some("Invalid slug or token".into()),
-            },
-        )
-        .await?;
+        metrics.auth_failure();
+        match bans.record_failure(addr.ip()).await {
+            Ok(Failure { ban: Some(ban), .. }) => {
+                metrics.ban();
+                warn!("Relay: auth failure from {} (slug='{}') — banned for {}s",
+                      addr.ip(), slug, ban.as_secs());
+            }
+            Ok(Failure { failures, ban: None }) => {
+                warn!("Relay: auth failure from {} (slug='{}') — {} attempt(s)",
+                      addr.ip(), slug, failures);
+            }
+            Err(e) => warn!("Relay: auth failure from {} (slug='{}') not recorded: {e}", addr.ip(), slug),
+        }
+        write_msg(&mut stream, &ServerMessage::rejected("Invalid slug or token")).await?;
         return Ok(());
     }
 
-    // 3. Register in the in-memory registry.
+    // 3. Expose its TCP services. From here on `services` holds those granted;
+    //    the lease keeps their ports open for as long as this tunnel.
+    let requested = std::mem::take(&mut registration.services);
+    let (grants, granted, _lease) = services.open(&registration.slug, requested).await;
+    registration.services = granted;
+    registration.host = Some(services.host(&registration.slug));
+
+    serve_tunnel(stream, addr, registry, registration, grants).await
+}
+
+/// Read the client's Register. Protocol versions we can't speak are refused
+/// before touching the database, with a message that makes sense in an old
+/// client's logs; `None` once the client has been turned away.
+async fn read_register<S>(stream: &mut S, addr: SocketAddr) -> anyhow::Result<Option<Registration>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(ClientMessage::Register { slug, token, version, capabilities, framings, timeouts, services }) =
+        read_msg::<_, ClientMessage>(stream).await?
+    else {
+        write_msg(stream, &ServerMessage::rejected("Expected register message")).await?;
+        return Ok(None);
+    };
+
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        warn!("Relay: '{slug}' from {} speaks protocol v{version} — rejecting", addr.ip());
+        let speaks = if MIN_PROTOCOL_VERSION == PROTOCOL_VERSION {
+            format!("v{PROTOCOL_VERSION}")
+        } else {
+            format!("v{MIN_PROTOCOL_VERSION}–v{PROTOCOL_VERSION}")
+        };
+        let error = if version < MIN_PROTOCOL_VERSION {
+            format!(
+                "Relay protocol v{version} is no longer supported (this relay speaks {speaks}). \
+                 Please upgrade nodyx-relay: https://github.com/Pokled/Nodyx/releases"
+            )
+        } else {
+            format!(
+                "Relay protocol v{version} is newer than this relay server ({speaks}). \
+                 Please use an older nodyx-relay or ask the relay operator to upgrade."
+            )
+        };
+        write_msg(stream, &ServerMessage::rejected(error)).await?;
+        return Ok(None);
+    }
+
+    let capabilities = capability::intersect(&capabilities);
+    // Services come with the `tcp` capability only.
+    let services = if capabilities.iter().any(|c| c == capability::TCP) { services } else { Vec::new() };
+    Ok(Some(Registration {
+        slug,
+        token,
+        version,
+        capabilities,
+        framing: Framing::negotiate(&framings),
+        timeouts,
+        services,
+        host: None,
+    }))
+}
+
+/// Register an authenticated client's tunnel, confirm it, and relay its
+/// traffic until either side goes away.
+async fn serve_tunnel<S>(
+    mut stream: S,
+    addr: SocketAddr,
+    registry: Registry,
+    registration: Registration,
+    grants: Vec<ServiceGrant>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Registration { slug, version, capabilities, framing, timeouts, services, host, .. } = registration;
+
+    // 4. Register in the in-memory registry.
     let (tx, mut rx) = mpsc::channel::<PendingRequest>(64);
-    registry.insert(slug.clone(), TunnelHandle { tx });
-    info!("Slug '{slug}' registered in relay");
+    let handle = registry.register(slug.clone(), tx, capabilities.clone(), timeouts, services, addr);
+    let tunnel_id = handle.id;
+    info!("Slug '{slug}' registered in relay (tunnel #{tunnel_id}, protocol v{version}, {framing:?} framing, capabilities: {capabilities:?})");
 
-    write_msg(&mut stream, &ServerMessage::Registered { ok: true, error: None }).await?;
+    write_msg(
+        &mut stream,
+        &ServerMessage::Registered {
+            ok: true,
+            error: None,
+            version,
+            capabilities,
+            framing: Some(framing),
+            services: grants,
+            host,
+        },
+    )
+    .await?;
 
-    // 4. Split the stream for concurrent read + write.
-    let (mut reader, mut writer) = stream.into_split();
+    // 5. Split the stream for concurrent read + write.
+    let (mut reader, mut writer) = tokio::io::split(stream);
 
-    // Pending requests awaiting a client Response.
+    // Pending requests awaiting a client response head.
     let pending: Arc<dashmap::DashMap<String, tokio::sync::oneshot::Sender<RelayResponse>>> =
         Arc::new(dashmap::DashMap::new());
 
-    // Task A — receive outgoing requests from the HTTP proxy and forward to client.
+    // Task A — receive outgoing messages from the HTTP proxy and forward to client.
     let pending_a = pending.clone();
     let slug_a = slug.clone();
-    let write_task = tokio::spawn(async move {
-        while let Some(PendingRequest { msg, reply_tx }) = rx.recv().await {
-            let id = match &msg {
-                ServerMessage::Request { id, .. } => id.clone(),
-                ServerMessage::Ping => {
-                    // Just forward the ping, no pending entry needed.
-                    if write_msg(&mut writer, &msg).await.is_err() {
-                        break;
+    let handle_a = handle.clone();
+    let header_list = handle.supports(capability::HEADER_LIST);
+    // Legacy clients take each request whole.
+    let mut held = (version == LEGACY_PROTOCOL_VERSION).then(HeldRequests::default);
+    let mut write_task = tokio::spawn(async move {
+        while let Some(queued) = rx.recv().await {
+            if let ServerMessage::RequestChunk { data, .. } | ServerMessage::StreamData { data, .. } = &queued.msg {
+                handle_a.add_bytes_out(data.len());
+            }
+            let queued = match &mut held {
+                Some(held) => held.push(queued),
+                None => Some(queued),
+            };
+            let Some(PendingRequest { mut msg, reply_tx }) = queued else { continue };
+            match (&msg, reply_tx) {
+                (
+                    ServerMessage::Request { id, .. }
+                    | ServerMessage::StreamOpen { id, .. }
+                    | ServerMessage::TcpOpen { id, .. },
+                    Some(reply_tx),
+                ) => {
+                    pending_a.insert(id.clone(), reply_tx);
+                }
+                (ServerMessage::Cancel { id }, _) => {
+                    // Nobody is waiting for this head any more.
+                    pending_a.remove(id);
+                    // Older clients would choke on a message they don't know.
+                    if !handle_a.supports(capability::CANCEL) {
+                        continue;
                     }
-                    continue;
                 }
-                _ => continue,
-            };
-            pending_a.insert(id, reply_tx);
-            if write_msg(&mut writer, &msg).await.is_err() {
+                (ServerMessage::Goodbye, _) => {
+                    // Last message of the session; closing the connection is
+                    // goodbye enough for older clients.
+                    if handle_a.supports(capability::GOODBYE) {
+                        let _ = write_frame(&mut writer, &msg, framing).await;
+                    }
+                    let _ = writer.shutdown().await;
+                    break;
+                }
+                _ => {}
+            }
+            if !header_list {
+                // Older clients only read a name → value map.
+                if let Some(headers) = msg.headers_mut() {
+                    let lost = headers.flatten();
+                    if !lost.is_empty() {
+                        warn!("Relay: '{slug_a}' runs a client too old for these headers, dropped: {}", lost.join(", "));
+                    }
+                }
+            }
+            if write_frame(&mut writer, &msg, framing).await.is_err() {
                 break;
             }
         }
@@ -191,20 +316,83 @@ async fn handle_client(
     let pending_b = pending.clone();
     let slug_b = slug.clone();
     let registry_b = registry.clone();
-    let read_task = tokio::spawn(async move {
+    let handle_b = handle.clone();
+    let mut read_task = tokio::spawn(async move {
+        // Response bodies and upgraded streams currently open, keyed by request id.
+        // Only this task touches it, so a plain HashMap is enough. Whatever is
+        // still open when the tunnel goes away ends truncated.
+        let mut bodies: HashMap<String, BodySender> = HashMap::new();
+
         loop {
-            match read_msg::<_, ClientMessage>(&mut reader).await {
+            match read_frame::<_, ClientMessage>(&mut reader, framing).await {
                 Ok(Some(ClientMessage::Response { id, status, headers, body_b64 })) => {
                     let body = B64.decode(&body_b64).unwrap_or_else(|e| {
                         warn!("Relay: base64 decode error on response id={id}: {e}");
                         vec![]
                     });
+                    handle_b.add_bytes_in(body.len());
                     if let Some((_, tx)) = pending_b.remove(&id) {
+                        let body = BodyReceiver::whole(Bytes::from(body));
                         let _ = tx.send(RelayResponse { status, headers, body });
                     }
                 }
+                Ok(Some(
+                    ClientMessage::ResponseHead { id, status, headers }
+                    | ClientMessage::StreamOpened { id, status, headers },
+                )) => {
+                    if let Some((_, tx)) = pending_b.remove(&id) {
+                        let (body_tx, body) = handle_b.flow.receive(&id);
+                        if tx.send(RelayResponse { status, headers, body }).is_ok() {
+                            bodies.insert(id, body_tx);
+                        }
+                    }
+                }
+                Ok(Some(
+                    ClientMessage::ResponseChunk { id, data }
+                    | ClientMessage::StreamData { id, data },
+                )) => {
+                    handle_b.add_bytes_in(data.len());
+                    let Some(body_tx) = bodies.get(&id) else { continue };
+                    match body_tx.send(data).await {
+                        Ok(()) => {}
+                        // The browser went away — drop the rest of this body.
+                        Err(Undelivered::Closed) => {
+                            bodies.remove(&id);
+                        }
+                        // The relay client ignored its window: cut the body short.
+                        Err(Undelivered::Overrun) => {
+                            bodies.remove(&id);
+                            warn!("Relay: '{slug_b}' sent more of {id} than it had room for — cancelling it");
+                            let tx = handle_b.tx.clone();
+                            tokio::spawn(async move {
+                                let _ = tx.send(PendingRequest { msg: ServerMessage::Cancel { id }, reply_tx: None }).await;
+                            });
+                        }
+                    }
+                }
+                Ok(Some(ClientMessage::Credit { id, chunks })) => {
+                    handle_b.flow.grant(&id, chunks);
+                }
+                Ok(Some(ClientMessage::ResponseEnd { id } | ClientMessage::StreamClose { id })) => {
+                    if let Some(body_tx) = bodies.remove(&id) {
+                        body_tx.finish();
+                    }
+                }
+                Ok(Some(ClientMessage::Abort { id })) => {
+                    // Dropped unfinished: the browser sees the body cut short.
+                    bodies.remove(&id);
+                    warn!("Relay: '{slug_b}' could not finish response {id}");
+                }
                 Ok(Some(ClientMessage::Heartbeat)) => {
-                    // No-op — keep-alive acknowledged.
+                    // Forget bodies whose browser went away while the relay
+                    // client had nothing to send for them.
+                    bodies.retain(|_, body_tx| !body_tx.is_closed());
+                }
+                Ok(Some(ClientMessage::Drain)) => {
+                    // Hand-over: other tunnels of the slug take new requests
+                    // while this one finishes what it has.
+                    handle_b.drain();
+                    info!("Tunnel #{tunnel_id} for '{slug_b}' is draining ({} in flight)", handle_b.in_flight());
                 }
                 Ok(Some(ClientMessage::Register { .. })) => {
                     warn!("Unexpected Register from '{slug_b}' — ignoring");
@@ -212,35 +400,385 @@ async fn handle_client(
                 Ok(None) | Err(_) => break,
             }
         }
-        registry_b.remove(&slug_b);
-        info!("Slug '{slug_b}' unregistered from relay");
+        registry_b.remove(&slug_b, tunnel_id);
+        info!("Tunnel #{tunnel_id} for '{slug_b}' unregistered from relay");
     });
 
-    // 5. Keep-alive: ping every 30 s.
-    let slug_c = slug.clone();
-    let registry_c = registry.clone();
-    let ping_task = tokio::spawn(async move {
+    // 6. Keep-alive: ping every 30 s.
+    let tx_c = handle.tx.clone();
+    let mut ping_task = tokio::spawn(async move {
         loop {
             tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
-            if let Some(handle) = registry_c.get(&slug_c) {
-                let (dummy_tx, _) = tokio::sync::oneshot::channel();
-                let _ = handle.tx.send(PendingRequest {
-                    msg: ServerMessage::Ping,
-                    reply_tx: dummy_tx,
-                }).await;
-            } else {
+            let ping = PendingRequest { msg: ServerMessage::Ping, reply_tx: None };
+            if tx_c.send(ping).await.is_err() {
                 break;
             }
         }
     });
 
-    // Wait until either task finishes (client disconnected).
+    // Wait until either task finishes (client disconnected) or an admin
+    // drops the tunnel.
     tokio::select! {
-        _ = write_task => {}
-        _ = read_task  => {}
-        _ = ping_task  => {}
+        _ = &mut write_task => {}
+        _ = &mut read_task  => {}
+        _ = &mut ping_task  => {}
+        _ = handle.disconnected() => {
+            info!("Tunnel #{tunnel_id} for '{slug}' disconnected by admin");
+        }
     }
 
-    registry.remove(&slug);
+    // Aborting the tasks drops both halves of the connection.
+    write_task.abort();
+    read_task.abort();
+    ping_task.abort();
+    registry.remove(&slug, tunnel_id);
     Ok(())
 }
+
+// ── Legacy clients ────────────────────────────────────────────────────────────
+
+/// Largest request body held for a legacy client. It travels base64-encoded
+/// in a single frame, which must stay under the 16 MiB frame limit.
+const LEGACY_MAX_BODY: usize = 8 * 1024 * 1024;
+
+/// Requests held back from a legacy client until their body is complete,
+/// then sent whole: a Request with `body_b64`, and no RequestChunk /
+/// RequestEnd, which such clients don't know.
+#[derive(Default)]
+struct HeldRequests {
+    requests: HashMap<String, (PendingRequest, Vec<u8>)>,
+}
+
+impl HeldRequests {
+    /// What to send the client for `queued`, if anything yet.
+    fn push(&mut self, queued: PendingRequest) -> Option<PendingRequest> {
+        match &queued.msg {
+            ServerMessage::Request { id, .. } => {
+                let id = id.clone();
+                self.requests.insert(id, (queued, Vec::new()));
+                None
+            }
+            ServerMessage::RequestChunk { id, data } => {
+                let (_, body) = self.requests.get_mut(id)?;
+                if body.len() + data.len() <= LEGACY_MAX_BODY {
+                    body.extend_from_slice(data);
+                    return None;
+                }
+                let (request, _) = self.requests.remove(id)?;
+                warn!("Request {id} is too large for a protocol v{LEGACY_PROTOCOL_VERSION} client — refusing it");
+                if let Some(reply_tx) = request.reply_tx {
+                    let body = BodyReceiver::whole(Bytes::from_static(b"Request body too large for this relay client"));
+                    let _ = reply_tx.send(RelayResponse { status: 413, headers: Headers::default(), body });
+                }
+                None
+            }
+            ServerMessage::RequestEnd { id } => {
+                let (mut request, body) = self.requests.remove(id)?;
+                if let ServerMessage::Request { body_b64, .. } = &mut request.msg {
+                    *body_b64 = Some(B64.encode(body));
+                }
+                Some(request)
+            }
+            ServerMessage::Cancel { id } => {
+                // Not sent yet: nothing to cancel on the client.
+                self.requests.remove(id);
+                Some(queued)
+            }
+            _ => Some(queued),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use serde_json::{json, Value};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::protocol::{BODY_CHANNEL_CAPACITY, Truncated};
+
+    /// A relay listener as `run` sets it up, minus the database: every token
+    /// is accepted and no TCP service is granted.
+    async fn listen(registry: Registry) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, peer)) = listener.accept().await {
+                let registry = registry.clone();
+                tokio::spawn(async move {
+                    if let Ok(Some(mut registration)) = read_register(&mut stream, peer).await {
+                        registration.services.clear();
+                        let _ = serve_tunnel(stream, peer, registry, registration, Vec::new()).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// Send request `id` for `slug` the way the HTTP proxy does, with its
+    /// body in `chunks`, and return where its response head will arrive.
+    async fn request(
+        registry: &Registry,
+        slug: &str,
+        id: &str,
+        chunks: &[&'static [u8]],
+    ) -> oneshot::Receiver<RelayResponse> {
+        let tunnel = registry.pick(slug, None).expect("tunnel registered");
+        let mut headers = Headers::default();
+        headers.push("x-test", "a");
+        headers.push("x-test", "b");
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let id = id.to_owned();
+        let msg = ServerMessage::Request {
+            id: id.clone(),
+            method: "POST".into(),
+            path: "/echo".into(),
+            headers,
+            timeout_ms: Some(5000),
+            body_b64: None,
+        };
+        let send = |msg| tunnel.tx.send(PendingRequest { msg, reply_tx: None });
+        tunnel.tx.send(PendingRequest { msg, reply_tx: Some(reply_tx) }).await.unwrap();
+        for chunk in chunks {
+            send(ServerMessage::RequestChunk { id: id.clone(), data: Bytes::from_static(chunk) }).await.unwrap();
+        }
+        send(ServerMessage::RequestEnd { id }).await.unwrap();
+        reply_rx
+    }
+
+    async fn body(mut response: RelayResponse) -> Result<Vec<u8>, Truncated> {
+        let mut body = Vec::new();
+        while let Some(chunk) = response.body.recv().await {
+            body.extend_from_slice(&chunk?);
+        }
+        Ok(body)
+    }
+
+    /// Register the way clients built before protocol versioning do.
+    async fn legacy_client(addr: SocketAddr, slug: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_msg(&mut stream, &json!({ "type": "register", "slug": slug, "token": "t" })).await.unwrap();
+        let registered: Value = read_msg(&mut stream).await.unwrap().unwrap();
+        assert_eq!(registered["type"], "registered");
+        assert_eq!(registered["ok"], true);
+        stream
+    }
+
+    async fn current_client(addr: SocketAddr, slug: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let register = ClientMessage::Register {
+            slug: slug.into(),
+            token: "t".into(),
+            version: PROTOCOL_VERSION,
+            capabilities: capability::all(),
+            framings: Framing::SUPPORTED.to_vec(),
+            timeouts: TimeoutPolicy::default(),
+            services: Vec::new(),
+        };
+        write_msg(&mut stream, &register).await.unwrap();
+        match read_msg(&mut stream).await.unwrap() {
+            Some(ServerMessage::Registered { ok: true, version, framing, .. }) => {
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(framing, Some(Framing::Binary));
+            }
+            other => panic!("unexpected answer: {other:?}"),
+        }
+        stream
+    }
+
+    async fn registered(registry: &Registry, slug: &str) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !registry.contains(slug) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("tunnel registered");
+    }
+
+    #[tokio::test]
+    async fn legacy_and_current_clients_share_a_listener() {
+        let registry = Registry::default();
+        let addr = listen(registry.clone()).await;
+        let mut old = legacy_client(addr, "old").await;
+        let mut new = current_client(addr, "new").await;
+        registered(&registry, "old").await;
+        registered(&registry, "new").await;
+
+        // The legacy client gets the request whole, headers as a map.
+        let reply = request(&registry, "old", "req-old", &[b"hello ", b"world"]).await;
+        let req: Value = read_msg(&mut old).await.unwrap().unwrap();
+        assert_eq!(req["type"], "request");
+        assert_eq!(req["id"], "req-old");
+        assert_eq!(req["headers"], json!({ "x-test": "a, b" }));
+        assert_eq!(B64.decode(req["body_b64"].as_str().unwrap()).unwrap(), b"hello world");
+        let response = json!({
+            "type": "response",
+            "id": "req-old",
+            "status": 201,
+            "headers": { "content-type": "text/plain" },
+            "body_b64": B64.encode("from old"),
+        });
+        write_msg(&mut old, &response).await.unwrap();
+        let response = reply.await.unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.headers.get("content-type"), Some("text/plain"));
+        assert_eq!(body(response).await.unwrap(), b"from old");
+
+        // The current client gets it streamed, headers as a list.
+        let reply = request(&registry, "new", "req-new", &[b"hello ", b"world"]).await;
+        let mut received = Vec::new();
+        loop {
+            match read_frame::<_, ServerMessage>(&mut new, Framing::Binary).await.unwrap().unwrap() {
+                ServerMessage::Request { id, headers, body_b64, .. } => {
+                    assert_eq!(id, "req-new");
+                    assert_eq!(headers.iter().filter(|(n, _)| *n == "x-test").count(), 2);
+                    assert_eq!(body_b64, None);
+                }
+                ServerMessage::RequestChunk { data, .. } => received.extend_from_slice(&data),
+                ServerMessage::RequestEnd { .. } => break,
+                other => panic!("unexpected message: {other:?}"),
+            }
+        }
+        assert_eq!(received, b"hello world");
+        let id = String::from("req-new");
+        let head = ClientMessage::ResponseHead { id: id.clone(), status: 200, headers: Headers::default() };
+        write_frame(&mut new, &head, Framing::Binary).await.unwrap();
+        let chunk = ClientMessage::ResponseChunk { id: id.clone(), data: Bytes::from_static(b"from new") };
+        write_frame(&mut new, &chunk, Framing::Binary).await.unwrap();
+        write_frame(&mut new, &ClientMessage::ResponseEnd { id }, Framing::Binary).await.unwrap();
+        let response = reply.await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(body(response).await.unwrap(), b"from new");
+    }
+
+    #[tokio::test]
+    async fn legacy_client_refuses_bodies_too_large_to_send_whole() {
+        let registry = Registry::default();
+        let addr = listen(registry.clone()).await;
+        let _old = legacy_client(addr, "old").await;
+        registered(&registry, "old").await;
+
+        let big: &'static [u8] = vec![0u8; LEGACY_MAX_BODY + 1].leak();
+        let reply = request(&registry, "old", "req-old", &[big]).await;
+        assert_eq!(reply.await.unwrap().status, 413);
+    }
+
+    /// A response's frames, as written in one go by the relay client.
+    async fn frames(msgs: impl IntoIterator<Item = ClientMessage>) -> Vec<u8> {
+        let mut frames = Vec::new();
+        for msg in msgs {
+            write_frame(&mut frames, &msg, Framing::Binary).await.unwrap();
+        }
+        frames
+    }
+
+    fn head(id: &str) -> ClientMessage {
+        ClientMessage::ResponseHead { id: id.into(), status: 200, headers: Headers::default() }
+    }
+
+    fn chunks(id: &str, n: usize) -> impl Iterator<Item = ClientMessage> + '_ {
+        (0..n).map(move |_| ClientMessage::ResponseChunk { id: id.into(), data: Bytes::from_static(b"data") })
+    }
+
+    #[tokio::test]
+    async fn a_stalled_browser_only_pauses_its_own_response() {
+        let registry = Registry::default();
+        let addr = listen(registry.clone()).await;
+        let mut client = current_client(addr, "new").await;
+        registered(&registry, "new").await;
+
+        let stalled = request(&registry, "new", "stalled", &[]).await;
+        let served = request(&registry, "new", "served", &[]).await;
+        let mut ended = 0;
+        while ended < 2 {
+            if let ServerMessage::RequestEnd { .. } = read_frame(&mut client, Framing::Binary).await.unwrap().unwrap() {
+                ended += 1;
+            }
+        }
+
+        // A whole window the browser does not read yet, then another response.
+        let mut msgs = vec![head("stalled")];
+        msgs.extend(chunks("stalled", BODY_CHANNEL_CAPACITY));
+        msgs.extend([head("served"), ClientMessage::ResponseEnd { id: "served".into() }]);
+        client.write_all(&frames(msgs).await).await.unwrap();
+        let served = tokio::time::timeout(Duration::from_secs(5), served).await.expect("not held up").unwrap();
+        assert_eq!(served.status, 200);
+
+        // Once the browser reads, the window is handed back for the rest.
+        let stalled = tokio::spawn(body(stalled.await.unwrap()));
+        let mut credit = 0;
+        while credit < BODY_CHANNEL_CAPACITY as u32 {
+            match read_frame(&mut client, Framing::Binary).await.unwrap().unwrap() {
+                ServerMessage::Credit { id, chunks } if id == "stalled" => credit += chunks,
+                other => panic!("unexpected message: {other:?}"),
+            }
+        }
+        let mut msgs: Vec<_> = chunks("stalled", BODY_CHANNEL_CAPACITY).collect();
+        msgs.push(ClientMessage::ResponseEnd { id: "stalled".into() });
+        client.write_all(&frames(msgs).await).await.unwrap();
+        assert_eq!(stalled.await.unwrap().unwrap().len(), BODY_CHANNEL_CAPACITY * 2 * 4);
+    }
+
+    #[tokio::test]
+    async fn overrunning_a_window_cancels_the_response() {
+        let registry = Registry::default();
+        let addr = listen(registry.clone()).await;
+        let mut client = current_client(addr, "new").await;
+        registered(&registry, "new").await;
+
+        let overrun = request(&registry, "new", "overrun", &[]).await;
+        while !matches!(read_frame(&mut client, Framing::Binary).await.unwrap().unwrap(), ServerMessage::RequestEnd { .. }) {}
+
+        let mut msgs = vec![head("overrun")];
+        msgs.extend(chunks("overrun", BODY_CHANNEL_CAPACITY + 1));
+        client.write_all(&frames(msgs).await).await.unwrap();
+        match read_frame(&mut client, Framing::Binary).await.unwrap().unwrap() {
+            ServerMessage::Cancel { id } => assert_eq!(id, "overrun"),
+            other => panic!("unexpected message: {other:?}"),
+        }
+        assert!(body(overrun.await.unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bodies_cut_short_do_not_pass_for_complete() {
+        let registry = Registry::default();
+        let addr = listen(registry.clone()).await;
+        let mut client = current_client(addr, "new").await;
+        registered(&registry, "new").await;
+
+        let aborted = request(&registry, "new", "aborted", &[]).await;
+        let dropped = request(&registry, "new", "dropped", &[]).await;
+        for id in ["aborted", "dropped"] {
+            let head = ClientMessage::ResponseHead { id: id.into(), status: 200, headers: Headers::default() };
+            write_frame(&mut client, &head, Framing::Binary).await.unwrap();
+            let chunk = ClientMessage::ResponseChunk { id: id.into(), data: Bytes::from_static(b"part") };
+            write_frame(&mut client, &chunk, Framing::Binary).await.unwrap();
+        }
+
+        // The local server failed part-way.
+        write_frame(&mut client, &ClientMessage::Abort { id: "aborted".into() }, Framing::Binary).await.unwrap();
+        assert!(body(aborted.await.unwrap()).await.is_err());
+
+        // The tunnel went away part-way.
+        drop(client);
+        assert!(body(dropped.await.unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn newer_versions_are_refused() {
+        let addr = listen(Registry::default()).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let register = json!({ "type": "register", "slug": "future", "token": "t", "version": PROTOCOL_VERSION + 1 });
+        write_msg(&mut stream, &register).await.unwrap();
+        match read_msg(&mut stream).await.unwrap() {
+            Some(ServerMessage::Registered { ok: false, error: Some(error), .. }) => {
+                assert!(error.contains("newer than this relay server"), "{error}");
+            }
+            other => panic!("unexpected answer: {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Request deadlines, shared by the relay client and server.
+//!
+//! The client announces its preferences in Register. For every request the
+//! server settles on one deadline and sends it along (`timeout_ms`): once
+//! the request body is through, the client gives its local server exactly
+//! that long to answer, while the server waits that plus REPLY_GRACE. Both
+//! ends work from the same number, so the client's 504 normally reaches the
+//! browser first; if the tail of the body takes longer than REPLY_GRACE to
+//! reach the local server, the server answers 504 itself.
+
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+/// Extra time the server waits beyond a request's deadline, for the client's
+/// own timeout response to travel back through the tunnel.
+pub const REPLY_GRACE: Duration = Duration::from_secs(3);
+
+/// Deadline used when neither side configures one.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(12);
+
+/// A deadline override for paths matching a pattern, e.g. `/api/export/*=120`.
+/// `*` matches any run of characters; the query string is ignored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeoutRule {
+    pub pattern: String,
+    pub timeout_ms: u64,
+}
+
+impl TimeoutRule {
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or(path);
+        glob_match(self.pattern.as_bytes(), path.as_bytes())
+    }
+}
+
+impl FromStr for TimeoutRule {
+    type Err = String;
+
+    /// Parses `PATTERN=SECONDS`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, secs) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected PATTERN=SECONDS, got '{s}'"))?;
+        let secs: u64 = secs
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid number of seconds in '{s}'"))?;
+        Ok(Self { pattern: pattern.trim().to_owned(), timeout_ms: secs * 1000 })
+    }
+}
+
+/// A side's timeout settings: a default and path overrides, first match wins.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TimeoutPolicy {
+    #[serde(default)]
+    pub default_ms: Option<u64>,
+    #[serde(default)]
+    pub rules: Vec<TimeoutRule>,
+}
+
+impl TimeoutPolicy {
+    /// The override for `path`, if any.
+    pub fn rule_for(&self, path: &str) -> Option<Duration> {
+        self.rules
+            .iter()
+            .find(|r| r.matches(path))
+            .map(|r| Duration::from_millis(r.timeout_ms))
+    }
+
+    fn default(&self) -> Option<Duration> {
+        self.default_ms.map(Duration::from_millis)
+    }
+}
+
+/// Server-side deadline resolution.
+#[derive(Clone, Debug)]
+pub struct ServerTimeouts {
+    pub policy: TimeoutPolicy,
+    /// Upper bound on any deadline a client asks for.
+    pub max: Duration,
+}
+
+impl ServerTimeouts {
+    /// Deadline for a request to `path` on a tunnel announcing `client`.
+    /// The instance knows its own slow endpoints best, so its overrides win,
+    /// then the server's; either way capped at `max`.
+    pub fn deadline(&self, client: &TimeoutPolicy, path: &str) -> Duration {
+        client
+            .rule_for(path)
+            .or_else(|| self.policy.rule_for(path))
+            .or_else(|| client.default())
+            .or_else(|| self.policy.default())
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT)
+            .min(self.max)
+    }
+}
+
+/// `*`-only glob match over bytes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it currently covers up to.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, covered)) = backtrack {
+            p = star + 1;
+            t = covered + 1;
+            backtrack = Some((star, covered + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
@@ -0,0 +1,169 @@
+//! TLS for the relay client ↔ server TCP link.
+//!
+//! The server terminates TLS with a PEM certificate chain and key. The client
+//! verifies the server certificate against the Mozilla roots (or a custom CA
+//! bundle for self-hosted relays) and can additionally pin the SHA-256 of the
+//! server's leaf certificate.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::{self, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+// ── Server side ───────────────────────────────────────────────────────────────
+
+/// Build the acceptor for the relay TCP listener from PEM files.
+pub fn server_acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|it| it.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading TLS certificate {}", cert_path.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("reading TLS key {}", key_path.display()))?;
+
+    let config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// ── Client side ───────────────────────────────────────────────────────────────
+
+/// Relay client TLS settings (`--tls*` flags).
+#[derive(Clone, Debug, Default)]
+pub struct ClientTlsOptions {
+    /// PEM bundle to trust instead of the built-in Mozilla roots.
+    pub ca: Option<PathBuf>,
+    /// Accepted SHA-256 fingerprints of the server's leaf certificate
+    /// (hex, colons optional). Empty = no pinning.
+    pub pins: Vec<String>,
+    /// Name to verify the certificate against. Defaults to the host of `--server`.
+    pub server_name: Option<String>,
+}
+
+/// A ready-to-use connector plus the name to present in SNI / verify against.
+#[derive(Clone)]
+pub struct ClientTls {
+    pub connector: TlsConnector,
+    pub server_name: ServerName<'static>,
+}
+
+/// Build the relay client connector for `server_addr` ("host:port").
+pub fn client_connector(server_addr: &str, opts: &ClientTlsOptions) -> anyhow::Result<ClientTls> {
+    let mut roots = RootCertStore::empty();
+    match &opts.ca {
+        Some(path) => {
+            for cert in CertificateDer::pem_file_iter(path)
+                .with_context(|| format!("reading TLS CA bundle {}", path.display()))?
+            {
+                roots.add(cert?)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let provider = provider();
+    let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?;
+    let pins = opts
+        .pins
+        .iter()
+        .map(|p| p.replace(':', "").to_lowercase())
+        .collect::<Vec<_>>();
+
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinningVerifier { inner: webpki, pins }))
+        .with_no_client_auth();
+
+    let host = match &opts.server_name {
+        Some(name) => name.clone(),
+        None => server_addr
+            .rsplit_once(':')
+            .map_or(server_addr, |(host, _)| host)
+            .trim_matches(['[', ']'])
+            .to_owned(),
+    };
+    let server_name = ServerName::try_from(host)
+        .context("invalid TLS server name")?;
+
+    Ok(ClientTls {
+        connector: TlsConnector::from(Arc::new(config)),
+        server_name,
+    })
+}
+
+/// Standard WebPKI verification, then an optional leaf-certificate pin check.
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        if !self.pins.is_empty() {
+            let fingerprint = sha256_hex(end_entity);
+            if !self.pins.contains(&fingerprint) {
+                return Err(rustls::Error::General(format!(
+                    "server certificate sha256 {fingerprint} does not match any pin"
+                )));
+            }
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+// ── Helpers ───────────────────────────────────────────────────────────────────
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn sha256_hex(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
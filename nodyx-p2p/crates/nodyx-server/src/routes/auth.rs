@@ -32,7 +32,7 @@ use crate::state::AppState;
 
 const SESSION_TTL: u64     = 7 * 24 * 60 * 60; // 7 days
 const RESET_TTL_SEC: u64   = 60 * 60;           // 1 hour
-const BCRYPT_ROUNDS: u32   = 12;
+pub(crate) const BCRYPT_ROUNDS: u32 = 12;
 
 /// Cached community_id for auto-join on register/login
 static COMMUNITY_ID: OnceCell<Option<Uuid>> = OnceCell::const_new();
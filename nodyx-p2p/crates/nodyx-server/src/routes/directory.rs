@@ -3,7 +3,7 @@ use axum::{
     http::{HeaderMap, Request, StatusCode},
     middleware::Next,
     response::{IntoResponse, Redirect, Response},
-    routing::{delete, get, post},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use redis::AsyncCommands;
@@ -14,6 +14,7 @@ use std::{collections::HashSet, net::SocketAddr};
 use uuid::Uuid;
 
 use crate::{error::ApiError, state::AppState};
+use super::auth::BCRYPT_ROUNDS;
 
 // ── Helpers ───────────────────────────────────────────────────────────────────
 
@@ -48,6 +49,14 @@ fn extract_subdomain(host: &str) -> Option<String> {
     Some(slug.to_string())
 }
 
+/// A new instance token, shown once to its owner, and the salt its hash is
+/// stored with (`directory_token_hash` in SQL, migration 063).
+fn new_token() -> (String, String) {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    (hex::encode(rng.gen::<[u8; 32]>()), hex::encode(rng.gen::<[u8; 16]>()))
+}
+
 pub fn client_ip(headers: &HeaderMap, peer: SocketAddr) -> String {
     headers
         .get("x-forwarded-for")
@@ -223,16 +232,13 @@ async fn register(
         return Err(ApiError::Conflict("Slug already taken".into()));
     }
 
-    let token = {
-        use rand::Rng;
-        let bytes: [u8; 32] = rand::thread_rng().gen();
-        hex::encode(bytes)
-    };
+    let (token, salt) = new_token();
 
     let row = sqlx::query(
         "INSERT INTO directory_instances
-           (slug, name, description, url, language, country, theme, version, token, status)
-         VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,'pending')
+           (slug, name, description, url, language, country, theme, version,
+            token_salt, token_hash, token_lookup, status)
+         VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,directory_token_hash($9,$10),directory_token_lookup($10),'pending')
          RETURNING id, slug, name, url, status, registered_at",
     )
     .bind(&body.slug).bind(&body.name)
@@ -242,7 +248,7 @@ async fn register(
     .bind(body.country.as_deref().unwrap_or(""))
     .bind(body.theme.as_deref().unwrap_or(""))
     .bind(body.version.as_deref().unwrap_or(""))
-    .bind(&token)
+    .bind(&salt).bind(&token)
     .fetch_one(&state.db)
     .await?;
 
@@ -316,14 +322,15 @@ async fn ping(
     let banner_url = body.banner_url.as_ref().and_then(|v| v.as_str()).map(String::from);
 
     let row = sqlx::query(
-        "UPDATE directory_instances
+        "UPDATE directory_instances d
          SET last_seen  = NOW(),
              members    = COALESCE($2, members),
              online     = COALESCE($3, online),
              logo_url   = COALESCE($4, logo_url),
              banner_url = COALESCE($5, banner_url),
              ip         = COALESCE($6::inet, ip)
-         WHERE token = $1
+         WHERE directory_token_lookup($1) IN (token_lookup, previous_token_lookup)
+           AND directory_token_valid(d, $1)
          RETURNING slug, status",
     )
     .bind(&body.token)
@@ -356,7 +363,8 @@ async fn unregister(
         return Err(ApiError::BadRequest("token required".into()));
     }
     let row = sqlx::query(
-        "SELECT id, cloudflare_record_id FROM directory_instances WHERE slug=$1 AND token=$2",
+        "SELECT id, cloudflare_record_id FROM directory_instances d
+         WHERE slug=$1 AND directory_token_valid(d, $2)",
     )
     .bind(&slug).bind(&body.token)
     .fetch_optional(&state.db).await?
@@ -370,6 +378,205 @@ async fn unregister(
     Ok(Json(json!({ "ok": true })))
 }
 
+// ── POST /api/directory/:slug/token ──────────────────────────────────────────
+
+/// Hours the previous token stays valid after a rotation, by default and at most.
+const TOKEN_GRACE_HOURS:     i32 = 24;
+const MAX_TOKEN_GRACE_HOURS: i32 = 168;
+
+#[derive(Deserialize)]
+struct RotateTokenBody { token: String, grace_hours: Option<i32> }
+
+/// Replace the instance token. The previous one keeps working for
+/// `grace_hours`, so the instance and its relay client can switch over.
+/// Only the current token may rotate.
+async fn rotate_token(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    Json(body): Json<RotateTokenBody>,
+) -> Result<Json<Value>, ApiError> {
+    if body.token.is_empty() {
+        return Err(ApiError::BadRequest("token required".into()));
+    }
+    let grace = body.grace_hours.unwrap_or(TOKEN_GRACE_HOURS);
+    if !(0..=MAX_TOKEN_GRACE_HOURS).contains(&grace) {
+        return Err(ApiError::BadRequest(format!("grace_hours must be between 0 and {MAX_TOKEN_GRACE_HOURS}")));
+    }
+
+    let (token, salt) = new_token();
+    let row = sqlx::query(
+        "UPDATE directory_instances
+         SET previous_token_salt       = token_salt,
+             previous_token_hash       = token_hash,
+             previous_token_lookup     = token_lookup,
+             previous_token_expires_at = NOW() + make_interval(hours => $3),
+             token_salt                = $4,
+             token_hash                = directory_token_hash($4, $5),
+             token_lookup              = directory_token_lookup($5),
+             token_rotated_at          = NOW()
+         WHERE slug=$1 AND token_hash = directory_token_hash(token_salt, $2)
+         RETURNING previous_token_expires_at",
+    )
+    .bind(&slug).bind(&body.token).bind(grace).bind(&salt).bind(&token)
+    .fetch_optional(&state.db).await?
+    .ok_or(ApiError::Forbidden)?;
+
+    tracing::info!("[Directory] {slug} rotated its token ({grace}h grace)");
+    Ok(Json(json!({
+        "ok": true,
+        "token": token,
+        "previous_valid_until": row.get::<chrono::DateTime<chrono::Utc>, _>("previous_token_expires_at"),
+    })))
+}
+
+// ── PUT /api/directory/:slug/relay-access ────────────────────────────────────
+
+const MAX_ACCESS_IPS: usize = 100;
+/// The relay checks basic auth passwords against a bcrypt hash, which only
+/// reads their first 72 bytes.
+const MAX_PASSWORD_BYTES: usize = 72;
+
+/// Headers the relay sets or consumes itself, so they can't carry the secret.
+const RESERVED_HEADERS: &[&str] = &[
+    "host", "authorization", "forwarded", "x-forwarded-for", "x-forwarded-host",
+    "x-forwarded-proto", "x-real-ip",
+];
+
+#[derive(Deserialize)]
+struct BasicAuth { user: String, password: String }
+
+#[derive(Deserialize)]
+struct SecretHeader { name: String, value: String }
+
+#[derive(Deserialize)]
+struct RelayAccessBody {
+    token: String,
+    #[serde(default)] allow_ips: Vec<String>,
+    #[serde(default)] deny_ips: Vec<String>,
+    basic_auth: Option<BasicAuth>,
+    secret_header: Option<SecretHeader>,
+}
+
+/// An IP address, or a network as ADDRESS/PREFIX.
+fn is_ip_or_cidr(value: &str) -> bool {
+    let (addr, prefix) = match value.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (value, None),
+    };
+    let Ok(ip) = addr.parse::<std::net::IpAddr>() else { return false };
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    prefix.is_none_or(|p| p.bytes().all(|b| b.is_ascii_digit()) && p.parse::<u8>().is_ok_and(|bits| bits <= max))
+}
+
+fn relay_access_error(body: &RelayAccessBody) -> Option<String> {
+    for (field, list) in [("allow_ips", &body.allow_ips), ("deny_ips", &body.deny_ips)] {
+        if list.len() > MAX_ACCESS_IPS || !list.iter().all(|ip| is_ip_or_cidr(ip)) {
+            return Some(format!("{field} must be a list of at most {MAX_ACCESS_IPS} IP addresses or CIDR networks"));
+        }
+    }
+    if let Some(basic) = &body.basic_auth {
+        if !(1..=64).contains(&basic.user.len()) || basic.user.contains(|c: char| c == ':' || c.is_whitespace()) {
+            return Some("basic_auth.user must be 1-64 characters, without spaces or \":\"".into());
+        }
+        if !(8..=MAX_PASSWORD_BYTES).contains(&basic.password.len()) {
+            return Some(format!("basic_auth.password must be 8-{MAX_PASSWORD_BYTES} bytes"));
+        }
+    }
+    if let Some(header) = &body.secret_header {
+        let name = header.name.to_ascii_lowercase();
+        if !(1..=64).contains(&name.len())
+            || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            || RESERVED_HEADERS.contains(&name.as_str())
+        {
+            return Some("secret_header.name must be a header name the relay does not set itself".into());
+        }
+        if !(16..=256).contains(&header.value.len()) || !header.value.bytes().all(|b| (0x21..=0x7e).contains(&b)) {
+            return Some("secret_header.value must be 16-256 printable ASCII characters".into());
+        }
+    }
+    None
+}
+
+/// Set who may reach the instance through the relay: IP allow/deny lists,
+/// HTTP basic auth and/or a shared-secret header, either of which lets a
+/// request through. Replaces the whole policy; an empty one removes it.
+/// Secrets are stored hashed, the password with bcrypt; nodyx-relay applies
+/// changes within a minute.
+async fn set_relay_access(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    Json(body): Json<RelayAccessBody>,
+) -> Result<Json<Value>, ApiError> {
+    if body.token.is_empty() {
+        return Err(ApiError::BadRequest("token required".into()));
+    }
+    if let Some(error) = relay_access_error(&body) {
+        return Err(ApiError::BadRequest(error));
+    }
+    sqlx::query("SELECT slug FROM directory_instances d WHERE slug=$1 AND directory_token_valid(d, $2)")
+        .bind(&slug).bind(&body.token)
+        .fetch_optional(&state.db).await?
+        .ok_or(ApiError::Forbidden)?;
+
+    if body.allow_ips.is_empty() && body.deny_ips.is_empty()
+        && body.basic_auth.is_none() && body.secret_header.is_none()
+    {
+        sqlx::query("DELETE FROM relay_access_policies WHERE slug=$1")
+            .bind(&slug).execute(&state.db).await?;
+        tracing::info!("[Directory] {slug} removed its relay access policy");
+        return Ok(Json(json!({ "ok": true, "policy": null })));
+    }
+
+    let basic_hash = match &body.basic_auth {
+        Some(basic) => {
+            let password = basic.password.clone();
+            let hashed = tokio::task::spawn_blocking(move || bcrypt::hash(password, BCRYPT_ROUNDS))
+                .await
+                .map_err(|e| ApiError::Internal(anyhow::anyhow!(e)))?
+                .map_err(|e| ApiError::Internal(anyhow::anyhow!(e)))?;
+            Some(hashed)
+        }
+        None => None,
+    };
+    let header_salt = body.secret_header.as_ref().map(|_| new_token().1);
+    let header_name = body.secret_header.as_ref().map(|h| h.name.to_ascii_lowercase());
+    sqlx::query(
+        "INSERT INTO relay_access_policies
+           (slug, allow_ips, deny_ips, basic_auth_user, basic_auth_hash,
+            secret_header, secret_header_salt, secret_header_hash, updated_at)
+         VALUES ($1,
+                 ARRAY(SELECT network(ip) FROM unnest($2::text[]::inet[]) AS ip),
+                 ARRAY(SELECT network(ip) FROM unnest($3::text[]::inet[]) AS ip),
+                 $4, $5,
+                 $6, $7, directory_token_hash($7, $8), NOW())
+         ON CONFLICT (slug) DO UPDATE SET
+           allow_ips          = EXCLUDED.allow_ips,
+           deny_ips           = EXCLUDED.deny_ips,
+           basic_auth_user    = EXCLUDED.basic_auth_user,
+           basic_auth_hash    = EXCLUDED.basic_auth_hash,
+           secret_header      = EXCLUDED.secret_header,
+           secret_header_salt = EXCLUDED.secret_header_salt,
+           secret_header_hash = EXCLUDED.secret_header_hash,
+           updated_at         = NOW()",
+    )
+    .bind(&slug).bind(&body.allow_ips).bind(&body.deny_ips)
+    .bind(body.basic_auth.as_ref().map(|b| &b.user)).bind(&basic_hash)
+    .bind(&header_name).bind(&header_salt)
+    .bind(body.secret_header.as_ref().map(|h| &h.value))
+    .execute(&state.db).await?;
+
+    tracing::info!("[Directory] {slug} updated its relay access policy");
+    Ok(Json(json!({
+        "ok": true,
+        "policy": {
+            "allow_ips": body.allow_ips,
+            "deny_ips": body.deny_ips,
+            "basic_auth_user": body.basic_auth.as_ref().map(|b| &b.user),
+            "secret_header": header_name,
+        },
+    })))
+}
+
 // ── POST /api/directory/assets ────────────────────────────────────────────────
 
 #[derive(Deserialize)]
@@ -394,7 +601,9 @@ async fn push_assets(
     if token.is_empty() { return Err(ApiError::Unauthorized); }
 
     let inst = sqlx::query(
-        "SELECT id, slug FROM directory_instances WHERE token = $1 AND status = 'active'",
+        "SELECT id, slug FROM directory_instances d
+         WHERE directory_token_lookup($1) IN (token_lookup, previous_token_lookup)
+           AND directory_token_valid(d, $1) AND status = 'active'",
     )
     .bind(token).fetch_optional(&state.db).await?.ok_or(ApiError::Forbidden)?;
 
@@ -549,7 +758,9 @@ async fn announce(
 ) -> Result<Json<Value>, ApiError> {
     if body.token.is_empty() { return Err(ApiError::BadRequest("token required".into())); }
     let inst = sqlx::query(
-        "SELECT slug, url FROM directory_instances WHERE token = $1 AND status = 'active' LIMIT 1",
+        "SELECT slug, url FROM directory_instances d
+         WHERE directory_token_lookup($1) IN (token_lookup, previous_token_lookup)
+           AND directory_token_valid(d, $1) AND status = 'active' LIMIT 1",
     )
     .bind(&body.token).fetch_optional(&state.db).await?.ok_or(ApiError::Forbidden)?;
 
@@ -633,7 +844,8 @@ async fn gossip_receive(
         _ => return Err(ApiError::BadRequest("invalid instance_url".into())),
     }
     sqlx::query(
-        "SELECT id FROM directory_instances WHERE slug=$1 AND token=$2 AND status='active' LIMIT 1",
+        "SELECT id FROM directory_instances d
+         WHERE slug=$1 AND directory_token_valid(d, $2) AND status='active' LIMIT 1",
     )
     .bind(&body.instance_slug).bind(token)
     .fetch_optional(&state.db).await?.ok_or(ApiError::Forbidden)?;
@@ -819,6 +1031,8 @@ pub fn router() -> Router<AppState> {
         .route("/directory/register",        post(register))
         .route("/directory/ping",            post(ping))
         .route("/directory/:slug",           delete(unregister))
+        .route("/directory/:slug/token",     post(rotate_token))
+        .route("/directory/:slug/relay-access", put(set_relay_access))
         .route("/directory/assets",          post(push_assets))
         .route("/directory/assets/search",   get(search_assets))
         .route("/directory/search/announce", post(announce))